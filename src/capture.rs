@@ -0,0 +1,753 @@
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rocket::serde::json::serde_json;
+use rocket::serde::Serialize;
+use rocket::tokio::fs::{self, File, OpenOptions};
+use rocket::tokio::io::{
+    duplex, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader,
+    DuplexStream,
+};
+use rocket::tokio::sync::Mutex as AsyncMutex;
+use rocket::tokio::task::JoinHandle;
+
+use crate::puppet::{Puppet, PuppetId};
+use crate::routes::{Combine, Stream};
+
+/// How much of a capture file is read at a time when reading it from the
+/// end.
+const TAIL_CHUNK: u64 = 8 * 1024;
+
+/// Bookkeeping for a captured stream, shared between whatever writes the
+/// capture file and whoever reports on it.
+#[derive(Default)]
+pub struct StreamStats {
+    dropped_bytes: AtomicU64,
+    /// How many bytes the child has written to the stream, or `None` if
+    /// they don't pass through the server to be counted.
+    written_bytes: Option<AtomicU64>,
+    /// Why capturing the stream stopped, if it did before the child
+    /// closed it.
+    error: Mutex<Option<String>>,
+}
+
+impl StreamStats {
+    /// Stats for a stream whose output passes through the server.
+    pub fn counted() -> StreamStats {
+        StreamStats {
+            written_bytes: Some(AtomicU64::new(0)),
+            ..StreamStats::default()
+        }
+    }
+
+    /// How many bytes the child has written to the stream in all,
+    /// including any since trimmed, truncated or rotated away.
+    pub fn written_bytes(&self) -> Option<u64> {
+        self.written_bytes
+            .as_ref()
+            .map(|written| written.load(Ordering::Relaxed))
+    }
+
+    fn add_written(&self, n: usize) {
+        if let Some(written) = &self.written_bytes {
+            written.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// How many bytes were trimmed off the front of the capture to keep
+    /// it under its size limit.
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.dropped_bytes() > 0
+    }
+
+    pub fn error(&self) -> Option<String> {
+        self.lock_error().clone()
+    }
+
+    fn set_error(&self, err: &io::Error) {
+        *self.lock_error() = Some(err.to_string());
+    }
+
+    fn lock_error(&self) -> MutexGuard<'_, Option<String>> {
+        self.error.lock().expect("stream stats lock poisoned")
+    }
+}
+
+/// Copies one of a child's output pipes (or its terminal) into its
+/// capture file, keeping only the most recent `max_lines` and `max_bytes`
+/// of it if given, or rotating it if asked to.
+///
+/// Handing the child the capture file directly would be cheaper, but then
+/// there'd be no opportunity to count the output or do anything else with
+/// it on its way to disk. So the file is only handed over directly for a
+/// detached child, which can outlive the server and the pump with it.
+pub struct Pump {
+    pub path: PathBuf,
+    pub max_bytes: Option<u64>,
+    pub max_lines: Option<u64>,
+    pub rotation: Option<Rotation>,
+}
+
+/// Where each complete line of a capture file ends, kept up to date as
+/// it's written to, so trimming it to `max_lines` doesn't mean reading
+/// all of it after every write.
+struct LineEnds {
+    /// The offset just past each newline, in order.
+    ends: VecDeque<u64>,
+    /// How long the file is.
+    len: u64,
+}
+
+impl LineEnds {
+    /// Finds where the lines of the file at `path` end, e.g. one being
+    /// appended to.
+    async fn scan(path: &Path) -> io::Result<LineEnds> {
+        let mut lines = LineEnds {
+            ends: VecDeque::new(),
+            len: 0,
+        };
+        match fs::read(path).await {
+            Ok(contents) => lines.push(&contents),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(lines)
+    }
+
+    /// Accounts for `bytes` having been appended to the file.
+    fn push(&mut self, bytes: &[u8]) {
+        let start = self.len;
+        self.ends.extend(
+            bytes
+                .iter()
+                .enumerate()
+                .filter(|(_, &byte)| byte == b'\n')
+                .map(|(i, _)| start + i as u64 + 1),
+        );
+        self.len += bytes.len() as u64;
+    }
+
+    /// Accounts for the first `cut` bytes of the file having been cut.
+    fn cut(&mut self, cut: u64) {
+        while self.ends.front().is_some_and(|&end| end <= cut) {
+            self.ends.pop_front();
+        }
+        for end in self.ends.iter_mut() {
+            *end -= cut;
+        }
+        self.len = self.len.saturating_sub(cut);
+    }
+}
+
+/// Moves a capture file aside once it reaches `max_bytes`, to `<path>.1`,
+/// shifting earlier ones along to `<path>.2` and so on. Only the `keep`
+/// most recent are kept.
+#[derive(Debug, Copy, Clone)]
+pub struct Rotation {
+    pub max_bytes: u64,
+    pub keep: usize,
+}
+
+impl Pump {
+    pub fn start<R>(
+        self,
+        id: PuppetId,
+        stream: Stream,
+        src: R,
+        stats: Arc<StreamStats>,
+    ) -> JoinHandle<()>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        self.spawn(id, stream, src, stats, true)
+    }
+
+    /// Captures a child's stdout and stderr together, merged as `combine`
+    /// says. Each stream's bytes are counted in its own stats as they're
+    /// read from the child, so neither count includes the other stream's
+    /// or the marks. Anything else about the capture is reported in
+    /// stdout's.
+    pub fn start_combined<O, E>(
+        self,
+        id: PuppetId,
+        combine: Combine,
+        stdout: O,
+        stderr: E,
+        stdout_stats: Arc<StreamStats>,
+        stderr_stats: Arc<StreamStats>,
+    ) -> JoinHandle<()>
+    where
+        O: AsyncRead + Unpin + Send + 'static,
+        E: AsyncRead + Unpin + Send + 'static,
+    {
+        let merged = merge(
+            id,
+            combine,
+            stdout,
+            stderr,
+            stdout_stats.clone(),
+            stderr_stats,
+        );
+        self.spawn(id, Stream::Stdout, merged, stdout_stats, false)
+    }
+
+    fn spawn<R>(
+        self,
+        id: PuppetId,
+        stream: Stream,
+        src: R,
+        stats: Arc<StreamStats>,
+        count: bool,
+    ) -> JoinHandle<()>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        rocket::tokio::spawn(async move {
+            let mut src = src;
+            if let Err(err) = self.run(&mut src, &stats, count).await {
+                warn!(
+                    "stopped capturing {} of puppet id={} err={}",
+                    stream, id, err
+                );
+                stats.set_error(&err);
+                match count {
+                    true => drain(src, &stats).await,
+                    false => drain(src, &StreamStats::default()).await,
+                }
+            }
+        })
+    }
+
+    /// Copies `src` into the capture file until it closes, counting what's
+    /// read in `stats` if `count` is set.
+    async fn run<R: AsyncRead + Unpin>(
+        &self,
+        mut src: R,
+        stats: &StreamStats,
+        count: bool,
+    ) -> io::Result<()> {
+        let mut file = open_append(&self.path).await?;
+        let mut lines = match self.max_lines {
+            Some(_) => Some(LineEnds::scan(&self.path).await?),
+            None => None,
+        };
+        let mut buf = vec![0; 8 * 1024];
+        loop {
+            let n = match src.read(&mut buf).await {
+                Ok(n) => n,
+                // The master side of a pty reports EIO rather than EOF
+                // once the last of the child's end has been closed.
+                Err(err) if err.raw_os_error() == Some(libc::EIO) => 0,
+                Err(err) => return Err(err),
+            };
+            if n == 0 {
+                return Ok(());
+            }
+            if count {
+                stats.add_written(n);
+            }
+            if let Some(rotation) = self.rotation {
+                self.write_rotating(&mut file, &buf[..n], rotation, stats)
+                    .await?;
+                continue;
+            }
+            file.write_all(&buf[..n]).await?;
+            if let (Some(max_lines), Some(lines)) = (self.max_lines, lines.as_mut()) {
+                let len = file.metadata().await?.len();
+                if let Some(trimmed) = self
+                    .trim_lines(max_lines, lines, len, &buf[..n], stats)
+                    .await?
+                {
+                    file = trimmed;
+                }
+            }
+            let max_bytes = match self.max_bytes {
+                Some(max_bytes) => max_bytes,
+                None => continue,
+            };
+            // Go by the file's actual length rather than keeping count,
+            // since the file can be truncated out from under us.
+            let len = file.metadata().await?.len();
+            if len > max_bytes {
+                file = self.trim(len, max_bytes).await?;
+                if let Some(lines) = lines.as_mut() {
+                    lines.cut(len - max_bytes);
+                }
+                stats
+                    .dropped_bytes
+                    .fetch_add(len - max_bytes, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Cuts the capture file of length `len` down to its last `max_bytes`
+    /// bytes. Returns a handle on the new file to keep writing to.
+    async fn trim(&self, len: u64, max_bytes: u64) -> io::Result<File> {
+        let mut old = File::open(&self.path).await?;
+        old.seek(SeekFrom::Start(len - max_bytes)).await?;
+        let mut tail = Vec::with_capacity(max_bytes as usize);
+        old.take(max_bytes).read_to_end(&mut tail).await?;
+        self.replace(&tail).await
+    }
+
+    /// Cuts the capture file, now `len` bytes long after `written` was
+    /// appended to it, down to its last `max_lines` complete lines.
+    /// Whatever follows the last newline is kept too, as the start of a
+    /// line still being written. The file is only read when something
+    /// has to be cut. Returns a handle on the new file if anything was.
+    async fn trim_lines(
+        &self,
+        max_lines: u64,
+        lines: &mut LineEnds,
+        len: u64,
+        written: &[u8],
+        stats: &StreamStats,
+    ) -> io::Result<Option<File>> {
+        // As when trimming by size, the file may have been truncated
+        // since, in which case where its lines end has to be found anew.
+        if len == lines.len + written.len() as u64 {
+            lines.push(written);
+        } else {
+            *lines = LineEnds::scan(&self.path).await?;
+        }
+        let excess = match (lines.ends.len() as u64).checked_sub(max_lines) {
+            Some(excess) if excess > 0 => excess as usize,
+            _ => return Ok(None),
+        };
+        let cut = lines.ends[excess - 1];
+        let mut old = File::open(&self.path).await?;
+        old.seek(SeekFrom::Start(cut)).await?;
+        let mut tail = Vec::new();
+        old.read_to_end(&mut tail).await?;
+        stats.dropped_bytes.fetch_add(cut, Ordering::Relaxed);
+        lines.cut(cut);
+        self.replace(&tail).await.map(Some)
+    }
+
+    /// Replaces the capture file with one holding `contents`. The new
+    /// file is written out beside the original and renamed over it, so a
+    /// concurrent reader sees either the old file or the new one rather
+    /// than something half-written. Returns a handle on the new file to
+    /// keep writing to.
+    async fn replace(&self, contents: &[u8]) -> io::Result<File> {
+        let trimmed = sibling_path(&self.path, "trimmed");
+        fs::write(&trimmed, contents).await?;
+        fs::rename(&trimmed, &self.path).await?;
+        open_append(&self.path).await
+    }
+
+    /// Writes `bytes` to the capture file, rotating it each time it fills
+    /// up.
+    async fn write_rotating(
+        &self,
+        file: &mut File,
+        mut bytes: &[u8],
+        rotation: Rotation,
+        stats: &StreamStats,
+    ) -> io::Result<()> {
+        while !bytes.is_empty() {
+            // As when trimming, the file may have been truncated since.
+            let len = file.metadata().await?.len();
+            if len >= rotation.max_bytes {
+                *file = self.rotate(rotation.keep, stats).await?;
+                continue;
+            }
+            let room = (rotation.max_bytes - len).min(bytes.len() as u64) as usize;
+            file.write_all(&bytes[..room]).await?;
+            bytes = &bytes[room..];
+        }
+        Ok(())
+    }
+
+    /// Shifts the rotated files along by one, dropping the oldest if
+    /// there are already `keep` of them, and moves the capture file into
+    /// the first slot. The capture file is linked into place before an
+    /// empty one is renamed over it, so it never goes missing for a
+    /// concurrent reader. Returns a handle on the new file.
+    async fn rotate(&self, keep: usize, stats: &StreamStats) -> io::Result<File> {
+        let oldest = rotated_path(&self.path, keep);
+        match fs::metadata(&oldest).await {
+            Ok(meta) => {
+                fs::remove_file(&oldest).await?;
+                stats.dropped_bytes.fetch_add(meta.len(), Ordering::Relaxed);
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        for n in (1..keep).rev() {
+            match fs::rename(rotated_path(&self.path, n), rotated_path(&self.path, n + 1)).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        fs::hard_link(&self.path, rotated_path(&self.path, 1)).await?;
+        let fresh = sibling_path(&self.path, "rotated");
+        fs::write(&fresh, b"").await?;
+        fs::rename(&fresh, &self.path).await?;
+        open_append(&self.path).await
+    }
+}
+
+/// Merges a child's stdout and stderr into the one stream, for a `Pump`
+/// to capture, with each line marked as `combine` says. Lines are only
+/// passed on once they're finished, so two streams' lines never get
+/// mixed up with each other, though one stream's may overtake a long line
+/// of the other's. The merged stream ends once both of the child's do.
+fn merge<O, E>(
+    id: PuppetId,
+    combine: Combine,
+    stdout: O,
+    stderr: E,
+    stdout_stats: Arc<StreamStats>,
+    stderr_stats: Arc<StreamStats>,
+) -> DuplexStream
+where
+    O: AsyncRead + Unpin + Send + 'static,
+    E: AsyncRead + Unpin + Send + 'static,
+{
+    let (merged, writer) = duplex(8 * 1024);
+    let writer = Arc::new(AsyncMutex::new(writer));
+    mark_lines(
+        id,
+        combine,
+        Stream::Stdout,
+        stdout,
+        stdout_stats,
+        Arc::clone(&writer),
+    );
+    mark_lines(id, combine, Stream::Stderr, stderr, stderr_stats, writer);
+    merged
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    stream: Stream,
+    line: &'a str,
+}
+
+/// Passes each line of `src` on to `writer`, marked as being from
+/// `stream`, counting the bytes read in `stats`. A last line without a
+/// newline gets one.
+fn mark_lines<R>(
+    id: PuppetId,
+    combine: Combine,
+    stream: Stream,
+    src: R,
+    stats: Arc<StreamStats>,
+    writer: Arc<AsyncMutex<DuplexStream>>,
+) where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    rocket::tokio::spawn(async move {
+        let mut src = BufReader::new(src);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match src.read_until(b'\n', &mut line).await {
+                Ok(0) => return,
+                Ok(n) => stats.add_written(n),
+                Err(err) => {
+                    warn!(
+                        "stopped combining {} of puppet id={} err={}",
+                        stream, id, err
+                    );
+                    return;
+                }
+            }
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            let mut marked = match combine {
+                Combine::Tagged => {
+                    let tag: &[u8] = match stream {
+                        Stream::Stdout => b"[out] ",
+                        Stream::Stderr => b"[err] ",
+                    };
+                    [tag, &line].concat()
+                }
+                Combine::Jsonl => serde_json::to_vec(&Line {
+                    stream,
+                    line: &String::from_utf8_lossy(&line),
+                })
+                .expect("a line should serialize to JSON"),
+            };
+            marked.push(b'\n');
+            // Only fails once the pump has stopped reading, and then
+            // there's nowhere for the rest to go.
+            if writer.lock().await.write_all(&marked).await.is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Reads `src` until it closes, throwing away what's read, for a stream
+/// whose capture has failed, e.g. because the disk filled up. The child
+/// can then carry on, rather than blocking on a full pipe or being killed
+/// by a `SIGPIPE` on writing to a closed one.
+async fn drain<R: AsyncRead + Unpin>(mut src: R, stats: &StreamStats) {
+    let mut buf = vec![0; 8 * 1024];
+    loop {
+        match src.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => stats.add_written(n),
+        }
+    }
+}
+
+/// Keeps a captured stream in memory rather than in a file, with only the
+/// most recent `max_bytes` of it if given. For small jobs, where creating
+/// a file per stream costs more than the output is worth.
+pub struct CaptureBuffer {
+    max_bytes: Option<u64>,
+    bytes: Mutex<Vec<u8>>,
+}
+
+impl CaptureBuffer {
+    pub fn new(max_bytes: Option<u64>) -> CaptureBuffer {
+        CaptureBuffer {
+            max_bytes,
+            bytes: Mutex::default(),
+        }
+    }
+
+    /// Copies `src` into the buffer until it closes. The copying runs on
+    /// a task of its own, so the child is never left blocked on a full
+    /// pipe.
+    pub fn start<R>(
+        self: Arc<Self>,
+        id: PuppetId,
+        stream: Stream,
+        src: R,
+        stats: Arc<StreamStats>,
+    ) -> JoinHandle<()>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        rocket::tokio::spawn(async move {
+            let mut src = src;
+            if let Err(err) = self.run(&mut src, &stats).await {
+                warn!(
+                    "stopped capturing {} of puppet id={} err={}",
+                    stream, id, err
+                );
+                stats.set_error(&err);
+                drain(src, &stats).await;
+            }
+        })
+    }
+
+    async fn run<R: AsyncRead + Unpin>(&self, mut src: R, stats: &StreamStats) -> io::Result<()> {
+        let mut buf = vec![0; 8 * 1024];
+        loop {
+            let n = src.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            stats.add_written(n);
+            let mut bytes = self.lock();
+            bytes.extend_from_slice(&buf[..n]);
+            let excess = match self.max_bytes {
+                Some(max_bytes) => (bytes.len() as u64).saturating_sub(max_bytes),
+                None => 0,
+            };
+            if excess > 0 {
+                bytes.drain(..excess as usize);
+                stats.dropped_bytes.fetch_add(excess, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Empties the buffer. Later output is kept as usual.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<u8>> {
+        self.bytes.lock().expect("capture buffer lock poisoned")
+    }
+}
+
+/// Where a captured stream can be read back from.
+#[derive(Clone)]
+pub enum Capture {
+    File(PathBuf),
+    Memory(Arc<CaptureBuffer>),
+}
+
+impl PartialEq for Capture {
+    fn eq(&self, other: &Capture) -> bool {
+        match (self, other) {
+            (Capture::File(path), Capture::File(other)) => path == other,
+            (Capture::Memory(buffer), Capture::Memory(other)) => Arc::ptr_eq(buffer, other),
+            _ => false,
+        }
+    }
+}
+
+impl Capture {
+    /// How many bytes the capture holds.
+    pub async fn size(&self) -> io::Result<u64> {
+        match self {
+            Capture::File(path) => fs::metadata(path).await.map(|meta| meta.len()),
+            Capture::Memory(buffer) => Ok(buffer.lock().len() as u64),
+        }
+    }
+
+    /// Reads the whole capture.
+    pub async fn read(&self) -> io::Result<Vec<u8>> {
+        match self {
+            Capture::File(path) => fs::read(path).await,
+            Capture::Memory(buffer) => Ok(buffer.lock().clone()),
+        }
+    }
+
+    /// Reads up to `len` bytes of the capture, starting `start` bytes in.
+    pub async fn read_at(&self, start: u64, len: u64) -> io::Result<Vec<u8>> {
+        match self {
+            Capture::File(path) => {
+                let mut file = File::open(path).await?;
+                file.seek(SeekFrom::Start(start)).await?;
+                let mut bytes = Vec::new();
+                file.take(len).read_to_end(&mut bytes).await?;
+                Ok(bytes)
+            }
+            Capture::Memory(buffer) => {
+                let bytes = buffer.lock();
+                let start = (start as usize).min(bytes.len());
+                let end = start.saturating_add(len as usize).min(bytes.len());
+                Ok(bytes[start..end].to_vec())
+            }
+        }
+    }
+
+    /// Reads the last `lines` lines of the capture, or all of it if it
+    /// has fewer. A file is read backwards a chunk at a time, so only
+    /// about as much of it as is returned is ever read.
+    pub async fn read_last_lines(&self, lines: u64) -> io::Result<Vec<u8>> {
+        let path = match self {
+            Capture::File(path) => path,
+            Capture::Memory(buffer) => {
+                let bytes = buffer.lock();
+                let start = last_lines_start(&bytes, lines).unwrap_or(0);
+                return Ok(bytes[start..].to_vec());
+            }
+        };
+        let mut file = File::open(path).await?;
+        let mut start = file.metadata().await?.len();
+        let mut tail = Vec::new();
+        while start > 0 {
+            if let Some(cut) = last_lines_start(&tail, lines) {
+                tail.drain(..cut);
+                return Ok(tail);
+            }
+            let chunk_start = start.saturating_sub(TAIL_CHUNK);
+            file.seek(SeekFrom::Start(chunk_start)).await?;
+            let mut chunk = vec![0; (start - chunk_start) as usize];
+            file.read_exact(&mut chunk).await?;
+            chunk.extend_from_slice(&tail);
+            tail = chunk;
+            start = chunk_start;
+        }
+        let cut = last_lines_start(&tail, lines).unwrap_or(0);
+        tail.drain(..cut);
+        Ok(tail)
+    }
+}
+
+/// Where a puppet's output streams are captured to, if anywhere.
+pub struct Captures {
+    pub stdout: Option<Capture>,
+    pub stderr: Option<Capture>,
+}
+
+impl Captures {
+    pub fn of(pup: &Puppet) -> Self {
+        Captures {
+            stdout: pup.capture(Stream::Stdout).ok(),
+            stderr: pup.capture(Stream::Stderr).ok(),
+        }
+    }
+}
+
+/// Reads whatever `capture` holds past `offset`, returning it and the
+/// offset just past it. A capture that has shrunk below `offset` is read
+/// from the start.
+pub async fn read_past(capture: &Capture, offset: u64) -> io::Result<(Vec<u8>, u64)> {
+    let len = capture.size().await?;
+    let start = if len < offset { 0 } else { offset };
+    let mut bytes = Vec::new();
+    if len > start {
+        bytes = capture.read_at(start, len - start).await?;
+    }
+    let end = start + bytes.len() as u64;
+    Ok((bytes, end))
+}
+
+/// Splits off as much of `bytes` as is valid UTF-8, returning it as text
+/// along with how many bytes it took. A character cut off at the end is
+/// left for next time, unless `last` says there won't be one; anything
+/// else that isn't UTF-8 is replaced.
+pub fn utf8_prefix(bytes: &[u8], last: bool) -> (String, usize) {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (String::from(text), bytes.len()),
+        Err(err) if err.error_len().is_none() && !last => {
+            let valid = &bytes[..err.valid_up_to()];
+            (String::from_utf8_lossy(valid).into_owned(), valid.len())
+        }
+        Err(_) => (String::from_utf8_lossy(bytes).into_owned(), bytes.len()),
+    }
+}
+
+/// Where the last `lines` lines of `bytes` start, or `None` if there
+/// aren't enough newlines in it to tell. A newline at the very end only
+/// ends the last line, rather than starting another.
+fn last_lines_start(bytes: &[u8], lines: u64) -> Option<usize> {
+    if lines == 0 {
+        return Some(bytes.len());
+    }
+    bytes
+        .strip_suffix(b"\n")
+        .unwrap_or(bytes)
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, &byte)| byte == b'\n')
+        .nth(lines as usize - 1)
+        .map(|(i, _)| i + 1)
+}
+
+/// Where the `n`th most recent rotation of the capture at `path` goes.
+pub fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    sibling_path(path, &n.to_string())
+}
+
+/// Appends `.<suffix>` to `path`. Unlike replacing its extension, this
+/// can't give two captures the same path, whatever they're named.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut sibling = OsString::from(path.as_os_str());
+    sibling.push(".");
+    sibling.push(suffix);
+    PathBuf::from(sibling)
+}
+
+/// Lists the rotations of the capture at `path` that exist, most recent
+/// first.
+pub fn rotations(path: &Path) -> Vec<PathBuf> {
+    (1..)
+        .map(|n| rotated_path(path, n))
+        .take_while(|rotated| rotated.exists())
+        .collect()
+}
+
+async fn open_append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().append(true).open(path).await
+}
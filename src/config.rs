@@ -0,0 +1,171 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::puppet::PuppetId;
+use crate::routes::{CaptureOptions, Stream};
+
+/// puppeteer-specific settings. These are read out of the same figment
+/// as Rocket's own configuration, so they can be set in `Rocket.toml` or
+/// through `ROCKET_`-prefixed environment variables.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Config {
+    /// Whether to hide the arguments of spawned commands in the logs.
+    pub redact_args: bool,
+    /// Where captured output is written. A fresh temporary directory,
+    /// removed on shutdown, is used if unset.
+    pub out_dir: Option<PathBuf>,
+    /// Where the temporary directory used in place of `out_dir` is made.
+    /// The system's temporary directory if unset.
+    pub temp_dir_parent: Option<PathBuf>,
+    /// What the name of the temporary directory used in place of
+    /// `out_dir` starts with, ahead of a random suffix, to make it easier
+    /// to pick out. `.tmp` if unset.
+    pub temp_dir_prefix: Option<String>,
+    /// The most puppets that may be running at once. Unbounded if unset.
+    pub max_puppets: Option<usize>,
+    /// The most puppets that may be spawned a second, on average, in
+    /// bursts of up to that many at once. Unlimited if unset or 0.
+    pub spawn_rate_per_sec: Option<u32>,
+    /// How long `/wait` waits for a puppet to exit before giving up.
+    /// Waits forever if unset.
+    pub default_timeout_ms: Option<u64>,
+    /// How long any puppet may run for before it's killed, whatever its
+    /// request asked for. Unlimited if unset.
+    pub max_runtime_ms: Option<u64>,
+    /// What kind of id new puppets are given.
+    pub id_scheme: IdScheme,
+    /// The most arguments a command may be given.
+    pub max_args: Option<usize>,
+    /// The most bytes the executable and its arguments may add up to.
+    pub max_command_bytes: Option<usize>,
+    /// Which streams are captured when a request doesn't say.
+    pub default_capture: DefaultCapture,
+    /// The only executables that may be run, if non-empty. Paths have to
+    /// match the requested executable exactly, while bare names allow
+    /// that name to be looked up on `PATH`.
+    pub allowed_execs: Vec<String>,
+    /// Refuse any executable that isn't an absolute path, so that nothing
+    /// is ever looked up on `PATH`.
+    pub require_absolute_exec: bool,
+    /// The most captured output of a stream that is included inline when
+    /// reporting how a puppet exited. 0 turns inlining off.
+    pub max_inline_output_bytes: Option<u64>,
+    /// What capture files are called, with `{stream}` standing for
+    /// `stdout` or `stderr`, `{id}` for the puppet's id and `{name}` for
+    /// its name, or its id if it has none. Has to include `{stream}` so
+    /// that the two streams don't share a file.
+    pub capture_file_name: Option<String>,
+    /// How long an exited puppet is kept track of before it's forgotten
+    /// and its captured output removed. 0 keeps it until it's deleted.
+    pub exited_ttl_ms: u64,
+    /// Environment variables whose names contain any of these, ignoring
+    /// case, have their values hidden by `/env`.
+    pub secret_env_patterns: Option<Vec<String>>,
+    /// A file to append a JSON line to for every puppet spawned, exited,
+    /// killed or signaled. Nothing is logged if unset.
+    pub event_log: Option<PathBuf>,
+    /// The umask puppets are started with, in place of the server's. Only
+    /// affects what they create themselves, not their capture files.
+    pub umask: Option<u32>,
+    /// The signal `/kill` sends when the request doesn't name one, e.g.
+    /// `TERM` to give puppets a chance to clean up. `KILL` if unset.
+    pub kill_signal: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DefaultCapture {
+    /// Both streams go through to the server's own.
+    #[default]
+    None,
+    Stdout,
+    Stderr,
+    All,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdScheme {
+    /// Integers counting up from 0. These start over whenever the server
+    /// restarts, so the same id can refer to different puppets over time.
+    #[default]
+    Sequential,
+    /// Random UUIDs, unique across restarts and hard to guess.
+    Uuid,
+}
+
+const DEFAULT_MAX_ARGS: usize = 1024;
+const DEFAULT_MAX_COMMAND_BYTES: usize = 1024 * 1024;
+const DEFAULT_MAX_INLINE_OUTPUT_BYTES: u64 = 4 * 1024;
+const DEFAULT_CAPTURE_FILE_NAME: &str = "{stream}";
+const DEFAULT_SECRET_ENV_PATTERNS: &[&str] =
+    &["SECRET", "TOKEN", "PASSWORD", "PASSWD", "KEY", "CREDENTIAL"];
+
+impl Config {
+    pub fn max_args(&self) -> usize {
+        self.max_args.unwrap_or(DEFAULT_MAX_ARGS)
+    }
+
+    pub fn max_command_bytes(&self) -> usize {
+        self.max_command_bytes.unwrap_or(DEFAULT_MAX_COMMAND_BYTES)
+    }
+
+    pub fn max_inline_output_bytes(&self) -> u64 {
+        self.max_inline_output_bytes
+            .unwrap_or(DEFAULT_MAX_INLINE_OUTPUT_BYTES)
+    }
+
+    pub fn capture_file_name(&self, stream: Stream, id: PuppetId, name: Option<&str>) -> String {
+        let id = id.to_string();
+        // Names can be anything, but the file has to stay in its directory.
+        let name = name.map_or_else(|| id.clone(), |name| name.replace('/', "_"));
+        self.capture_file_name
+            .as_deref()
+            .unwrap_or(DEFAULT_CAPTURE_FILE_NAME)
+            .replace("{stream}", &stream.to_string())
+            .replace("{id}", &id)
+            .replace("{name}", &name)
+    }
+
+    pub fn default_timeout(&self) -> Option<Duration> {
+        self.default_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn max_runtime(&self) -> Option<Duration> {
+        self.max_runtime_ms.map(Duration::from_millis)
+    }
+
+    pub fn exited_ttl(&self) -> Option<Duration> {
+        match self.exited_ttl_ms {
+            0 => None,
+            ttl_ms => Some(Duration::from_millis(ttl_ms)),
+        }
+    }
+
+    pub fn env_is_secret(&self, name: &str) -> bool {
+        let name = name.to_uppercase();
+        let matches = |pattern: &str| name.contains(&pattern.to_uppercase());
+        match &self.secret_env_patterns {
+            Some(patterns) => patterns.iter().any(|pattern| matches(pattern)),
+            None => DEFAULT_SECRET_ENV_PATTERNS
+                .iter()
+                .any(|pattern| matches(pattern)),
+        }
+    }
+
+    pub fn exec_allowed(&self, exec: &str) -> bool {
+        self.allowed_execs.is_empty() || self.allowed_execs.iter().any(|allowed| allowed == exec)
+    }
+
+    pub fn default_capture(&self) -> CaptureOptions {
+        match self.default_capture {
+            DefaultCapture::None => CaptureOptions::none(),
+            DefaultCapture::Stdout => CaptureOptions::stdout(),
+            DefaultCapture::Stderr => CaptureOptions::stderr(),
+            DefaultCapture::All => CaptureOptions::all(),
+        }
+    }
+}
@@ -1,37 +1,196 @@
-use std::io::Cursor;
+use std::io::{self, Cursor};
+use std::time::Duration;
 
 use rocket::{
-    http::ContentType,
+    http::{ContentType, Header, Status},
     response::{self, Responder},
     serde::json,
     Response,
 };
 use serde::{Deserialize, Serialize};
 
+use crate::puppet::PuppetId;
+use crate::routes::Stream;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("request too large: {0}")]
+    RequestTooLarge(String),
+    #[error("invalid id '{0}': ids are never negative")]
+    InvalidId(PuppetId),
     #[error("puppet with id '{0}' not found")]
-    PuppetNotFound(i32),
+    PuppetNotFound(PuppetId),
+    #[error("template '{0}' not found")]
+    TemplateNotFound(String),
+    #[error("puppet with id '{0}' did not capture {1}")]
+    StreamNotCaptured(PuppetId, Stream),
+    #[error("captured output of puppet with id '{0}' no longer exists")]
+    OutputUnavailable(PuppetId),
+    #[error("{1} of puppet with id '{0}' is not valid UTF-8")]
+    InvalidUtf8(PuppetId, Stream),
+    #[error("stdin of puppet with id '{0}' is not open")]
+    StdinClosed(PuppetId),
+    #[error("id '{0}' is in use by a running puppet")]
+    IdInUse(PuppetId),
+    #[error("puppet with id '{0}' is still running")]
+    StillRunning(PuppetId),
+    #[error("puppet with id '{0}' has already exited")]
+    NotRunning(PuppetId),
+    #[error("exec '{0}' is not allowed")]
+    ExecNotAllowed(String),
+    #[error("not permitted to run puppets with a nice value of {0}")]
+    NiceNotPermitted(i32),
+    #[error("not permitted to run puppets in the {0} I/O scheduling class")]
+    IoniceNotPermitted(&'static str),
+    #[error("not permitted to set the supplementary groups of puppets")]
+    GroupsNotPermitted,
+    #[error("cannot run more than {0} puppets at once")]
+    TooManyPuppets(usize),
+    #[error("the server is shutting down")]
+    ShuttingDown,
+    #[error("puppets are being spawned too quickly; retry in {}ms", .0.as_millis())]
+    RateLimited(Duration),
+    #[error("puppet with id '{0}' did not exit within {1}ms")]
+    WaitTimedOut(PuppetId, u128),
+    #[error("unsupported: {0}")]
+    Unsupported(String),
+    #[error("range not satisfiable: the output is {0} bytes long")]
+    RangeNotSatisfiable(u64),
+    #[error("failed to move puppet into cgroup '{0}': {1}")]
+    CgroupFailed(String, io::Error),
+    #[error("failed to set up capture of {stream}: {source}")]
+    CaptureSetupFailed { stream: Stream, source: io::Error },
     #[error("io error")]
-    IOError(#[from] std::io::Error),
+    Io(#[from] std::io::Error),
+}
+
+impl Error {
+    /// Wraps an error from accessing one of a puppet's capture files. A
+    /// missing file means it was removed out from under us, which is
+    /// worth telling apart from the stream never having been captured.
+    pub fn capture_io(id: PuppetId, err: io::Error) -> Error {
+        match err.kind() {
+            io::ErrorKind::NotFound => Error::OutputUnavailable(id),
+            _ => Error::Io(err),
+        }
+    }
+
+    /// Wraps an error from creating the capture of `stream`, which is
+    /// more use to whoever has to fix it than a bare io error.
+    pub fn capture_setup(stream: Stream, err: Error) -> Error {
+        match err {
+            Error::Io(source) => Error::CaptureSetupFailed { stream, source },
+            err => err,
+        }
+    }
+
+    fn status(&self) -> Status {
+        match self {
+            Error::InvalidRequest(_) | Error::InvalidId(_) => Status::BadRequest,
+            Error::RequestTooLarge(_) => Status::PayloadTooLarge,
+            Error::PuppetNotFound(_)
+            | Error::TemplateNotFound(_)
+            | Error::StreamNotCaptured(..) => Status::NotFound,
+            Error::OutputUnavailable(_) => Status::Gone,
+            Error::InvalidUtf8(..) => Status::UnprocessableEntity,
+            Error::StdinClosed(_)
+            | Error::IdInUse(_)
+            | Error::StillRunning(_)
+            | Error::NotRunning(_) => Status::Conflict,
+            Error::ExecNotAllowed(_)
+            | Error::NiceNotPermitted(_)
+            | Error::IoniceNotPermitted(_)
+            | Error::GroupsNotPermitted => Status::Forbidden,
+            Error::TooManyPuppets(_) | Error::ShuttingDown => Status::ServiceUnavailable,
+            Error::RateLimited(_) => Status::TooManyRequests,
+            Error::WaitTimedOut(..) => Status::RequestTimeout,
+            Error::RangeNotSatisfiable(_) => Status::RangeNotSatisfiable,
+            Error::Unsupported(_) => Status::NotImplemented,
+            Error::CgroupFailed(..) | Error::CaptureSetupFailed { .. } | Error::Io(_) => {
+                Status::InternalServerError
+            }
+        }
+    }
+
+    /// Names the kind of error, for clients to match on. Unlike the
+    /// message, these don't change.
+    fn code(&self) -> &'static str {
+        match self {
+            Error::InvalidRequest(_) => "invalid_request",
+            Error::RequestTooLarge(_) => "request_too_large",
+            Error::InvalidId(_) => "invalid_id",
+            Error::PuppetNotFound(_) => "puppet_not_found",
+            Error::TemplateNotFound(_) => "template_not_found",
+            Error::StreamNotCaptured(..) => "stream_not_captured",
+            Error::OutputUnavailable(_) => "output_unavailable",
+            Error::InvalidUtf8(..) => "invalid_utf8",
+            Error::StdinClosed(_) => "stdin_closed",
+            Error::IdInUse(_) => "id_in_use",
+            Error::StillRunning(_) => "still_running",
+            Error::NotRunning(_) => "not_running",
+            Error::ExecNotAllowed(_) => "exec_not_allowed",
+            Error::NiceNotPermitted(_) => "nice_not_permitted",
+            Error::IoniceNotPermitted(_) => "ionice_not_permitted",
+            Error::GroupsNotPermitted => "groups_not_permitted",
+            Error::TooManyPuppets(_) => "too_many_puppets",
+            Error::ShuttingDown => "shutting_down",
+            Error::RateLimited(_) => "rate_limited",
+            Error::WaitTimedOut(..) => "wait_timed_out",
+            Error::RangeNotSatisfiable(_) => "range_not_satisfiable",
+            Error::Unsupported(_) => "unsupported",
+            Error::CgroupFailed(..) => "cgroup_failed",
+            Error::CaptureSetupFailed { .. } => "capture_setup_failed",
+            Error::Io(_) => "io_error",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
-struct ErrorJSONResp {
-    err: String,
+pub struct ErrorJSONResp {
+    pub err: String,
+    pub code: String,
+}
+
+impl From<&Error> for ErrorJSONResp {
+    fn from(err: &Error) -> Self {
+        ErrorJSONResp {
+            err: format!("{}", err),
+            code: String::from(err.code()),
+        }
+    }
+}
+
+/// Whether the client would rather have errors as plain text, going by
+/// the media type it most prefers. Anything else gets JSON.
+fn wants_text(request: &rocket::Request<'_>) -> bool {
+    request
+        .accept()
+        .is_some_and(|accept| accept.preferred().media_type().is_plain())
 }
 
 impl<'r> Responder<'r, 'r> for Error {
     fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'r> {
-        let err_resp = ErrorJSONResp {
-            err: format!("{}", self),
+        let (content_type, body) = match wants_text(request) {
+            true => (ContentType::Plain, self.to_string()),
+            false => match json::to_string(&ErrorJSONResp::from(&self)) {
+                Ok(err_json) => (ContentType::JSON, err_json),
+                Err(err) => return response::Debug(err).respond_to(request),
+            },
         };
-        match json::to_string(&err_resp) {
-            Ok(err_json) => Response::build()
-                .header(ContentType::JSON)
-                .sized_body(err_json.len(), Cursor::new(err_json))
-                .ok(),
-            Err(err) => response::Debug(err).respond_to(request),
+        let mut resp = Response::build();
+        resp.status(self.status())
+            .header(content_type)
+            .sized_body(body.len(), Cursor::new(body));
+        if let Error::RateLimited(retry_after) = self {
+            // Retry-After only goes down to whole seconds.
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            resp.header(Header::new("Retry-After", secs.to_string()));
+        }
+        if let Error::RangeNotSatisfiable(len) = self {
+            resp.header(Header::new("Content-Range", format!("bytes */{}", len)));
         }
+        resp.ok()
     }
 }
@@ -0,0 +1,98 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rocket::serde::json::serde_json;
+use rocket::serde::Serialize;
+use rocket::tokio::sync::mpsc;
+
+use crate::puppet::{KillReason, PuppetId, PuppetState};
+
+/// Something that happened to a puppet, as recorded in the event log.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Spawn {
+        pid: u32,
+        exec: String,
+        /// Left out if `redact_args` is set.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        args: Option<Vec<String>>,
+    },
+    Exit {
+        state: PuppetState,
+    },
+    Kill {
+        reason: KillReason,
+    },
+    Signal {
+        signal: i32,
+    },
+}
+
+/// One line of the event log.
+#[derive(Serialize)]
+struct Line<'a> {
+    /// When the event happened, in milliseconds since the Unix epoch.
+    ts_ms: u64,
+    id: PuppetId,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Appends a JSON line to a file for each event recorded. The writing is
+/// done on a thread of its own, so recording an event never waits on the
+/// disk; lines are buffered up and flushed whenever the thread catches up.
+#[derive(Clone)]
+pub struct EventLog {
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl EventLog {
+    pub fn open(path: &Path) -> io::Result<EventLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        thread::Builder::new()
+            .name("event-log".into())
+            .spawn(move || write_lines(file, rx))?;
+        Ok(EventLog { tx })
+    }
+
+    pub fn record(&self, id: PuppetId, event: Event) {
+        let ts_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let line = Line {
+            ts_ms,
+            id,
+            event: &event,
+        };
+        let mut bytes = match serde_json::to_vec(&line) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                warn!("failed to serialize event for puppet id={} err={}", id, err);
+                return;
+            }
+        };
+        bytes.push(b'\n');
+        // The writer only goes away once every log has been dropped.
+        let _ = self.tx.send(bytes);
+    }
+}
+
+/// Writes out lines until every sender has hung up.
+fn write_lines(file: File, mut rx: mpsc::UnboundedReceiver<Vec<u8>>) {
+    let mut out = BufWriter::new(file);
+    while let Some(line) = rx.blocking_recv() {
+        let mut result = out.write_all(&line);
+        while let Ok(line) = rx.try_recv() {
+            result = result.and_then(|_| out.write_all(&line));
+        }
+        if let Err(err) = result.and_then(|_| out.flush()) {
+            warn!("failed to write to event log err={}", err);
+        }
+    }
+}
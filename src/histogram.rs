@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The upper bounds of the buckets durations are counted in, in
+/// milliseconds. Anything longer only counts towards the total.
+pub const BUCKETS_MS: [f64; 10] = [1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0];
+
+/// Counts durations into buckets, in the shape of a Prometheus histogram.
+/// Recording never blocks, so it can be done from anywhere.
+#[derive(Default)]
+pub struct Histogram {
+    /// How many durations fell into each bucket, and no lower one.
+    buckets: [AtomicU64; BUCKETS_MS.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+/// A point-in-time copy of a `Histogram`.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    /// How many durations were at most each of `BUCKETS_MS`, so that each
+    /// count includes the ones before it.
+    pub cumulative: [u64; BUCKETS_MS.len()],
+    pub count: u64,
+    pub sum_ms: f64,
+}
+
+impl Histogram {
+    pub fn record(&self, duration: Duration) {
+        let ms = duration.as_secs_f64() * 1000.0;
+        if let Some(bucket) = BUCKETS_MS.iter().position(|&bound| ms <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Copies out the counts. These are read one at a time, so one taken
+    /// while durations are being recorded may be slightly off.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut cumulative = [0; BUCKETS_MS.len()];
+        let mut total = 0;
+        for (count, bucket) in cumulative.iter_mut().zip(&self.buckets) {
+            total += bucket.load(Ordering::Relaxed);
+            *count = total;
+        }
+        Snapshot {
+            cumulative,
+            count: self.count.load(Ordering::Relaxed),
+            sum_ms: self.sum_us.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
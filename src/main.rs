@@ -1,58 +1,150 @@
-use rocket::tokio::sync::Mutex;
+use std::time::Duration;
 
-use crate::puppet::PuppetManager;
-use crate::routes::{cmd, kill, wait};
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use rocket::{Build, Rocket};
+
+use crate::config::Config;
+use crate::puppet::{kill_all, PuppetManager};
+use crate::routes::{
+    add_template, cmd, cmd_batch, cmd_from_template, env, info, kill, kill_matching, list, metrics,
+    openapi, output, output_multipart, remove, rename, replay, resume, rotations, rpc, run,
+    start_capture, stats, status, status_batch, stdin, suspend, truncate, wait, wait_any,
+    wait_batch, websocket,
+};
 
 #[macro_use]
 extern crate rocket;
 
+mod capture;
+mod config;
 mod error;
+mod events;
+mod histogram;
+mod pty;
 mod puppet;
+mod rate;
 mod routes;
+mod template;
+mod webhook;
+
+/// How long shutdown waits for killed puppets to be reaped before giving
+/// up on them.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
 
 #[launch]
 fn rocket() -> _ {
-    rocket::build()
-        .manage(Mutex::new(
-            PuppetManager::new().expect("failed to start up puppet manager"),
-        ))
+    build(rocket::Config::figment())
+}
+
+fn build(figment: Figment) -> Rocket<Build> {
+    let config: Config = figment
+        .extract()
+        .expect("failed to read puppeteer configuration");
+    rocket::custom(figment)
+        .manage(PuppetManager::new(config).expect("failed to start up puppet manager"))
         .mount("/", routes![cmd])
+        .mount("/", routes![cmd_batch])
+        .mount("/", routes![add_template])
+        .mount("/", routes![cmd_from_template])
         .mount("/", routes![wait])
+        .mount("/", routes![wait_batch])
+        .mount("/", routes![wait_any])
+        .mount("/", routes![run])
         .mount("/", routes![kill])
+        .mount("/", routes![suspend])
+        .mount("/", routes![resume])
+        .mount("/", routes![start_capture])
+        .mount("/", routes![kill_matching])
+        .mount("/", routes![remove])
+        .mount("/", routes![rename])
+        .mount("/", routes![truncate])
+        .mount("/", routes![rotations])
+        .mount("/", routes![metrics])
+        .mount("/", routes![stats])
+        .mount("/", routes![info])
+        .mount("/", routes![output])
+        .mount("/", routes![output_multipart])
+        .mount("/", routes![stdin])
+        .mount("/", routes![replay])
+        .mount("/", routes![websocket])
+        .mount("/", routes![status])
+        .mount("/", routes![status_batch])
+        .mount("/", routes![env])
+        .mount("/", routes![list])
+        .mount("/", routes![openapi])
+        .mount("/", routes![rpc])
+        .attach(AdHoc::on_liftoff("Forget exited puppets", |rocket| {
+            Box::pin(async move {
+                if let Some(sweeper) = rocket
+                    .state::<PuppetManager>()
+                    .and_then(|pups| pups.sweeper())
+                {
+                    rocket::tokio::spawn(sweeper.run());
+                }
+            })
+        }))
+        .attach(AdHoc::on_shutdown("Kill puppets", |rocket| {
+            Box::pin(async move {
+                if let Some(pups) = rocket.state::<PuppetManager>() {
+                    pups.shut_down();
+                    let procs = pups.attached();
+                    kill_all(procs, SHUTDOWN_GRACE).await;
+                }
+            })
+        }))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::routes::{CaptureOptions, CreateReq, CreateResp, WaitResp};
+    use crate::puppet::{PuppetId, StreamMode};
+    use crate::routes::{
+        CaptureOptions, CreateReq, CreateResp, RestartPolicy, RunResp, StatsResp, StatusResp,
+        WaitResp,
+    };
 
-    use super::rocket;
+    use super::{build, rocket};
     use core::time;
-    use rocket::{http::Status, local::blocking::Client};
+    use rocket::figment::{Figment, Provider};
+    use rocket::{
+        http::{ContentType, Status},
+        local::blocking::{Client, LocalResponse},
+    };
     use std::path::{Path, PathBuf};
     use uuid::Uuid;
 
-    const INHERITED: &str = "inherited";
-
     fn make_rocket_client() -> Client {
         Client::tracked(rocket()).unwrap()
     }
 
+    fn make_configured_client<P: Provider>(config: P) -> Client {
+        Client::tracked(build(rocket::Config::figment().merge(config))).unwrap()
+    }
+
+    fn create(client: &Client, req: &CreateReq) -> CreateResp {
+        client
+            .put("/cmd")
+            .json(req)
+            .dispatch()
+            .into_json::<CreateResp>()
+            .expect("expected non-None response for creating command")
+    }
+
     fn create_req(
         client: &Client,
         exec: &str,
         args: Vec<&str>,
         capture: CaptureOptions,
     ) -> CreateResp {
-        client
-            .put("/cmd")
-            .json(&CreateReq {
+        create(
+            client,
+            &CreateReq {
                 exec,
                 args,
                 capture: Some(capture),
-            })
-            .dispatch()
-            .into_json::<CreateResp>()
-            .expect("expected non-None response for creating command")
+                ..Default::default()
+            },
+        )
     }
 
     struct StdOutput {
@@ -67,30 +159,28 @@ mod tests {
         capture_opts: CaptureOptions,
     ) -> StdOutput {
         let create_resp = create_req(client, exec, args, capture_opts);
-        let wait_resp = wait_for_id(&client, create_resp.id);
+        let wait_resp = wait_for_id(client, create_resp.id);
         assert!(wait_resp.success);
         let mut output = StdOutput {
             stdout: String::from(""),
             stderr: String::from(""),
         };
         if capture_opts.stdout {
-            assert!(create_resp.stdout != "");
-            output.stdout = get_contents(&create_resp.stdout);
+            output.stdout = get_captured(&create_resp.stdout);
         } else {
-            assert_eq!(create_resp.stdout, INHERITED);
+            assert_eq!(create_resp.stdout, StreamMode::Inherited);
         }
 
         if capture_opts.stderr {
-            assert!(create_resp.stderr != "");
-            output.stderr = get_contents(&create_resp.stderr);
+            output.stderr = get_captured(&create_resp.stderr);
         } else {
-            assert_eq!(create_resp.stderr, INHERITED);
+            assert_eq!(create_resp.stderr, StreamMode::Inherited);
         }
 
         output
     }
 
-    fn wait_for_id(client: &Client, id: i32) -> WaitResp {
+    fn wait_for_id(client: &Client, id: PuppetId) -> WaitResp {
         client
             .post(format!("/wait/{}", id))
             .dispatch()
@@ -98,230 +188,3766 @@ mod tests {
             .expect("expected a non-None response for waiting on command")
     }
 
-    fn kill_id(client: &Client, id: i32) {
-        assert_eq!(
-            client.post(format!("/kill/{}", id)).dispatch().status(),
-            Status::Ok
-        )
+    fn kill_id(client: &Client, id: PuppetId) -> WaitResp {
+        let resp = client.post(format!("/kill/{}", id)).dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+        resp.into_json::<WaitResp>()
+            .expect("expected a non-None response for killing command")
+    }
+
+    fn get_status(client: &Client, id: PuppetId) -> StatusResp {
+        client
+            .get(format!("/status/{}", id))
+            .dispatch()
+            .into_json::<StatusResp>()
+            .expect("expected a non-None response for puppet status")
     }
 
     fn get_contents(filepath: &str) -> String {
-        std::fs::read_to_string(&filepath)
-            .expect(&format!("failed to open stdout file @ {}", filepath))
+        std::fs::read_to_string(filepath)
+            .unwrap_or_else(|_| panic!("failed to open stdout file @ {}", filepath))
+    }
+
+    fn captured_path(mode: &StreamMode) -> &str {
+        let path = mode.path().expect("expected the stream to be captured");
+        assert!(!path.is_empty());
+        path
+    }
+
+    fn get_captured(mode: &StreamMode) -> String {
+        get_contents(captured_path(mode))
     }
 
     fn get_testscript_path<P: AsRef<Path>>(name: P) -> PathBuf {
         let current_dir = std::env::current_dir().expect("failed to get current working directory");
-        return current_dir.join("testscripts").join(name);
+        current_dir.join("testscripts").join(name)
     }
 
     #[test]
     fn run_cmd_successfully() {
         let client = make_rocket_client();
         let create_resp = create_req(&client, "echo", vec!["-n", ""], CaptureOptions::none());
-        assert_eq!(create_resp.id, 0);
-        assert_eq!(create_resp.stdout, INHERITED);
-        assert_eq!(create_resp.stderr, INHERITED);
+        assert_eq!(create_resp.id, PuppetId::Seq(0));
+        assert_eq!(create_resp.stdout, StreamMode::Inherited);
+        assert_eq!(create_resp.stderr, StreamMode::Inherited);
         assert_ne!(create_resp.pid, 0);
         let wait_resp = wait_for_id(&client, create_resp.id);
         assert!(wait_resp.success);
     }
 
     #[test]
-    fn check_wait_resp_fields() {
+    fn refused_cmd_does_not_use_up_an_id() {
         let client = make_rocket_client();
+        let refused = client
+            .put("/cmd")
+            .json(&CreateReq {
+                exec: "/this/does/not/exist",
+                args: vec![],
+                capture: None,
+                ..Default::default()
+            })
+            .dispatch();
+        assert_ne!(refused.status(), Status::Created);
         let create_resp = create_req(&client, "echo", vec!["-n", ""], CaptureOptions::none());
-        let wait_resp = wait_for_id(&client, create_resp.id);
-        assert!(wait_resp.success);
-        assert_eq!(wait_resp.exit_code, 0);
-        assert!(!wait_resp.signaled);
-        assert_eq!(!wait_resp.signal_code, -1);
+        assert_eq!(create_resp.id, PuppetId::Seq(0));
+        assert!(wait_for_id(&client, create_resp.id).success);
     }
 
-    fn find_proc(pid: u32) -> Option<psutil::process::Process> {
-        psutil::process::processes()
-            .expect("failed to get a listing of system processes")
+    #[test]
+    fn batch_create_reports_per_command_results() {
+        use crate::routes::{BatchCreateReq, BatchCreateResp, BatchCreateResult};
+
+        let client = make_rocket_client();
+        let batch_resp = client
+            .put("/cmd/batch")
+            .json(&BatchCreateReq {
+                commands: vec![
+                    CreateReq {
+                        exec: "echo",
+                        args: vec!["-n", ""],
+                        capture: None,
+                        ..Default::default()
+                    },
+                    CreateReq {
+                        exec: "/this/does/not/exist",
+                        args: vec![],
+                        capture: None,
+                        ..Default::default()
+                    },
+                ],
+            })
+            .dispatch()
+            .into_json::<BatchCreateResp>()
+            .expect("expected non-None response for batch creating commands");
+
+        assert_eq!(batch_resp.results.len(), 2);
+        match &batch_resp.results[0] {
+            BatchCreateResult::Created(create_resp) => {
+                assert!(wait_for_id(&client, create_resp.id).success)
+            }
+            BatchCreateResult::Failed(err) => panic!("expected a spawn, got: {}", err.err),
+        }
+        assert!(matches!(
+            batch_resp.results[1],
+            BatchCreateResult::Failed(_)
+        ));
+    }
+
+    #[rocket::async_test]
+    async fn concurrent_spawns_get_unique_ids() {
+        use rocket::futures::future::join_all;
+        use rocket::local::asynchronous::Client;
+        use std::collections::BTreeSet;
+
+        const SPAWNS: i32 = 50;
+        let client = Client::tracked(rocket()).await.unwrap();
+        let spawns = (0..SPAWNS).map(|_| async {
+            client
+                .put("/cmd")
+                .json(&CreateReq {
+                    exec: "true",
+                    ..Default::default()
+                })
+                .dispatch()
+                .await
+                .into_json::<CreateResp>()
+                .await
+                .expect("expected non-None response for creating command")
+        });
+        let ids: BTreeSet<i32> = join_all(spawns)
+            .await
             .into_iter()
-            .find(|proc_res| proc_res.as_ref().map_or(false, |proc| proc.pid() == pid)) // Option<Result<ProcessResult<Process>>>
-            .map(|proc_res| proc_res.map_or(None, |proc| Some(proc))) // Option<Option<Process>>
-            .map(|proc| proc.expect("wtf")) // Option<Process>
+            .map(|create_resp| match create_resp.id {
+                PuppetId::Seq(id) => id,
+                PuppetId::Uuid(id) => panic!("expected a sequential id, got {}", id),
+            })
+            .collect();
+        assert_eq!(ids, (0..SPAWNS).collect());
     }
 
-    #[test]
-    fn kill_cmd() {
-        let client = make_rocket_client();
+    #[rocket::async_test]
+    async fn kill_during_wait() {
+        use rocket::local::asynchronous::Client;
+
+        let client = Client::tracked(rocket()).await.unwrap();
         let forever = get_testscript_path("forever.sh");
-        let create_resp = create_req(
-            &client,
-            forever
-                .to_str()
-                .expect("failed to unwrap forever script filepath"),
-            vec![],
-            CaptureOptions::none(),
+        let create_resp = client
+            .put("/cmd")
+            .json(&CreateReq {
+                exec: forever
+                    .to_str()
+                    .expect("failed to unwrap forever script filepath"),
+                ..Default::default()
+            })
+            .dispatch()
+            .await
+            .into_json::<CreateResp>()
+            .await
+            .expect("expected non-None response for creating command");
+
+        let wait_uri = format!("/wait/{}", create_resp.id);
+        let kill_uri = format!("/kill/{}", create_resp.id);
+        let (wait, kill, kill_again) = rocket::tokio::join!(
+            client.post(&wait_uri).dispatch(),
+            client.post(&kill_uri).dispatch(),
+            client.post(&kill_uri).dispatch(),
         );
-        assert_ne!(find_proc(create_resp.pid), None);
-        kill_id(&client, create_resp.id);
-        println!("ok killed {}", create_resp.pid);
-        while find_proc(create_resp.pid) != None {}
+        let mut resps = Vec::new();
+        for resp in [wait, kill, kill_again] {
+            assert_eq!(resp.status(), Status::Ok);
+            resps.push(resp.into_json::<WaitResp>().await.unwrap());
+        }
+        for resp in &resps {
+            assert!(resp.signaled);
+            assert_eq!(resp.signal_code, 9);
+            assert_eq!(resp.duration_ms, resps[0].duration_ms);
+        }
+        assert_eq!(find_proc(create_resp.pid), None);
     }
 
-    #[test]
-    fn cmd_inherits_from_server_env() {
-        let client = make_rocket_client();
-        let expected_env_var_key = format!("puppet-{}", Uuid::new_v4());
-        let expected_env_var_val = "blah";
-        std::env::set_var(&expected_env_var_key, expected_env_var_val);
-        let output =
-            run_cmd_and_get_output(&client, "env", vec![], CaptureOptions::stdout()).stdout;
-        output.contains(&format!(
-            "{}={}",
-            expected_env_var_key, expected_env_var_val
+    #[rocket::async_test]
+    async fn interrupted_waits_are_retried() {
+        use crate::puppet::{wait_uninterrupted, Waitable};
+        use std::future::{ready, Future};
+        use std::io;
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+
+        /// Fails each wait with the next of `errors`, then exits cleanly.
+        struct Flaky {
+            errors: Vec<io::ErrorKind>,
+            waits: usize,
+        }
+
+        impl Waitable for Flaky {
+            fn wait(&mut self) -> impl Future<Output = io::Result<ExitStatus>> + Send + '_ {
+                self.waits += 1;
+                ready(match self.errors.pop() {
+                    Some(kind) => Err(io::Error::from(kind)),
+                    None => Ok(ExitStatus::from_raw(0)),
+                })
+            }
+        }
+
+        let mut child = Flaky {
+            errors: vec![io::ErrorKind::Interrupted; 2],
+            waits: 0,
+        };
+        assert!(wait_uninterrupted(&mut child).await.unwrap().success());
+        assert_eq!(child.waits, 3);
+
+        // Anything else is given up on straight away.
+        let mut child = Flaky {
+            errors: vec![io::ErrorKind::Interrupted, io::ErrorKind::BrokenPipe],
+            waits: 0,
+        };
+        let err = wait_uninterrupted(&mut child).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(child.waits, 1);
+    }
+
+    #[rocket::async_test]
+    async fn websocket_forwards_stdin_and_output() {
+        use crate::puppet::PuppetManager;
+        use crate::routes::{Stream, WsFrame};
+        use rocket::futures::{SinkExt, StreamExt};
+        use rocket::tokio::time::sleep;
+        use tokio_tungstenite::{connect_async, tungstenite};
+
+        async fn next_frame<S>(socket: &mut S) -> WsFrame
+        where
+            S: StreamExt<Item = Result<tungstenite::Message, tungstenite::Error>> + Unpin,
+        {
+            match socket.next().await {
+                Some(Ok(tungstenite::Message::Text(text))) => {
+                    rocket::serde::json::from_str(&text).expect("expected a valid frame")
+                }
+                msg => panic!("expected a frame, got: {:?}", msg),
+            }
+        }
+
+        // Local clients can't upgrade connections, so this takes a real
+        // server.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("failed to find a free port")
+            .port();
+        let rocket = build(rocket::Config::figment().merge(("port", port)))
+            .ignite()
+            .await
+            .unwrap();
+        let mut proc = {
+            let pups = rocket.state::<PuppetManager>().unwrap();
+            let req = CreateReq {
+                exec: "cat",
+                args: vec![],
+                capture: Some(CaptureOptions::stdout()),
+                stdin: true,
+                ..Default::default()
+            };
+            pups.push(&req).expect("failed to spawn cat").proc()
+        };
+        let shutdown = rocket.shutdown();
+        rocket::tokio::spawn(rocket.launch());
+
+        let url = format!("ws://127.0.0.1:{}/ws/{}", port, proc.id());
+        let mut socket = loop {
+            match connect_async(&url).await {
+                Ok((socket, _)) => break socket,
+                // The server may not be listening yet.
+                Err(_) => sleep(time::Duration::from_millis(10)).await,
+            }
+        };
+        let line = String::from("hello\n");
+        socket
+            .send(tungstenite::Message::Text(line.clone()))
+            .await
+            .unwrap();
+        let mut echoed = String::new();
+        while echoed.len() < line.len() {
+            match next_frame(&mut socket).await {
+                WsFrame::Output { stream, data } => {
+                    assert_eq!(stream, Stream::Stdout);
+                    echoed.push_str(&data);
+                }
+                _ => panic!("expected output"),
+            }
+        }
+        assert_eq!(echoed, line);
+
+        proc.kill().await.unwrap();
+        match next_frame(&mut socket).await {
+            WsFrame::Exit(wait_resp) => {
+                assert_eq!(wait_resp.id, proc.id());
+                assert!(wait_resp.signaled);
+            }
+            _ => panic!("expected an exit"),
+        }
+        assert!(matches!(
+            socket.next().await,
+            Some(Ok(tungstenite::Message::Close(_)))
         ));
+        shutdown.notify();
     }
 
     #[test]
-    fn can_stream_cmd_output_without_wait() {
+    fn wait_after_kill() {
         let client = make_rocket_client();
+        let create_resp = create_req(&client, "sleep", vec!["100"], CaptureOptions::none());
+        let kill_resp = kill_id(&client, create_resp.id);
+
+        // The kill only reports the exit the reaper recorded, which every
+        // later wait, and kill, reports too.
+        let wait_resp = wait_for_id(&client, create_resp.id);
+        assert_eq!(wait_resp.id, create_resp.id);
+        assert!(wait_resp.signaled);
+        assert!(!wait_resp.success);
+        assert_eq!(wait_resp.signal_code, 9);
+        assert_eq!(wait_resp.exit_code, kill_resp.exit_code);
+        assert_eq!(wait_resp.duration_ms, kill_resp.duration_ms);
+        assert_eq!(
+            get_status(&client, create_resp.id).state,
+            crate::puppet::PuppetState::Killed
+        );
+        let again = wait_for_id(&client, create_resp.id);
+        assert_eq!(again.signal_code, wait_resp.signal_code);
+        assert_eq!(again.duration_ms, wait_resp.duration_ms);
+        let killed_again = kill_id(&client, create_resp.id);
+        assert_eq!(killed_again.signal_code, kill_resp.signal_code);
+        assert_eq!(killed_again.duration_ms, kill_resp.duration_ms);
+    }
 
+    #[test]
+    fn suspend_and_resume() {
+        let client = make_rocket_client();
         let periodic_print = get_testscript_path("periodic.sh");
         let create_resp = create_req(
             &client,
             periodic_print
                 .to_str()
                 .expect("failed to unwrap periodic script filepath"),
-            vec!["bar"],
+            vec![],
             CaptureOptions::stdout(),
         );
-        assert_eq!(create_resp.id, 0);
-        assert!(create_resp.stdout != "");
-        assert_eq!(create_resp.stderr, INHERITED);
-
-        let get_last_num = || loop {
-            let contents = get_contents(&create_resp.stdout);
-            if contents.len() > 0 {
-                let last_line = contents
-                    .split("\n")
-                    .last()
-                    .expect("expected a non-zero length periodic output to have a last line");
-                if let Ok(last_num) = last_line.parse::<i32>() {
-                    break last_num;
-                } else {
-                    // It is possible that we end up picking up the
-                    // very first line of the file, which would be an
-                    // empty line with only a newline. It is fairly
-                    // rare, but possible as long as the threads align
-                    // properly.
-                    continue;
-                }
+        let captured_len = || get_captured(&create_resp.stdout).len();
+        let wait_for_output = |past: usize| {
+            let deadline = std::time::Instant::now() + time::Duration::from_secs(5);
+            while captured_len() <= past {
+                assert!(
+                    std::time::Instant::now() < deadline,
+                    "output stopped advancing"
+                );
+                std::thread::sleep(time::Duration::from_millis(50));
             }
         };
+        wait_for_output(0);
 
-        // The logic is as follows, given that the script is just outputting a monotonically increasing integer every second:
-        //	1. Keep the loop going until it finds any amount of output.
-        //	2. Once output is found, find the last line of that output, and save it.
-        //  3. Run a loop again, repeatedly finding the last line.
-        //  4. Keep doing this until you find a last-line that shows a number greater than the one saved in step 2.
-        // This proves that we are finding data that is being continuously streamed.
-        let last_num = get_last_num();
+        let post = |action: &str| {
+            client
+                .post(format!("/{}/{}", action, create_resp.id))
+                .dispatch()
+                .status()
+        };
+        assert_eq!(post("suspend"), Status::Ok);
+        assert!(get_status(&client, create_resp.id).suspended);
+        // The script prints every second, so give it a couple of chances.
+        let suspended_len = captured_len();
+        std::thread::sleep(time::Duration::from_millis(2500));
+        assert_eq!(captured_len(), suspended_len);
 
-        const DELAY: time::Duration = time::Duration::from_millis(100);
-        const MAX_ATTEMPTS: i32 = 100; // delay * max_attempts = 10 seconds. Should be more than enough.
-        let mut attempts = 0;
-        while get_last_num() == last_num {
-            std::thread::sleep(DELAY);
-            attempts += 1;
-            assert!(attempts < MAX_ATTEMPTS);
-        }
+        assert_eq!(post("resume"), Status::Ok);
+        assert!(!get_status(&client, create_resp.id).suspended);
+        wait_for_output(suspended_len);
 
-        // If we get here, we found a differing number -- we've passed.
-        // Let's clean-up by killing that script we ran, since it'll otherwise run for a really long time:
         kill_id(&client, create_resp.id);
+        assert_eq!(post("suspend"), Status::Conflict);
+        assert!(!get_status(&client, create_resp.id).suspended);
     }
 
-    mod captures {
-        use super::*;
+    #[rocket::async_test]
+    async fn abandoned_wait_leaves_puppet_alone() {
+        use rocket::local::asynchronous::Client;
+        use rocket::tokio::time::timeout;
 
-        #[test]
-        fn stdout() {
-            let client = make_rocket_client();
-            let expected_output = "bar";
-            assert_eq!(
-                run_cmd_and_get_output(
-                    &client,
-                    "echo",
-                    vec![expected_output],
-                    CaptureOptions::stdout()
-                )
-                .stdout,
-                format!("{}\n", expected_output)
-            );
-        }
+        let client = Client::tracked(rocket()).await.unwrap();
+        let create_resp = client
+            .put("/cmd")
+            .json(&CreateReq {
+                exec: "sleep",
+                args: vec!["100"],
+                ..Default::default()
+            })
+            .dispatch()
+            .await
+            .into_json::<CreateResp>()
+            .await
+            .expect("expected non-None response for creating command");
 
-        #[test]
-        fn stderr() {
-            let client = make_rocket_client();
-            let expected_output = "bar";
-            let stderr_print = get_testscript_path("stderr.sh");
-            assert_eq!(
-                run_cmd_and_get_output(
-                    &client,
-                    stderr_print
-                        .to_str()
-                        .expect("failed to unwrap stderr script filepath"),
-                    vec![expected_output],
-                    CaptureOptions::stderr()
-                )
-                .stderr,
-                format!("{}\n", expected_output)
-            );
-        }
+        // Dropping the request part way through is as close as the local
+        // client gets to hanging up.
+        let wait = client.post(format!("/wait/{}", create_resp.id)).dispatch();
+        assert!(timeout(time::Duration::from_millis(200), wait)
+            .await
+            .is_err());
 
-        #[test]
-        fn both() {
-            let client = make_rocket_client();
-            // TODO: we should maybe emit two different values for stdout v stderr -- this tests we aren't mixing the two up.
-            let expected_output = "bar";
-            let both_std_print = get_testscript_path("both_std.sh");
-            let output = run_cmd_and_get_output(
-                &client,
-                both_std_print
-                    .to_str()
-                    .expect("failed to unwrap stderr script filepath"),
-                vec![expected_output],
-                CaptureOptions::all(),
-            );
-            assert_eq!(output.stdout, format!("{}\n", expected_output));
-            assert_eq!(output.stderr, format!("{}\n", expected_output));
-        }
+        let status = timeout(
+            time::Duration::from_secs(1),
+            client.get(format!("/status/{}", create_resp.id)).dispatch(),
+        )
+        .await
+        .expect("status should not be held up by the abandoned wait")
+        .into_json::<StatusResp>()
+        .await
+        .unwrap();
+        assert!(status.running);
+        assert_ne!(find_proc(create_resp.pid), None);
+
+        client
+            .post(format!("/kill/{}", create_resp.id))
+            .dispatch()
+            .await;
     }
 
-    // TODO: Need to test error cases:
-    // * double-wait
-    // * double-kill
-    // * wait after kill
-    // * exec that DNE
-    // * exec that isn't an exec?
+    #[test]
+    fn batch_wait_on_several_puppets() {
+        use crate::routes::{BatchWaitReq, BatchWaitResult};
 
-    mod errors {
-        use serde::{Deserialize, Serialize};
+        let client = make_rocket_client();
+        let mut ids: Vec<PuppetId> = (0..3)
+            .map(|_| create_req(&client, "echo", vec!["-n", ""], CaptureOptions::none()).id)
+            .collect();
+        let unknown_id = PuppetId::Seq(243423423);
+        ids.push(unknown_id);
 
-        use super::*;
+        let results = client
+            .post("/wait/batch")
+            .json(&BatchWaitReq { ids: ids.clone() })
+            .dispatch()
+            .into_json::<Vec<BatchWaitResult>>()
+            .expect("expected non-None response for batch waiting on commands");
 
-        #[derive(Serialize, Deserialize)]
-        struct ErrorJSONResp {
-            err: String,
+        assert_eq!(results.len(), ids.len());
+        for (result, id) in results.iter().zip(&ids[..3]) {
+            match result {
+                BatchWaitResult::Exited(wait_resp) => {
+                    assert_eq!(wait_resp.id, *id);
+                    assert!(wait_resp.success);
+                }
+                BatchWaitResult::Failed(err) => panic!("expected an exit, got: {}", err.err),
+            }
+        }
+        match &results[3] {
+            BatchWaitResult::Failed(err) => assert_eq!(
+                err.err,
+                format!("puppet with id '{}' not found", unknown_id)
+            ),
+            BatchWaitResult::Exited(_) => panic!("expected an unknown id to fail"),
         }
+    }
 
-        #[test]
-        fn puppet_dne() {
-            let client = make_rocket_client();
-            let fake_id = 243423423;
-            let resp = client.post(format!("/wait/{}", fake_id)).dispatch();
-            let err_json = resp.into_json::<ErrorJSONResp>().unwrap();
-            assert_eq!(
-                err_json.err,
-                format!("puppet with id '{}' not found", fake_id)
-            );
+    #[test]
+    fn wait_any_returns_first_to_exit() {
+        use crate::routes::BatchWaitReq;
+
+        let client = make_rocket_client();
+        let slow = create_req(&client, "sleep", vec!["5"], CaptureOptions::none()).id;
+        let fast = create_req(&client, "sleep", vec!["0.1"], CaptureOptions::none()).id;
+
+        let wait_resp = client
+            .post("/wait/any")
+            .json(&BatchWaitReq {
+                ids: vec![slow, fast],
+            })
+            .dispatch()
+            .into_json::<WaitResp>()
+            .expect("expected non-None response for waiting on any command");
+        assert_eq!(wait_resp.id, fast);
+        assert!(wait_resp.success);
+        assert!(get_status(&client, slow).running);
+
+        let resp = client
+            .post("/wait/any")
+            .json(&BatchWaitReq {
+                ids: vec![slow, PuppetId::Seq(243423423)],
+            })
+            .dispatch();
+        assert_eq!(resp.status(), Status::NotFound);
+        kill_id(&client, slow);
+    }
+
+    #[test]
+    fn capture_to_fifo() {
+        use std::io::{BufRead, BufReader};
+
+        let client = make_rocket_client();
+        let create_resp = create(
+            &client,
+            &CreateReq {
+                exec: "sh",
+                args: vec!["-c", "echo hi && sleep 100"],
+                capture: Some(CaptureOptions {
+                    fifo: true,
+                    ..CaptureOptions::stdout()
+                }),
+                ..Default::default()
+            },
+        );
+        let path = match &create_resp.stdout {
+            StreamMode::Fifo { path } => path,
+            mode => panic!("expected a fifo, got {:?}", mode),
+        };
+        let fifo = std::fs::File::open(path).expect("failed to open fifo");
+        let mut line = String::new();
+        BufReader::new(fifo).read_line(&mut line).unwrap();
+        assert_eq!(line, "hi\n");
+
+        let resp = client
+            .get(format!("/output/{}/stdout", create_resp.id))
+            .dispatch();
+        assert_eq!(resp.status(), Status::NotFound);
+        kill_id(&client, create_resp.id);
+    }
+
+    #[test]
+    fn stream_modes_are_tagged() {
+        use rocket::serde::json::Value;
+
+        let client = make_rocket_client();
+        let resp = client
+            .put("/cmd")
+            .json(&CreateReq {
+                exec: "echo",
+                args: vec!["-n", ""],
+                capture: Some(CaptureOptions::stdout()),
+                ..Default::default()
+            })
+            .dispatch()
+            .into_json::<Value>()
+            .expect("expected non-None response for creating command");
+
+        assert_eq!(resp["stdout"]["mode"], "captured");
+        assert!(resp["stdout"]["path"].is_string());
+        assert_eq!(resp["stderr"]["mode"], "inherited");
+        assert!(resp["stderr"].get("path").is_none());
+        let id = PuppetId::Seq(resp["id"].as_i64().expect("expected an integer id") as i32);
+        assert!(wait_for_id(&client, id).success);
+    }
+
+    fn run(client: &Client, exec: &str, args: Vec<&str>, query: &str) -> RunResp {
+        client
+            .put(format!("/run{}", query))
+            .json(&CreateReq {
+                exec,
+                args,
+                capture: Some(CaptureOptions::stdout()),
+                ..Default::default()
+            })
+            .dispatch()
+            .into_json::<RunResp>()
+            .expect("expected a non-None response for running command")
+    }
+
+    #[test]
+    fn run_inlines_output() {
+        let client = make_rocket_client();
+        let run_resp = run(&client, "echo", vec!["hi"], "");
+        assert!(run_resp.wait.success);
+        assert!(!run_resp.timed_out);
+        assert_eq!(run_resp.stdout.as_deref(), Some("hi\n"));
+        assert_eq!(run_resp.stderr, None);
+    }
+
+    #[test]
+    fn run_encodes_binary_output() {
+        use base64::engine::general_purpose::STANDARD as BASE64;
+        use base64::Engine;
+
+        let client = make_rocket_client();
+        let bytes_print = get_testscript_path("bytes.sh");
+        let bytes_print = bytes_print
+            .to_str()
+            .expect("failed to unwrap bytes script filepath");
+        let run_resp = run(&client, bytes_print, vec!["00", "ff", "68"], "?base64=true");
+        let decoded = BASE64
+            .decode(run_resp.stdout_b64.expect("expected base64 stdout"))
+            .expect("expected valid base64");
+        assert_eq!(decoded, vec![0x00, 0xff, 0x68]);
+        assert_eq!(run_resp.stderr_b64, None);
+
+        let run_resp = run(&client, bytes_print, vec!["68"], "");
+        assert_eq!(run_resp.stdout.as_deref(), Some("h"));
+        assert_eq!(run_resp.stdout_b64, None);
+    }
+
+    #[test]
+    fn run_stringifies_typed_args() {
+        let client = make_rocket_client();
+        let run_typed = |args: &str| {
+            let capture = rocket::serde::json::to_string(&CaptureOptions::stdout()).unwrap();
+            client
+                .put("/run")
+                .header(ContentType::JSON)
+                .body(format!(
+                    r#"{{"exec": "echo", "args": {}, "capture": {}}}"#,
+                    args, capture
+                ))
+                .dispatch()
+        };
+
+        let run_resp = run_typed(r#"["x", 42, true, -1.5]"#)
+            .into_json::<RunResp>()
+            .expect("expected typed args to be accepted");
+        assert_eq!(run_resp.stdout.as_deref(), Some("x 42 true -1.5\n"));
+
+        for args in [r#"["x", [1]]"#, r#"[{"a": 1}]"#, "[null]"] {
+            assert_eq!(run_typed(args).status(), Status::UnprocessableEntity);
+        }
+    }
+
+    #[test]
+    fn run_kills_on_timeout() {
+        let client = make_rocket_client();
+        let forever = get_testscript_path("forever.sh");
+        let run_resp = run(
+            &client,
+            forever
+                .to_str()
+                .expect("failed to unwrap forever script filepath"),
+            vec![],
+            "?timeout_ms=100",
+        );
+        assert!(run_resp.timed_out);
+        assert!(run_resp.wait.signaled);
+        assert!(run_resp.stdout.is_some());
+    }
+
+    #[test]
+    fn run_returns_partial_output_on_timeout() {
+        let client = make_rocket_client();
+        let periodic_print = get_testscript_path("periodic.sh");
+        let run_resp = run(
+            &client,
+            periodic_print
+                .to_str()
+                .expect("failed to unwrap periodic script filepath"),
+            vec![],
+            "?timeout_ms=1500",
+        );
+        assert!(run_resp.timed_out);
+        assert!(run_resp
+            .stdout
+            .expect("expected captured stdout")
+            .starts_with("\n0"));
+    }
+
+    #[test]
+    fn create_resp_echoes_command() {
+        let client = make_rocket_client();
+        let create_resp = create_req(
+            &client,
+            "echo",
+            vec!["-n", "foo bar"],
+            CaptureOptions::none(),
+        );
+        assert_eq!(create_resp.command, vec!["echo", "-n", "foo bar"]);
+        assert!(wait_for_id(&client, create_resp.id).success);
+    }
+
+    #[test]
+    fn duration_is_reported_once_exited() {
+        let client = make_rocket_client();
+        let create_resp = create_req(&client, "sleep", vec!["1"], CaptureOptions::none());
+        let status = client
+            .get(format!("/status/{}", create_resp.id))
+            .dispatch()
+            .into_json::<StatusResp>()
+            .expect("expected a non-None response for puppet status");
+        assert!(status.running);
+        assert_eq!(status.duration_ms, None);
+        assert_ne!(status.started_at, 0);
+
+        let wait_resp = wait_for_id(&client, create_resp.id);
+        assert_eq!(wait_resp.started_at, status.started_at);
+        assert!(wait_resp.duration_ms >= 1000);
+        let listed = client
+            .get("/list")
+            .dispatch()
+            .into_json::<Vec<StatusResp>>()
+            .expect("expected a non-None response for listing puppets");
+        assert_eq!(listed[0].duration_ms, Some(wait_resp.duration_ms));
+    }
+
+    #[test]
+    fn check_wait_resp_fields() {
+        let client = make_rocket_client();
+        let create_resp = create_req(&client, "echo", vec!["-n", ""], CaptureOptions::none());
+        let wait_resp = wait_for_id(&client, create_resp.id);
+        assert!(wait_resp.success);
+        assert_eq!(wait_resp.exit_code, 0);
+        assert!(!wait_resp.signaled);
+        assert_eq!(!wait_resp.signal_code, -1);
+    }
+
+    #[test]
+    fn failed_wait_includes_stderr_tail() {
+        let client = make_rocket_client();
+        let create_resp = create_req(
+            &client,
+            "sh",
+            vec!["-c", "echo starting; echo no such file >&2; exit 1"],
+            CaptureOptions::all(),
+        );
+        let wait_resp = wait_for_id(&client, create_resp.id);
+        assert!(!wait_resp.success);
+        assert_eq!(wait_resp.stderr_tail.as_deref(), Some("no such file\n"));
+
+        let create_resp = create_req(&client, "true", vec![], CaptureOptions::all());
+        assert_eq!(wait_for_id(&client, create_resp.id).stderr_tail, None);
+    }
+
+    #[test]
+    fn wait_inlines_small_output() {
+        let client = make_rocket_client();
+        let small = create_req(&client, "echo", vec!["hi"], CaptureOptions::stdout());
+        let wait_resp = wait_for_id(&client, small.id);
+        let stdout = wait_resp.stdout.expect("expected stdout to be reported");
+        assert!(stdout.inlined);
+        assert_eq!(stdout.content.as_deref(), Some("hi\n"));
+        assert_eq!(stdout.path.as_deref(), Some(captured_path(&small.stdout)));
+        assert!(wait_resp.stderr.is_none());
+
+        let large = create_req(&client, "seq", vec!["10000"], CaptureOptions::stdout());
+        let stdout = wait_for_id(&client, large.id)
+            .stdout
+            .expect("expected stdout to be reported");
+        assert!(!stdout.inlined);
+        assert_eq!(stdout.content, None);
+        assert_eq!(stdout.path.as_deref(), Some(captured_path(&large.stdout)));
+    }
+
+    #[cfg(feature = "camel-case")]
+    #[test]
+    fn camel_case_field_names() {
+        let client = make_rocket_client();
+        let create_resp = create_req(&client, "true", vec![], CaptureOptions::none());
+        let body = client
+            .post(format!("/wait/{}", create_resp.id))
+            .dispatch()
+            .into_string()
+            .expect("expected a body for waiting on command");
+        assert!(body.contains("\"exitCode\":0"), "got {}", body);
+        assert!(!body.contains("exit_code"), "got {}", body);
+    }
+
+    #[test]
+    fn exited_puppets_are_forgotten_after_ttl() {
+        let client = make_configured_client(("exited_ttl_ms", 200));
+        let create_resp = create_req(&client, "echo", vec!["hi"], CaptureOptions::stdout());
+        wait_for_id(&client, create_resp.id);
+        let list = || {
+            client
+                .get("/list")
+                .dispatch()
+                .into_json::<Vec<StatusResp>>()
+                .expect("expected a non-None response for listing puppets")
+        };
+        assert_eq!(list().len(), 1);
+
+        std::thread::sleep(time::Duration::from_millis(600));
+        assert!(list().is_empty());
+        assert_eq!(
+            client
+                .get(format!("/status/{}", create_resp.id))
+                .dispatch()
+                .status(),
+            Status::NotFound
+        );
+        assert!(!Path::new(&captured_path(&create_resp.stdout)).exists());
+    }
+
+    fn find_proc(pid: u32) -> Option<psutil::process::Process> {
+        psutil::process::processes()
+            .expect("failed to get a listing of system processes")
+            .into_iter()
+            .find(|proc_res| proc_res.as_ref().is_ok_and(|proc| proc.pid() == pid)) // Option<Result<ProcessResult<Process>>>
+            .map(|proc_res| proc_res.ok()) // Option<Option<Process>>
+            .map(|proc| proc.expect("wtf")) // Option<Process>
+    }
+
+    #[test]
+    fn kill_cmd() {
+        let client = make_rocket_client();
+        let forever = get_testscript_path("forever.sh");
+        let create_resp = create_req(
+            &client,
+            forever
+                .to_str()
+                .expect("failed to unwrap forever script filepath"),
+            vec![],
+            CaptureOptions::none(),
+        );
+        assert_ne!(find_proc(create_resp.pid), None);
+        let kill_resp = kill_id(&client, create_resp.id);
+        assert_eq!(kill_resp.id, create_resp.id);
+        assert!(kill_resp.signaled);
+        assert_eq!(kill_resp.signal_code, 9);
+        assert!(!kill_resp.success);
+        println!("ok killed {}", create_resp.pid);
+        while find_proc(create_resp.pid).is_some() {}
+    }
+
+    #[test]
+    fn detached_puppets_outlive_shutdown() {
+        let client = make_rocket_client();
+        let create_resp = create(
+            &client,
+            &CreateReq {
+                exec: "sleep",
+                args: vec!["100"],
+                detach: true,
+                ..Default::default()
+            },
+        );
+        let status = client
+            .get(format!("/status/{}", create_resp.id))
+            .dispatch()
+            .into_json::<StatusResp>()
+            .unwrap();
+        assert!(status.running);
+        let pid = create_resp.pid as libc::pid_t;
+        assert_eq!(unsafe { libc::getsid(pid) }, pid);
+
+        client.terminate();
+        assert_ne!(find_proc(create_resp.pid), None);
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+    }
+
+    #[test]
+    fn setsid_puppets_are_still_tracked() {
+        let client = make_rocket_client();
+        let create_resp = create(
+            &client,
+            &CreateReq {
+                exec: "sleep",
+                args: vec!["100"],
+                setsid: true,
+                ..Default::default()
+            },
+        );
+        let pid = create_resp.pid as libc::pid_t;
+        assert_eq!(unsafe { libc::getsid(pid) }, pid);
+        assert!(get_status(&client, create_resp.id).running);
+        kill_id(&client, create_resp.id);
+        let wait_resp = wait_for_id(&client, create_resp.id);
+        assert!(wait_resp.signaled);
+        assert!(!get_status(&client, create_resp.id).running);
+
+        let create_resp = create(
+            &client,
+            &CreateReq {
+                exec: "sh",
+                args: vec!["-c", "echo leader; exit 3"],
+                capture: Some(CaptureOptions::stdout()),
+                setsid: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(wait_for_id(&client, create_resp.id).exit_code, 3);
+        assert_eq!(get_captured(&create_resp.stdout), "leader\n");
+    }
+
+    #[test]
+    fn requested_id_is_reserved_while_running() {
+        let client = make_rocket_client();
+        let id = PuppetId::Seq(7);
+        let req = CreateReq {
+            exec: "sleep",
+            args: vec!["100"],
+            requested_id: Some(id),
+            ..Default::default()
+        };
+        let first = create(&client, &req);
+        assert_eq!(first.id, id);
+
+        let resp = client.put("/cmd").json(&req).dispatch();
+        assert_eq!(resp.status(), Status::Conflict);
+        let resp = client.delete(format!("/cmd/{}", id)).dispatch();
+        assert_eq!(resp.status(), Status::Conflict);
+
+        kill_id(&client, id);
+        let resp = client.delete(format!("/cmd/{}", id)).dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+        let resp = client.get(format!("/status/{}", id)).dispatch();
+        assert_eq!(resp.status(), Status::NotFound);
+
+        let second = create(&client, &req);
+        assert_eq!(second.id, id);
+        assert_ne!(second.pid, first.pid);
+        kill_id(&client, id);
+
+        // Ids that are picked for us carry on from 0 and skip the taken one.
+        let picked = create_req(&client, "true", vec![], CaptureOptions::none());
+        assert_eq!(picked.id, PuppetId::Seq(0));
+    }
+
+    #[test]
+    fn force_delete_kills_first() {
+        let client = make_rocket_client();
+        let create_resp = create_req(&client, "sleep", vec!["100"], CaptureOptions::stdout());
+        let capture = PathBuf::from(captured_path(&create_resp.stdout));
+        assert!(capture.exists());
+
+        let resp = client.delete(format!("/cmd/{}", create_resp.id)).dispatch();
+        assert_eq!(resp.status(), Status::Conflict);
+        let resp = client
+            .delete(format!("/cmd/{}?force=true", create_resp.id))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+
+        // Reaped, not just signaled, before the delete returned.
+        assert_eq!(find_proc(create_resp.pid), None);
+        assert!(!capture.exists());
+        let resp = client.get(format!("/status/{}", create_resp.id)).dispatch();
+        assert_eq!(resp.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn kill_all_by_name_prefix() {
+        use crate::routes::{KillAllReq, KillAllResp};
+
+        let client = make_rocket_client();
+        let ids: Vec<PuppetId> = ["build-1", "build-2", "serve"]
+            .into_iter()
+            .map(|name| {
+                create(
+                    &client,
+                    &CreateReq {
+                        exec: "sleep",
+                        args: vec!["100"],
+                        name: Some(name),
+                        ..Default::default()
+                    },
+                )
+                .id
+            })
+            .collect();
+
+        let filter = KillAllReq {
+            name_prefix: Some(String::from("build-")),
+            ..Default::default()
+        };
+        let resp = client
+            .post("/kill-all")
+            .json(&filter)
+            .dispatch()
+            .into_json::<KillAllResp>()
+            .expect("expected a kill-all body");
+        assert_eq!(resp.killed, ids[..2]);
+
+        let running = |id: PuppetId| get_status(&client, id).running;
+        assert!(!running(ids[0]) && !running(ids[1]));
+        assert!(running(ids[2]));
+
+        let resp = client
+            .post("/kill-all")
+            .json(&KillAllReq::default())
+            .dispatch()
+            .into_json::<KillAllResp>()
+            .expect("expected a kill-all body");
+        assert_eq!(resp.killed, ids[2..]);
+    }
+
+    #[test]
+    fn create_returns_location() {
+        let client = make_rocket_client();
+        let resp = client
+            .put("/cmd")
+            .json(&CreateReq {
+                exec: "true",
+                ..Default::default()
+            })
+            .dispatch();
+        assert_eq!(resp.status(), Status::Created);
+        let location = resp.headers().get_one("Location").map(String::from);
+        let id = resp.into_json::<CreateResp>().unwrap().id;
+        assert_eq!(location, Some(format!("/status/{}", id)));
+        assert_eq!(get_status(&client, id).id, id);
+    }
+
+    #[test]
+    fn rpc_create_then_wait() {
+        use crate::routes::RpcResp;
+        use rocket::serde::json::serde_json::{self, json};
+
+        let client = make_rocket_client();
+        let call = |body: String| {
+            client
+                .post("/rpc")
+                .body(body)
+                .dispatch()
+                .into_json::<RpcResp>()
+                .expect("expected a JSON-RPC response")
+        };
+        let resp = call(
+            json!({
+                "jsonrpc": "2.0",
+                "method": "create",
+                "params": {
+                    "exec": "echo",
+                    "args": ["hi"],
+                    "capture": {"stdout": true, "stderr": false},
+                },
+                "id": 1,
+            })
+            .to_string(),
+        );
+        assert_eq!(resp.jsonrpc, "2.0");
+        assert_eq!(resp.id, json!(1));
+        assert!(resp.error.is_none());
+        let create_resp: CreateResp = serde_json::from_value(resp.result.unwrap()).unwrap();
+
+        let wait = |id: PuppetId| {
+            call(
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "wait",
+                    "params": {"id": id},
+                    "id": "wait",
+                })
+                .to_string(),
+            )
+        };
+        let resp = wait(create_resp.id);
+        assert_eq!(resp.id, json!("wait"));
+        let wait_resp: WaitResp = serde_json::from_value(resp.result.unwrap()).unwrap();
+        assert_eq!(wait_resp.id, create_resp.id);
+        assert!(wait_resp.success);
+        assert_eq!(
+            wait_resp
+                .stdout
+                .and_then(|stdout| stdout.content)
+                .as_deref(),
+            Some("hi\n")
+        );
+
+        // Errors from the route come with its error code.
+        let resp = wait(PuppetId::Seq(12345));
+        assert!(resp.result.is_none());
+        let err = resp.error.expect("expected an error for an unknown id");
+        assert_eq!(err.code, -32000);
+        assert_eq!(err.data.unwrap().code, "puppet_not_found");
+
+        let err = call(json!({"jsonrpc": "2.0", "method": "nope", "id": 2}).to_string());
+        assert_eq!(err.error.unwrap().code, -32601);
+        let err = call(String::from("{"));
+        assert_eq!(err.id, serde_json::Value::Null);
+        assert_eq!(err.error.unwrap().code, -32700);
+
+        // A null id is still an id, and gets a response.
+        let resp = client
+            .post("/rpc")
+            .body(json!({"jsonrpc": "2.0", "method": "list", "id": null}).to_string())
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+        let resp = resp.into_json::<RpcResp>().unwrap();
+        assert_eq!(resp.id, serde_json::Value::Null);
+        assert!(resp.result.is_some_and(|list| list.is_array()));
+
+        // Notifications are carried out, but get no response.
+        let resp = client
+            .post("/rpc")
+            .body(json!({"jsonrpc": "2.0", "method": "list"}).to_string())
+            .dispatch();
+        assert_eq!(resp.status(), Status::NoContent);
+    }
+
+    #[test]
+    fn rename_puppet() {
+        use crate::routes::{KillAllReq, KillAllResp, RenameReq, StatusResp};
+
+        let client = make_rocket_client();
+        let id = create(
+            &client,
+            &CreateReq {
+                exec: "sleep",
+                args: vec!["100"],
+                name: Some("old"),
+                ..Default::default()
+            },
+        )
+        .id;
+        let rename = |name: Option<&str>| {
+            client
+                .patch(format!("/cmd/{}", id))
+                .json(&RenameReq {
+                    name: name.map(String::from),
+                })
+                .dispatch()
+        };
+        let resp = rename(Some("new")).into_json::<StatusResp>().unwrap();
+        assert_eq!(resp.name.as_deref(), Some("new"));
+        assert_eq!(get_status(&client, id).name.as_deref(), Some("new"));
+
+        let resp = client
+            .post("/kill-all")
+            .json(&KillAllReq {
+                name_prefix: Some(String::from("new")),
+                ..Default::default()
+            })
+            .dispatch()
+            .into_json::<KillAllResp>()
+            .expect("expected a kill-all body");
+        assert_eq!(resp.killed, vec![id]);
+
+        assert_eq!(get_status(&client, id).name, Some(String::from("new")));
+        assert!(rename(None)
+            .into_json::<StatusResp>()
+            .unwrap()
+            .name
+            .is_none());
+        assert_eq!(
+            client
+                .patch("/cmd/42")
+                .json(&RenameReq { name: None })
+                .dispatch()
+                .status(),
+            Status::NotFound
+        );
+    }
+
+    #[test]
+    fn status_reports_state() {
+        use crate::puppet::{PuppetState, TimeoutReason};
+
+        let client = make_rocket_client();
+        let state = |id| get_status(&client, id).state;
+
+        let exited = create_req(&client, "sh", vec!["-c", "exit 3"], CaptureOptions::none());
+        wait_for_id(&client, exited.id);
+        assert_eq!(state(exited.id), PuppetState::Exited { code: 3 });
+
+        let killed = create_req(&client, "sleep", vec!["100"], CaptureOptions::none());
+        assert_eq!(state(killed.id), PuppetState::Running);
+        kill_id(&client, killed.id);
+        assert_eq!(state(killed.id), PuppetState::Killed);
+
+        let signaled = create_req(&client, "sleep", vec!["100"], CaptureOptions::none());
+        unsafe { libc::kill(signaled.pid as libc::pid_t, libc::SIGTERM) };
+        wait_for_id(&client, signaled.id);
+        assert_eq!(
+            state(signaled.id),
+            PuppetState::Signaled {
+                signal: libc::SIGTERM
+            }
+        );
+
+        let timed_out = run(&client, "sleep", vec!["100"], "?timeout_ms=100");
+        assert!(timed_out.timed_out);
+        assert_eq!(
+            state(timed_out.wait.id),
+            PuppetState::TimedOut {
+                reason: TimeoutReason::Request
+            }
+        );
+    }
+
+    #[test]
+    fn status_after_wait() {
+        use crate::puppet::PuppetState;
+
+        let client = make_rocket_client();
+        let create_resp = create_req(&client, "sh", vec!["-c", "exit 7"], CaptureOptions::none());
+        let wait_resp = wait_for_id(&client, create_resp.id);
+        assert_eq!(wait_resp.exit_code, 7);
+        for _ in 0..2 {
+            let status = get_status(&client, create_resp.id);
+            assert!(!status.running);
+            assert_eq!(status.state, PuppetState::Exited { code: 7 });
+            assert_eq!(status.duration_ms, Some(wait_resp.duration_ms));
+            wait_for_id(&client, create_resp.id);
+        }
+    }
+
+    #[test]
+    fn status_batch_reports_each_id() {
+        use crate::puppet::PuppetState;
+        use crate::routes::{BatchStatusResult, BatchWaitReq};
+        use std::collections::BTreeMap;
+
+        let client = make_rocket_client();
+        let exited = create_req(&client, "true", vec![], CaptureOptions::none());
+        wait_for_id(&client, exited.id);
+        let running = create_req(&client, "sleep", vec!["100"], CaptureOptions::none());
+        let (unknown, invalid) = (PuppetId::Seq(243423423), PuppetId::Seq(-1));
+
+        let results = client
+            .post("/status/batch")
+            .json(&BatchWaitReq {
+                ids: vec![exited.id, running.id, unknown, invalid],
+            })
+            .dispatch()
+            .into_json::<BTreeMap<String, BatchStatusResult>>()
+            .expect("expected a non-None response for batch status");
+        assert_eq!(results.len(), 4);
+        let result = |id: PuppetId| &results[&id.to_string()];
+        match result(exited.id) {
+            BatchStatusResult::Found(status) => {
+                assert_eq!(status.state, PuppetState::Exited { code: 0 })
+            }
+            BatchStatusResult::Failed(err) => panic!("expected a status, got: {}", err.err),
+        }
+        match result(running.id) {
+            BatchStatusResult::Found(status) => assert_eq!(status.state, PuppetState::Running),
+            BatchStatusResult::Failed(err) => panic!("expected a status, got: {}", err.err),
+        }
+        for (id, code) in [(unknown, "puppet_not_found"), (invalid, "invalid_id")] {
+            match result(id) {
+                BatchStatusResult::Failed(err) => assert_eq!(err.code, code),
+                BatchStatusResult::Found(_) => panic!("expected id {} to fail", id),
+            }
+        }
+        kill_id(&client, running.id);
+    }
+
+    #[test]
+    fn shutdown_kills_puppets() {
+        let client = make_rocket_client();
+        let create_resp = create_req(&client, "sleep", vec!["100"], CaptureOptions::none());
+        assert_ne!(find_proc(create_resp.pid), None);
+        client.terminate();
+        // The child is reaped before shutdown returns, so there should be
+        // no trace of it left, not even a zombie.
+        assert_eq!(find_proc(create_resp.pid), None);
+    }
+
+    #[test]
+    fn no_spawns_after_shutdown() {
+        use crate::puppet::PuppetManager;
+
+        let client = make_rocket_client();
+        let running = create_req(&client, "sleep", vec!["100"], CaptureOptions::none());
+        // As the shutdown fairing does; a terminated client can't be sent
+        // any more requests.
+        client
+            .rocket()
+            .state::<PuppetManager>()
+            .unwrap()
+            .shut_down();
+        let resp = client
+            .put("/cmd")
+            .json(&CreateReq {
+                exec: "true",
+                ..Default::default()
+            })
+            .dispatch();
+        assert_eq!(resp.status(), Status::ServiceUnavailable);
+        let body = resp.into_string().unwrap();
+        assert!(body.contains(r#""code":"shutting_down""#), "got {}", body);
+        assert!(get_status(&client, running.id).running);
+        assert!(kill_id(&client, running.id).signaled);
+    }
+
+    #[test]
+    fn cmd_inherits_from_server_env() {
+        let client = make_rocket_client();
+        let expected_env_var_key = format!("puppet-{}", Uuid::new_v4());
+        let expected_env_var_val = "blah";
+        std::env::set_var(&expected_env_var_key, expected_env_var_val);
+        let output =
+            run_cmd_and_get_output(&client, "env", vec![], CaptureOptions::stdout()).stdout;
+        output.contains(&format!(
+            "{}={}",
+            expected_env_var_key, expected_env_var_val
+        ));
+    }
+
+    #[test]
+    fn cmd_resolves_relative_exec_against_cwd() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let client = make_rocket_client();
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("script.sh");
+        std::fs::write(&script, "#!/bin/sh\npwd\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let dir = dir.path().canonicalize().unwrap();
+
+        let create_resp = create(
+            &client,
+            &CreateReq {
+                exec: "./script.sh",
+                args: vec![],
+                capture: Some(CaptureOptions::stdout()),
+                cwd: dir.to_str(),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            create_resp.command,
+            vec![dir.join("script.sh").to_str().unwrap()]
+        );
+        assert!(wait_for_id(&client, create_resp.id).success);
+        assert_eq!(
+            get_captured(&create_resp.stdout),
+            format!("{}\n", dir.display())
+        );
+
+        let resp = client
+            .put("/cmd")
+            .json(&CreateReq {
+                exec: "./missing.sh",
+                args: vec![],
+                cwd: dir.to_str(),
+                ..Default::default()
+            })
+            .dispatch();
+        assert_eq!(resp.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn cmd_looks_up_exec_on_given_path() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let client = make_rocket_client();
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("puppet-path-test");
+        std::fs::write(&script, "#!/bin/sh\necho found\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let req = CreateReq {
+            exec: "puppet-path-test",
+            args: vec![],
+            capture: Some(CaptureOptions::stdout()),
+            ..Default::default()
+        };
+        let resp = client.put("/cmd").json(&req).dispatch();
+        assert_eq!(resp.status(), Status::BadRequest);
+
+        let create_resp = create(
+            &client,
+            &CreateReq {
+                path: dir.path().to_str(),
+                ..req
+            },
+        );
+        assert!(wait_for_id(&client, create_resp.id).success);
+        assert_eq!(get_captured(&create_resp.stdout), "found\n");
+    }
+
+    #[test]
+    fn restarts_on_failure_up_to_max_restarts() {
+        let client = make_rocket_client();
+        let create_resp = create(
+            &client,
+            &CreateReq {
+                exec: "sh",
+                args: vec!["-c", "echo run; exit 1"],
+                capture: Some(CaptureOptions::stdout()),
+                restart_policy: RestartPolicy::OnFailure,
+                max_restarts: Some(3),
+                backoff_ms: Some(10),
+                ..Default::default()
+            },
+        );
+
+        let mut restart_counts = vec![0];
+        for _ in 0..100 {
+            let status = get_status(&client, create_resp.id);
+            if status.restart_count != *restart_counts.last().unwrap() {
+                restart_counts.push(status.restart_count);
+            }
+            if status.restart_count == 3 && !status.running {
+                break;
+            }
+            std::thread::sleep(time::Duration::from_millis(10));
+        }
+        assert_eq!(restart_counts.last(), Some(&3));
+        assert!(restart_counts.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Backing off would have it restart after 80ms if it were going to.
+        std::thread::sleep(time::Duration::from_millis(200));
+        let status = get_status(&client, create_resp.id);
+        assert_eq!(status.restart_count, 3);
+        assert_eq!(status.state, crate::puppet::PuppetState::Exited { code: 1 });
+        // Restarts append to what was captured before.
+        assert_eq!(get_captured(&create_resp.stdout), "run\n".repeat(4));
+    }
+
+    #[test]
+    fn cmd_fails_on_missing_cgroup() {
+        let client = make_rocket_client();
+        let resp = client
+            .put("/cmd")
+            .json(&CreateReq {
+                exec: "true",
+                args: vec![],
+                cgroup: Some("/nonexistent/cgroup"),
+                ..Default::default()
+            })
+            .dispatch();
+        assert_eq!(resp.status(), Status::InternalServerError);
+        let err = resp.into_json::<crate::error::ErrorJSONResp>().unwrap();
+        assert_eq!(err.code, "cgroup_failed");
+    }
+
+    /// Needs a writable cgroup v2 hierarchy at /sys/fs/cgroup.
+    #[test]
+    #[ignore]
+    fn cmd_moves_puppet_into_cgroup() {
+        let client = make_rocket_client();
+        let cgroup = Path::new("/sys/fs/cgroup").join(format!("puppeteer-test-{}", Uuid::new_v4()));
+        std::fs::create_dir(&cgroup).expect("failed to create cgroup");
+        let create_resp = create(
+            &client,
+            &CreateReq {
+                exec: "sleep",
+                args: vec!["100"],
+                cgroup: cgroup.to_str(),
+                ..Default::default()
+            },
+        );
+        let procs = std::fs::read_to_string(cgroup.join("cgroup.procs")).unwrap();
+        assert!(procs.lines().any(|pid| pid == create_resp.pid.to_string()));
+        kill_id(&client, create_resp.id);
+        std::fs::remove_dir(&cgroup).expect("failed to remove cgroup");
+    }
+
+    #[test]
+    fn cmd_overrides_arg0() {
+        let client = make_rocket_client();
+        // With nothing after the -c script, $0 is the shell's own argv[0].
+        let req = CreateReq {
+            exec: "sh",
+            args: vec!["-c", "echo $0"],
+            capture: Some(CaptureOptions::stdout()),
+            arg0: Some("puppet-shell"),
+            ..Default::default()
+        };
+        let create_resp = create(&client, &req);
+        assert_eq!(create_resp.command, vec!["sh", "-c", "echo $0"]);
+        wait_for_id(&client, create_resp.id);
+        assert_eq!(get_captured(&create_resp.stdout), "puppet-shell\n");
+    }
+
+    #[test]
+    fn env_reports_spawned_env() {
+        let client = make_rocket_client();
+        let var = format!("puppet_{}", Uuid::new_v4().simple());
+        let secret = format!("{}_token", var);
+        let missing = format!("{}_missing", var);
+        std::env::set_var(&var, "visible");
+        std::env::set_var(&secret, "hunter2");
+        let req = CreateReq {
+            exec: "true",
+            args: vec![],
+            clear_env: true,
+            inherit_env: Some(vec![&var, &secret, &missing]),
+            ..Default::default()
+        };
+        let create_resp = create(&client, &req);
+        let env = client
+            .get(format!("/env/{}", create_resp.id))
+            .dispatch()
+            .into_json::<std::collections::BTreeMap<String, String>>()
+            .expect("expected a non-None response for getting the env");
+        let expected = [(var, "visible"), (secret, "[REDACTED]")]
+            .into_iter()
+            .map(|(name, val)| (name, String::from(val)))
+            .collect();
+        assert_eq!(env, expected);
+    }
+
+    #[test]
+    fn cmd_expands_env_in_args() {
+        let client = make_rocket_client();
+        let var = format!("puppet_{}", Uuid::new_v4().simple());
+        let missing = format!("puppet_{}", Uuid::new_v4().simple());
+        std::env::set_var(&var, "expanded");
+        let arg = format!("${{{}}}:${{{}}}", var, missing);
+        let req = CreateReq {
+            exec: "echo",
+            args: vec![&arg],
+            capture: Some(CaptureOptions::stdout()),
+            expand_env: true,
+            ..Default::default()
+        };
+        let create_resp = create(&client, &req);
+        assert_eq!(create_resp.command, vec!["echo", "expanded:"]);
+        assert!(wait_for_id(&client, create_resp.id).success);
+        assert_eq!(get_captured(&create_resp.stdout), "expanded:\n");
+
+        let strict = CreateReq {
+            expand_env_strict: true,
+            ..req.clone()
+        };
+        let resp = client.put("/cmd").json(&strict).dispatch();
+        assert_eq!(resp.status(), Status::BadRequest);
+
+        // Variables the child won't get aren't expanded either.
+        let cleared = CreateReq {
+            clear_env: true,
+            ..req
+        };
+        let create_resp = create(&client, &cleared);
+        assert_eq!(create_resp.command, vec!["echo", ":"]);
+        wait_for_id(&client, create_resp.id);
+    }
+
+    #[test]
+    fn cmd_inherits_only_allowed_env() {
+        let client = make_rocket_client();
+        let allowed = format!("puppet-{}", Uuid::new_v4());
+        let denied = format!("puppet-{}", Uuid::new_v4());
+        let missing = format!("puppet-{}", Uuid::new_v4());
+        std::env::set_var(&allowed, "yes");
+        std::env::set_var(&denied, "no");
+        let create_resp = create(
+            &client,
+            &CreateReq {
+                exec: "env",
+                capture: Some(CaptureOptions::stdout()),
+                clear_env: true,
+                inherit_env: Some(vec![&allowed, &missing]),
+                ..Default::default()
+            },
+        );
+        assert!(wait_for_id(&client, create_resp.id).success);
+        assert_eq!(
+            get_captured(&create_resp.stdout),
+            format!("{}=yes\n", allowed)
+        );
+
+        let resp = client
+            .put("/cmd")
+            .json(&CreateReq {
+                exec: "env",
+                inherit_env: Some(vec![&allowed]),
+                ..Default::default()
+            })
+            .dispatch();
+        assert_eq!(resp.status(), Status::BadRequest);
+    }
+
+    fn niceness(pid: u32) -> i32 {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .expect("failed to read process stat");
+        // The fields after the parenthesized command name start at the
+        // process state, making the nice value the 17th.
+        let (_, fields) = stat.rsplit_once(") ").expect("malformed process stat");
+        fields
+            .split(' ')
+            .nth(16)
+            .and_then(|nice| nice.parse().ok())
+            .expect("malformed nice value")
+    }
+
+    #[test]
+    fn cmd_runs_with_nice_value() {
+        let client = make_rocket_client();
+        let forever = get_testscript_path("forever.sh");
+        for (nice, expected) in [(10, 10), (100, 19)] {
+            let create_resp = create(
+                &client,
+                &CreateReq {
+                    exec: forever
+                        .to_str()
+                        .expect("failed to unwrap forever script filepath"),
+                    nice: Some(nice),
+                    ..Default::default()
+                },
+            );
+            assert_eq!(niceness(create_resp.pid), expected);
+            kill_id(&client, create_resp.id);
+        }
+    }
+
+    /// Needs CAP_SETGID.
+    #[test]
+    #[ignore]
+    fn cmd_runs_with_groups() {
+        let client = make_rocket_client();
+        let create_resp = create(
+            &client,
+            &CreateReq {
+                exec: "id",
+                args: vec!["-G"],
+                capture: Some(CaptureOptions::stdout()),
+                groups: Some(vec![12345, 23456]),
+                ..Default::default()
+            },
+        );
+        assert!(wait_for_id(&client, create_resp.id).success);
+        let groups = get_captured(&create_resp.stdout);
+        let groups: Vec<_> = groups.split_whitespace().collect();
+        assert!(groups.contains(&"12345"), "{:?}", groups);
+        assert!(groups.contains(&"23456"), "{:?}", groups);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn cmd_runs_with_ionice() {
+        use crate::routes::Ionice;
+
+        let client = make_rocket_client();
+        let create_resp = create(
+            &client,
+            &CreateReq {
+                exec: "sleep",
+                args: vec!["100"],
+                ionice: Some(Ionice::Idle),
+                ..Default::default()
+            },
+        );
+        let ioprio = unsafe { libc::syscall(libc::SYS_ioprio_get, 1, create_resp.pid) };
+        // The class is kept in the bits above the priority.
+        assert_eq!(ioprio >> 13, 3);
+        kill_id(&client, create_resp.id);
+
+        let resp = client
+            .put("/cmd")
+            .json(&CreateReq {
+                exec: "true",
+                ionice: Some(Ionice::BestEffort { priority: 8 }),
+                ..Default::default()
+            })
+            .dispatch();
+        assert_eq!(resp.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn status_reports_open_files_limit() {
+        use crate::puppet::Rlimit;
+
+        let client = make_rocket_client();
+        let create_resp = create(
+            &client,
+            &CreateReq {
+                exec: "sh",
+                args: vec!["-c", "ulimit -n"],
+                capture: Some(CaptureOptions::stdout()),
+                max_open_files: Some(64),
+                ..Default::default()
+            },
+        );
+        assert!(wait_for_id(&client, create_resp.id).success);
+        assert_eq!(get_captured(&create_resp.stdout), "64\n");
+        assert_eq!(
+            get_status(&client, create_resp.id).open_files_limit,
+            Rlimit {
+                soft: Some(64),
+                hard: Some(64),
+            }
+        );
+
+        // Left alone, the limits are the server's own.
+        let create_resp = create_req(&client, "true", vec![], CaptureOptions::none());
+        let inherited = get_status(&client, create_resp.id).open_files_limit;
+        assert!(inherited.soft.is_none_or(|soft| soft > 64));
+
+        // Only root could raise the hard limit.
+        if let Some(hard) = inherited.hard {
+            let resp = client
+                .put("/cmd")
+                .json(&CreateReq {
+                    exec: "true",
+                    max_open_files: Some(hard + 1),
+                    ..Default::default()
+                })
+                .dispatch();
+            assert_eq!(resp.status(), Status::BadRequest);
+        }
+    }
+
+    /// Long-polls for stdout past `offset`, returning the new output, the
+    /// offset to continue from and whether the puppet is done.
+    fn follow_output(client: &Client, id: PuppetId, offset: u64) -> (String, u64, bool) {
+        let resp = client
+            .get(format!(
+                "/output/{}/stdout?follow=true&offset={}",
+                id, offset
+            ))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+        let header = |name| {
+            resp.headers()
+                .get_one(name)
+                .unwrap_or_else(|| panic!("expected a {} header", name))
+                .parse::<String>()
+                .unwrap()
+        };
+        let next_offset = header("X-Puppeteer-Offset").parse().unwrap();
+        let eof = header("X-Puppeteer-Eof").parse().unwrap();
+        (resp.into_string().unwrap(), next_offset, eof)
+    }
+
+    #[test]
+    fn follow_reports_eof_after_exit() {
+        let client = make_rocket_client();
+        let create_resp = create_req(&client, "echo", vec!["hi"], CaptureOptions::stdout());
+        assert!(wait_for_id(&client, create_resp.id).success);
+        assert_eq!(
+            follow_output(&client, create_resp.id, 0),
+            (String::from("hi\n"), 3, false)
+        );
+        assert_eq!(
+            follow_output(&client, create_resp.id, 3),
+            (String::new(), 3, true)
+        );
+    }
+
+    #[test]
+    fn pty_looks_like_a_terminal() {
+        let client = make_rocket_client();
+        let script = "test -t 0 && test -t 1 && test -t 2 && echo tty";
+        let run_in = |pty| {
+            let create_resp = create(
+                &client,
+                &CreateReq {
+                    exec: "sh",
+                    args: vec!["-c", script],
+                    capture: Some(CaptureOptions::stdout()),
+                    pty,
+                    ..Default::default()
+                },
+            );
+            wait_for_id(&client, create_resp.id);
+            create_resp
+        };
+
+        let create_resp = run_in(true);
+        // The terminal turns newlines into CRLFs on their way out.
+        assert_eq!(get_captured(&create_resp.stdout), "tty\r\n");
+        assert_eq!(create_resp.stderr, create_resp.stdout);
+        assert!(create_resp.tty);
+        assert!(get_status(&client, create_resp.id).tty);
+        let create_resp = run_in(false);
+        assert_eq!(get_captured(&create_resp.stdout), "");
+        assert!(!create_resp.tty);
+        assert!(!get_status(&client, create_resp.id).tty);
+    }
+
+    #[test]
+    fn cmd_gets_extra_fds() {
+        use crate::routes::FdSpec;
+
+        let client = make_rocket_client();
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let (input, output) = (dir.path().join("input"), dir.path().join("output"));
+        std::fs::write(&input, "hello\n").expect("failed to write input file");
+        fn req<'a>(output: &'a str, input: &'a str) -> CreateReq<'a> {
+            CreateReq {
+                exec: "sh",
+                args: vec!["-c", "cat <&4 >> /dev/fd/3"],
+                fds: vec![
+                    FdSpec {
+                        path: output,
+                        write: true,
+                    },
+                    FdSpec {
+                        path: input,
+                        write: false,
+                    },
+                ],
+                ..Default::default()
+            }
+        }
+        let output = output.to_str().unwrap();
+
+        let create_resp = create(&client, &req(output, input.to_str().unwrap()));
+        assert!(wait_for_id(&client, create_resp.id).success);
+        assert_eq!(get_contents(output), "hello\n");
+
+        let missing = dir.path().join("missing");
+        let resp = client
+            .put("/cmd")
+            .json(&req(output, missing.to_str().unwrap()))
+            .dispatch();
+        assert_eq!(resp.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn can_stream_cmd_output_without_wait() {
+        let client = make_rocket_client();
+
+        let periodic_print = get_testscript_path("periodic.sh");
+        let create_resp = create_req(
+            &client,
+            periodic_print
+                .to_str()
+                .expect("failed to unwrap periodic script filepath"),
+            vec!["bar"],
+            CaptureOptions::stdout(),
+        );
+        assert_eq!(create_resp.id, PuppetId::Seq(0));
+        assert_eq!(create_resp.stderr, StreamMode::Inherited);
+
+        // The script prints a monotonically increasing integer every
+        // second. Each follow should block until the next one arrives and
+        // return just that, without having to poll the file ourselves.
+        let mut offset = 0;
+        let mut nums = Vec::new();
+        while nums.len() < 2 {
+            let (chunk, next_offset, eof) = follow_output(&client, create_resp.id, offset);
+            assert!(!eof);
+            assert!(next_offset >= offset);
+            offset = next_offset;
+            nums.extend(
+                chunk
+                    .split('\n')
+                    .filter_map(|line| line.parse::<i32>().ok()),
+            );
+        }
+        assert!(nums.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // If we get here, we found a differing number -- we've passed.
+        // Let's clean-up by killing that script we ran, since it'll otherwise run for a really long time:
+        kill_id(&client, create_resp.id);
+    }
+
+    mod captures {
+        use super::*;
+
+        #[test]
+        fn stdout() {
+            let client = make_rocket_client();
+            let expected_output = "bar";
+            assert_eq!(
+                run_cmd_and_get_output(
+                    &client,
+                    "echo",
+                    vec![expected_output],
+                    CaptureOptions::stdout()
+                )
+                .stdout,
+                format!("{}\n", expected_output)
+            );
+        }
+
+        #[test]
+        fn stderr() {
+            let client = make_rocket_client();
+            let expected_output = "bar";
+            let stderr_print = get_testscript_path("stderr.sh");
+            assert_eq!(
+                run_cmd_and_get_output(
+                    &client,
+                    stderr_print
+                        .to_str()
+                        .expect("failed to unwrap stderr script filepath"),
+                    vec![expected_output],
+                    CaptureOptions::stderr()
+                )
+                .stderr,
+                format!("{}\n", expected_output)
+            );
+        }
+
+        #[test]
+        fn both() {
+            let client = make_rocket_client();
+            // TODO: we should maybe emit two different values for stdout v stderr -- this tests we aren't mixing the two up.
+            let expected_output = "bar";
+            let both_std_print = get_testscript_path("both_std.sh");
+            let output = run_cmd_and_get_output(
+                &client,
+                both_std_print
+                    .to_str()
+                    .expect("failed to unwrap stderr script filepath"),
+                vec![expected_output],
+                CaptureOptions::all(),
+            );
+            assert_eq!(output.stdout, format!("{}\n", expected_output));
+            assert_eq!(output.stderr, format!("{}\n", expected_output));
+        }
+
+        #[test]
+        fn combined() {
+            use crate::routes::Combine;
+
+            let client = make_rocket_client();
+            let both_std_print = get_testscript_path("both_std.sh");
+            let combined = |combine: Combine| {
+                let output = run_cmd_and_get_output(
+                    &client,
+                    both_std_print
+                        .to_str()
+                        .expect("failed to unwrap both_std script filepath"),
+                    vec!["bar"],
+                    CaptureOptions {
+                        combine: Some(combine),
+                        ..CaptureOptions::all()
+                    },
+                );
+                // Read through either stream, it's the one capture.
+                assert_eq!(output.stdout, output.stderr);
+                // Which stream's line comes first is up to the scheduler.
+                let mut lines: Vec<_> = output.stdout.lines().map(String::from).collect();
+                lines.sort();
+                lines
+            };
+            assert_eq!(combined(Combine::Tagged), vec!["[err] bar", "[out] bar"]);
+            assert_eq!(
+                combined(Combine::Jsonl),
+                vec![
+                    r#"{"stream":"stderr","line":"bar"}"#,
+                    r#"{"stream":"stdout","line":"bar"}"#,
+                ]
+            );
+
+            // Each stream counts only what it wrote, not the marks.
+            let create_resp = create_req(
+                &client,
+                both_std_print
+                    .to_str()
+                    .expect("failed to unwrap both_std script filepath"),
+                vec!["bar"],
+                CaptureOptions {
+                    combine: Some(Combine::Tagged),
+                    ..CaptureOptions::all()
+                },
+            );
+            assert!(wait_for_id(&client, create_resp.id).success);
+            let status = get_status(&client, create_resp.id);
+            assert_eq!(status.stdout_bytes, Some(4));
+            assert_eq!(status.stderr_bytes, Some(4));
+        }
+
+        #[test]
+        fn both_in_one_response() {
+            let client = make_rocket_client();
+            let both_std_print = get_testscript_path("both_std.sh");
+            let create_resp = create_req(
+                &client,
+                both_std_print
+                    .to_str()
+                    .expect("failed to unwrap both_std script filepath"),
+                vec!["bar"],
+                CaptureOptions::all(),
+            );
+            assert!(wait_for_id(&client, create_resp.id).success);
+            let resp = client.get(format!("/output/{}", create_resp.id)).dispatch();
+            assert_eq!(resp.status(), Status::Ok);
+            let content_type = resp.content_type().expect("expected a content type");
+            assert_eq!(content_type.top(), "multipart");
+            assert_eq!(content_type.sub(), "mixed");
+            let boundary = content_type
+                .params()
+                .find(|(name, _)| *name == "boundary")
+                .map(|(_, value)| value.to_string())
+                .expect("expected a boundary");
+            let body = resp.into_string().unwrap();
+            let part = |stream: &str, content: &str| {
+                format!(
+                    "--{}\r\nContent-Type: application/octet-stream\r\n\
+                     Content-Disposition: inline; name=\"{}\"\r\n\r\n{}\r\n",
+                    boundary, stream, content
+                )
+            };
+            assert_eq!(
+                body,
+                format!(
+                    "{}{}--{}--\r\n",
+                    part("stdout", "bar\n"),
+                    part("stderr", "bar\n"),
+                    boundary
+                )
+            );
+
+            // Streams that weren't captured are left out.
+            let create_resp = create_req(&client, "echo", vec!["hi"], CaptureOptions::stdout());
+            assert!(wait_for_id(&client, create_resp.id).success);
+            let body = client
+                .get(format!("/output/{}", create_resp.id))
+                .dispatch()
+                .into_string()
+                .unwrap();
+            assert!(body.contains(r#"name="stdout""#));
+            assert!(!body.contains(r#"name="stderr""#));
+        }
+    }
+
+    #[test]
+    fn metrics_count_spawns() {
+        let client = make_rocket_client();
+        for _ in 0..2 {
+            let create_resp = create_req(&client, "echo", vec!["-n", ""], CaptureOptions::none());
+            assert!(wait_for_id(&client, create_resp.id).success);
+        }
+        let failed = client
+            .put("/cmd")
+            .json(&CreateReq {
+                exec: "/this/does/not/exist",
+                args: vec![],
+                capture: None,
+                ..Default::default()
+            })
+            .dispatch();
+        assert!(failed.into_json::<CreateResp>().is_none());
+
+        let forever = get_testscript_path("forever.sh");
+        let running = create_req(
+            &client,
+            forever
+                .to_str()
+                .expect("failed to unwrap forever script filepath"),
+            vec![],
+            CaptureOptions::none(),
+        );
+
+        let metrics = client
+            .get("/metrics")
+            .dispatch()
+            .into_string()
+            .expect("expected a metrics body");
+        let lines: Vec<&str> = metrics.lines().collect();
+        assert!(lines.contains(&"puppeteer_puppets_total 3"));
+        assert!(lines.contains(&"puppeteer_puppets_running 1"));
+        assert!(lines.contains(&"puppeteer_spawn_failures_total 1"));
+        assert!(lines.contains(&"# TYPE puppeteer_puppets_running gauge"));
+
+        // The failed spawn was refused before getting as far as spawning.
+        assert!(lines.contains(&"# TYPE puppeteer_spawn_duration_ms histogram"));
+        assert!(lines.contains(&"puppeteer_spawn_duration_ms_count 3"));
+        assert!(lines.contains(&r#"puppeteer_spawn_duration_ms_bucket{le="+Inf"} 3"#));
+        let sum: f64 = lines
+            .iter()
+            .find_map(|line| line.strip_prefix("puppeteer_spawn_duration_ms_sum "))
+            .and_then(|sum| sum.parse().ok())
+            .expect("expected a spawn duration sum");
+        assert!(sum > 0.0);
+        // Each bucket counts everything in the ones before it too.
+        let buckets: Vec<u64> = lines
+            .iter()
+            .filter(|line| line.starts_with("puppeteer_spawn_duration_ms_bucket"))
+            .map(|line| line.rsplit_once(' ').unwrap().1.parse().unwrap())
+            .collect();
+        assert_eq!(buckets.len(), 11);
+        assert!(buckets.windows(2).all(|pair| pair[0] <= pair[1]));
+
+        kill_id(&client, running.id);
+    }
+
+    #[test]
+    fn cmd_from_template() {
+        use crate::routes::{FromTemplateReq, TemplateReq};
+        use crate::template::Template;
+
+        let client = make_rocket_client();
+        let template_req = TemplateReq {
+            name: String::from("greet"),
+            template: Template {
+                exec: String::from("echo"),
+                args: vec![String::from("{0}")],
+                capture: Some(CaptureOptions::stdout()),
+            },
+        };
+        let resp = client.put("/template").json(&template_req).dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+
+        let resp = client
+            .put("/cmd/from-template/greet")
+            .json(&FromTemplateReq { values: vec!["hi"] })
+            .dispatch();
+        assert_eq!(resp.status(), Status::Created);
+        let location = resp.headers().get_one("Location").map(String::from);
+        let create_resp = resp
+            .into_json::<CreateResp>()
+            .expect("expected a created puppet");
+        assert_eq!(location, Some(format!("/status/{}", create_resp.id)));
+        assert_eq!(create_resp.command, vec!["echo", "hi"]);
+        assert!(wait_for_id(&client, create_resp.id).success);
+        assert_eq!(get_captured(&create_resp.stdout), "hi\n");
+
+        let resp = client
+            .put("/cmd/from-template/greet")
+            .json(&FromTemplateReq::default())
+            .dispatch();
+        assert_eq!(resp.status(), Status::BadRequest);
+        let resp = client
+            .put("/cmd/from-template/nope")
+            .json(&FromTemplateReq { values: vec!["hi"] })
+            .dispatch();
+        assert_eq!(resp.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn info_identifies_server() {
+        use crate::config::IdScheme;
+        use crate::routes::InfoResp;
+
+        let client = make_rocket_client();
+        let info = client
+            .get("/info")
+            .dispatch()
+            .into_json::<InfoResp>()
+            .expect("expected an info body");
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.pid, std::process::id());
+        assert_eq!(info.id_scheme, IdScheme::Sequential);
+    }
+
+    #[test]
+    fn stats_count_puppets() {
+        fn get_stats(client: &Client) -> StatsResp {
+            client
+                .get("/stats")
+                .dispatch()
+                .into_json::<StatsResp>()
+                .expect("expected a stats body")
+        }
+
+        let client = make_rocket_client();
+        let stats = get_stats(&client);
+        assert_eq!(
+            (stats.total_spawned, stats.running, stats.exited),
+            (0, 0, 0)
+        );
+
+        let create_resp = create_req(&client, "sleep", vec!["100"], CaptureOptions::none());
+        let stats = get_stats(&client);
+        assert_eq!(
+            (stats.total_spawned, stats.running, stats.exited),
+            (1, 1, 0)
+        );
+
+        kill_id(&client, create_resp.id);
+        let stats = get_stats(&client);
+        assert_eq!(
+            (stats.total_spawned, stats.running, stats.exited),
+            (1, 0, 1)
+        );
+        assert_eq!(stats.spawn_failures, 0);
+    }
+
+    mod openapi {
+        use super::*;
+        #[cfg(not(feature = "camel-case"))]
+        use crate::error::ErrorJSONResp;
+        #[cfg(not(feature = "camel-case"))]
+        use crate::routes::{InfoResp, StreamStatus};
+        use rocket::serde::json::{self, Value};
+        #[cfg(not(feature = "camel-case"))]
+        use rocket::serde::Serialize;
+        #[cfg(not(feature = "camel-case"))]
+        use std::collections::BTreeSet;
+
+        fn get_doc(client: &Client) -> Value {
+            let resp = client.get("/openapi.json").dispatch();
+            assert_eq!(resp.status(), Status::Ok);
+            assert_eq!(resp.content_type(), Some(ContentType::JSON));
+            json::from_str(&resp.into_string().unwrap()).expect("expected valid JSON")
+        }
+
+        #[cfg(not(feature = "camel-case"))]
+        fn schema_fields(doc: &Value, name: &str) -> BTreeSet<String> {
+            doc["components"]["schemas"][name]["properties"]
+                .as_object()
+                .unwrap_or_else(|| panic!("expected a schema for {}", name))
+                .keys()
+                .cloned()
+                .collect()
+        }
+
+        #[cfg(not(feature = "camel-case"))]
+        fn serialized_fields<T: Serialize>(value: &T) -> BTreeSet<String> {
+            json::to_value(value)
+                .unwrap()
+                .as_object()
+                .expect("expected a JSON object")
+                .keys()
+                .cloned()
+                .collect()
+        }
+
+        #[test]
+        fn documents_every_route() {
+            let client = make_rocket_client();
+            let doc = get_doc(&client);
+            assert_eq!(doc["openapi"], "3.0.3");
+            let paths = doc["paths"].as_object().expect("expected paths");
+            for route in client.rocket().routes() {
+                // OpenAPI writes path parameters as {id} rather than <id>,
+                // and lists query parameters separately.
+                let path = route.uri.path().replace('<', "{").replace('>', "}");
+                let method = route.method.as_str().to_lowercase();
+                assert!(
+                    paths.get(&path).and_then(|ops| ops.get(&method)).is_some(),
+                    "{} {} is not documented",
+                    method,
+                    path
+                );
+            }
+        }
+
+        // The document describes the default snake_case field names.
+        #[cfg(not(feature = "camel-case"))]
+        #[test]
+        fn schemas_match_types() {
+            let client = make_rocket_client();
+            let doc = get_doc(&client);
+
+            let capture = CaptureOptions::all();
+            assert_eq!(
+                schema_fields(&doc, "CaptureOptions"),
+                serialized_fields(&capture)
+            );
+            let create_req = CreateReq {
+                exec: "true",
+                capture: Some(capture),
+                ..Default::default()
+            };
+            assert_eq!(
+                schema_fields(&doc, "CreateReq"),
+                serialized_fields(&create_req)
+            );
+
+            let create_resp = create_req_and_wait(&client);
+            assert_eq!(
+                schema_fields(&doc, "CreateResp"),
+                serialized_fields(&create_resp)
+            );
+            let wait_resp = wait_for_id(&client, create_resp.id);
+            assert_eq!(
+                schema_fields(&doc, "WaitResp"),
+                serialized_fields(&wait_resp)
+            );
+            let status = client
+                .get(format!("/status/{}", create_resp.id))
+                .dispatch()
+                .into_json::<StatusResp>()
+                .unwrap();
+            assert_eq!(
+                schema_fields(&doc, "StatusResp"),
+                serialized_fields(&status)
+            );
+            // StreamStatus flattens StreamMode into itself, so only its own
+            // fields are documented on top of it.
+            let mut stream_fields = serialized_fields::<StreamStatus>(&status.stdout);
+            stream_fields.retain(|field| field != "mode" && field != "path");
+            assert_eq!(
+                doc["components"]["schemas"]["StreamStatus"]["allOf"][1]["properties"]
+                    .as_object()
+                    .unwrap()
+                    .keys()
+                    .cloned()
+                    .collect::<BTreeSet<_>>(),
+                stream_fields
+            );
+            let stats = client
+                .get("/stats")
+                .dispatch()
+                .into_json::<StatsResp>()
+                .unwrap();
+            assert_eq!(schema_fields(&doc, "StatsResp"), serialized_fields(&stats));
+            let info = client
+                .get("/info")
+                .dispatch()
+                .into_json::<InfoResp>()
+                .unwrap();
+            assert_eq!(schema_fields(&doc, "InfoResp"), serialized_fields(&info));
+            assert_eq!(
+                schema_fields(&doc, "Error"),
+                serialized_fields(&ErrorJSONResp {
+                    err: String::new(),
+                    code: String::new(),
+                })
+            );
+        }
+
+        #[cfg(not(feature = "camel-case"))]
+        fn create_req_and_wait(client: &Client) -> CreateResp {
+            let create_resp = create_req(client, "true", vec![], CaptureOptions::stdout());
+            assert!(wait_for_id(client, create_resp.id).success);
+            create_resp
+        }
+    }
+
+    #[test]
+    fn spawn_logging_redacts_args() {
+        use crate::puppet::describe_args;
+        use rocket::serde::json::serde_json;
+
+        assert_eq!(describe_args(&["--token", "hunter2"], true), "[REDACTED]");
+        assert_eq!(
+            describe_args(&["--token", "hunter2"], false),
+            r#"["--token", "hunter2"]"#
+        );
+
+        // Nor do the arguments make it into the event log.
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("events.jsonl");
+        let client = make_configured_client(
+            Figment::new()
+                .merge(("redact_args", true))
+                .merge(("event_log", &log_path)),
+        );
+        let create_resp = create_req(&client, "echo", vec!["hunter2"], CaptureOptions::none());
+        assert!(wait_for_id(&client, create_resp.id).success);
+        // The log is written in the background, so give it a moment.
+        let mut spawn = None;
+        for _ in 0..50 {
+            let contents = std::fs::read_to_string(&log_path).unwrap_or_default();
+            spawn = contents
+                .lines()
+                .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                .find(|line| line["event"] == "spawn");
+            if spawn.is_some() {
+                break;
+            }
+            std::thread::sleep(time::Duration::from_millis(20));
+        }
+        let spawn = spawn.expect("expected the spawn to be logged");
+        assert_eq!(spawn["id"], serde_json::json!(create_resp.id));
+        assert!(spawn.get("args").is_none(), "{}", spawn);
+    }
+
+    mod config {
+        use super::*;
+
+        fn spawn_forever(client: &Client) -> LocalResponse<'_> {
+            let forever = get_testscript_path("forever.sh");
+            client
+                .put("/cmd")
+                .json(&CreateReq {
+                    exec: forever
+                        .to_str()
+                        .expect("failed to unwrap forever script filepath"),
+                    args: vec![],
+                    capture: None,
+                    ..Default::default()
+                })
+                .dispatch()
+        }
+
+        #[test]
+        fn max_puppets_is_enforced() {
+            let client = make_configured_client(("max_puppets", 1));
+            let running = spawn_forever(&client)
+                .into_json::<CreateResp>()
+                .expect("expected the first puppet to spawn");
+            assert_eq!(spawn_forever(&client).status(), Status::ServiceUnavailable);
+
+            // Once the running puppet is gone there is room again.
+            kill_id(&client, running.id);
+            let create_resp = spawn_forever(&client)
+                .into_json::<CreateResp>()
+                .expect("expected a puppet to spawn after making room");
+            kill_id(&client, create_resp.id);
+        }
+
+        #[test]
+        fn spawn_rate_is_limited() {
+            let client = make_configured_client(("spawn_rate_per_sec", 2));
+            let req = CreateReq {
+                exec: "true",
+                args: vec![],
+                ..Default::default()
+            };
+            let limited = (0..10)
+                .map(|_| client.put("/cmd").json(&req).dispatch())
+                .find(|resp| resp.status() != Status::Created)
+                .expect("expected rapid spawns to be rate limited");
+            assert_eq!(limited.status(), Status::TooManyRequests);
+            assert_eq!(limited.headers().get_one("Retry-After"), Some("1"));
+        }
+
+        #[test]
+        fn capture_file_name_is_configurable() {
+            let client = make_configured_client(("capture_file_name", "{stream}.log"));
+            let create_resp = create_req(&client, "echo", vec!["hi"], CaptureOptions::all());
+            assert!(captured_path(&create_resp.stdout).ends_with("/stdout.log"));
+            assert!(captured_path(&create_resp.stderr).ends_with("/stderr.log"));
+            wait_for_id(&client, create_resp.id);
+            assert_eq!(get_captured(&create_resp.stdout), "hi\n");
+
+            let client = make_configured_client(("capture_file_name", "{name}-{stream}.log"));
+            let create_resp = create(
+                &client,
+                &CreateReq {
+                    exec: "echo",
+                    args: vec!["hi"],
+                    capture: Some(CaptureOptions::stdout()),
+                    name: Some("web/1"),
+                    ..Default::default()
+                },
+            );
+            assert!(captured_path(&create_resp.stdout).ends_with("/web_1-stdout.log"));
+        }
+
+        #[test]
+        fn event_log_records_spawn_and_exit() {
+            use rocket::serde::json::serde_json;
+
+            let dir = tempfile::tempdir().unwrap();
+            let log_path = dir.path().join("events.jsonl");
+            let client = make_configured_client(("event_log", &log_path));
+            let create_resp = create_req(&client, "echo", vec!["hi"], CaptureOptions::none());
+            assert!(wait_for_id(&client, create_resp.id).success);
+
+            // The log is written in the background, so give it a moment.
+            let mut lines = Vec::new();
+            for _ in 0..50 {
+                let contents = std::fs::read_to_string(&log_path).unwrap_or_default();
+                lines = contents
+                    .lines()
+                    .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+                    .collect();
+                if lines.len() >= 2 {
+                    break;
+                }
+                std::thread::sleep(time::Duration::from_millis(20));
+            }
+            assert_eq!(lines.len(), 2);
+            assert_eq!(lines[0]["event"], "spawn");
+            assert_eq!(lines[0]["id"], serde_json::json!(create_resp.id));
+            assert_eq!(lines[0]["pid"], create_resp.pid);
+            assert_eq!(lines[0]["args"], serde_json::json!(["hi"]));
+            assert_eq!(lines[1]["event"], "exit");
+            assert_eq!(lines[1]["state"]["kind"], "exited");
+            assert_eq!(lines[1]["state"]["code"], 0);
+            assert!(lines[0]["ts_ms"].as_u64() <= lines[1]["ts_ms"].as_u64());
+        }
+
+        #[test]
+        fn umask_applies_to_puppets() {
+            use std::os::unix::fs::PermissionsExt;
+
+            let client = make_configured_client(("umask", 0o077));
+            let dir = tempfile::tempdir().unwrap();
+            let create_resp = create(
+                &client,
+                &CreateReq {
+                    exec: "touch",
+                    args: vec!["created"],
+                    cwd: dir.path().to_str(),
+                    ..Default::default()
+                },
+            );
+            assert!(wait_for_id(&client, create_resp.id).success);
+            let meta = std::fs::metadata(dir.path().join("created")).unwrap();
+            assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        }
+
+        #[test]
+        fn default_timeout_bounds_wait() {
+            let client = make_configured_client(("default_timeout_ms", 100));
+            let create_resp = spawn_forever(&client)
+                .into_json::<CreateResp>()
+                .expect("expected the puppet to spawn");
+            assert_eq!(
+                client
+                    .post(format!("/wait/{}", create_resp.id))
+                    .dispatch()
+                    .status(),
+                Status::RequestTimeout
+            );
+            assert_ne!(find_proc(create_resp.pid), None);
+            kill_id(&client, create_resp.id);
+        }
+
+        #[test]
+        fn kill_signal_lets_puppets_clean_up() {
+            use crate::puppet::PuppetState;
+
+            let client = make_configured_client(("kill_signal", "TERM"));
+            let trapped = get_testscript_path("trapped.sh");
+            let spawn_trapped = || {
+                let create_resp = create_req(
+                    &client,
+                    trapped
+                        .to_str()
+                        .expect("failed to unwrap trapped script filepath"),
+                    vec![],
+                    CaptureOptions::stdout(),
+                );
+                while !get_captured(&create_resp.stdout).contains("ready") {
+                    std::thread::sleep(time::Duration::from_millis(10));
+                }
+                create_resp
+            };
+
+            let graceful = spawn_trapped();
+            let wait_resp = kill_id(&client, graceful.id);
+            assert!(!wait_resp.signaled);
+            assert_eq!(wait_resp.exit_code, 0);
+            assert_eq!(get_captured(&graceful.stdout), "ready\ncleaned up\n");
+            assert_eq!(get_status(&client, graceful.id).state, PuppetState::Killed);
+
+            // A suspended puppet is let carry on, so it gets the signal.
+            let suspended = spawn_trapped();
+            let resp = client.post(format!("/suspend/{}", suspended.id)).dispatch();
+            assert_eq!(resp.status(), Status::Ok);
+            let wait_resp = kill_id(&client, suspended.id);
+            assert_eq!(wait_resp.exit_code, 0);
+            assert_eq!(get_captured(&suspended.stdout), "ready\ncleaned up\n");
+
+            // The request can still insist.
+            let forced = spawn_trapped();
+            let resp = client
+                .post(format!("/kill/{}?signal=KILL", forced.id))
+                .dispatch();
+            assert_eq!(resp.status(), Status::Ok);
+            let wait_resp = resp.into_json::<WaitResp>().unwrap();
+            assert!(wait_resp.signaled);
+            assert_eq!(wait_resp.signal_code, libc::SIGKILL);
+            assert_eq!(get_captured(&forced.stdout), "ready\n");
+
+            // Nor are signals that would leave the kill waiting forever.
+            let stopped = spawn_trapped();
+            let stop = libc::SIGSTOP.to_string();
+            for signal in ["NOPE", "STOP", "SIGCONT", "winch", &stop] {
+                let resp = client
+                    .post(format!("/kill/{}?signal={}", stopped.id, signal))
+                    .dispatch();
+                assert_eq!(resp.status(), Status::BadRequest, "{}", signal);
+            }
+            kill_id(&client, stopped.id);
+        }
+
+        #[test]
+        fn max_runtime_outlasts_ignored_kill_signal() {
+            use crate::puppet::PuppetState;
+
+            let client = make_configured_client(
+                Figment::new()
+                    .merge(("kill_signal", "TERM"))
+                    .merge(("max_runtime_ms", 500)),
+            );
+            let stubborn = get_testscript_path("stubborn.sh");
+            let create_resp = create_req(
+                &client,
+                stubborn
+                    .to_str()
+                    .expect("failed to unwrap stubborn script filepath"),
+                vec![],
+                CaptureOptions::stdout(),
+            );
+            while !get_captured(&create_resp.stdout).contains("ready") {
+                std::thread::sleep(time::Duration::from_millis(10));
+            }
+            let wait_resp = kill_id(&client, create_resp.id);
+            assert!(wait_resp.signaled);
+            assert_eq!(wait_resp.signal_code, libc::SIGKILL);
+            assert!(wait_resp.duration_ms < 5000);
+            // It was asked to be killed before running out of time.
+            assert_eq!(
+                get_status(&client, create_resp.id).state,
+                PuppetState::Killed
+            );
+        }
+
+        #[test]
+        fn kill_signal_must_end_puppets() {
+            use crate::config::Config;
+            use crate::puppet::PuppetManager;
+
+            let config: Config = Figment::new()
+                .merge(("kill_signal", "STOP"))
+                .extract()
+                .expect("failed to read config");
+            assert!(PuppetManager::new(config).is_err());
+        }
+
+        #[test]
+        fn max_runtime_kills_long_puppets() {
+            use crate::puppet::{PuppetState, TimeoutReason};
+
+            let client = make_configured_client(("max_runtime_ms", 300));
+            let create_resp = create_req(&client, "sleep", vec!["100"], CaptureOptions::none());
+            let wait_resp = wait_for_id(&client, create_resp.id);
+            assert!(wait_resp.signaled);
+            assert!(wait_resp.duration_ms < 5000);
+            assert_eq!(
+                get_status(&client, create_resp.id).state,
+                PuppetState::TimedOut {
+                    reason: TimeoutReason::Global
+                }
+            );
+
+            // A shorter timeout on the request still wins, and a longer
+            // one doesn't save it.
+            let request = run(&client, "sleep", vec!["100"], "?timeout_ms=100");
+            assert_eq!(request.timeout_reason, Some(TimeoutReason::Request));
+            let global = run(&client, "sleep", vec!["100"], "?timeout_ms=10000");
+            assert!(global.timed_out);
+            assert_eq!(global.timeout_reason, Some(TimeoutReason::Global));
+
+            let quick = run(&client, "true", vec![], "");
+            assert!(!quick.timed_out);
+            assert_eq!(quick.timeout_reason, None);
+        }
+
+        #[test]
+        fn out_dir_holds_captures() {
+            let out_dir = std::env::temp_dir().join(format!("puppet-out-{}", Uuid::new_v4()));
+            let client = make_configured_client(("out_dir", &out_dir));
+            let create_resp = create_req(&client, "echo", vec!["hi"], CaptureOptions::stdout());
+            assert!(wait_for_id(&client, create_resp.id).success);
+            assert!(Path::new(captured_path(&create_resp.stdout)).starts_with(&out_dir));
+            assert_eq!(get_captured(&create_resp.stdout), "hi\n");
+            std::fs::remove_dir_all(&out_dir).expect("failed to clean up out_dir");
+        }
+
+        #[test]
+        fn capture_setup_failure_names_stream() {
+            use crate::error::ErrorJSONResp;
+
+            let out_dir = tempfile::tempdir().unwrap();
+            // Tests tend to run as root, which ignores a read-only out_dir,
+            // so block the puppet's directory with a file instead.
+            std::fs::write(out_dir.path().join("0"), "").unwrap();
+            let client = make_configured_client(("out_dir", out_dir.path()));
+            let resp = client
+                .put("/cmd")
+                .json(&CreateReq {
+                    exec: "echo",
+                    args: vec!["hi"],
+                    capture: Some(CaptureOptions::all()),
+                    ..Default::default()
+                })
+                .dispatch();
+            assert_eq!(resp.status(), Status::InternalServerError);
+            let err_json = resp.into_json::<ErrorJSONResp>().unwrap();
+            assert_eq!(err_json.code, "capture_setup_failed");
+            assert!(err_json
+                .err
+                .starts_with("failed to set up capture of stdout: "));
+        }
+
+        #[test]
+        fn full_disk_is_reported() {
+            let out_dir = tempfile::tempdir().unwrap();
+            // /dev/full fails every write with ENOSPC, as a full disk
+            // would. Capturing with `append` keeps it from being
+            // truncated, which it can't be.
+            let id_dir = out_dir.path().join("0");
+            std::fs::create_dir(&id_dir).unwrap();
+            std::os::unix::fs::symlink("/dev/full", id_dir.join("stdout")).unwrap();
+            // There'd be no end to reading /dev/full back in.
+            let client = make_configured_client(
+                Figment::new()
+                    .merge(("out_dir", out_dir.path()))
+                    .merge(("max_inline_output_bytes", 0)),
+            );
+            let create_resp = create_req(
+                &client,
+                "sh",
+                vec!["-c", "for i in 1 2 3; do echo $i; sleep 0.05; done"],
+                CaptureOptions {
+                    append: true,
+                    ..CaptureOptions::stdout()
+                },
+            );
+            // Not killed for writing to a pipe nobody's reading any more.
+            assert!(wait_for_id(&client, create_resp.id).success);
+            let status = get_status(&client, create_resp.id);
+            let err = status.stdout.error.expect("expected a capture error");
+            assert!(err.contains("No space left on device"), "{}", err);
+            assert!(!status.stdout.open);
+            assert_eq!(status.stdout_bytes, Some(6));
+            assert_eq!(status.stderr.error, None);
+        }
+
+        #[test]
+        fn append_keeps_earlier_output() {
+            let out_dir = std::env::temp_dir().join(format!("puppet-out-{}", Uuid::new_v4()));
+            // Pretend an earlier server left output behind for id 0.
+            std::fs::create_dir_all(out_dir.join("0")).expect("failed to create id dir");
+            std::fs::write(out_dir.join("0").join("stdout"), "old\n")
+                .expect("failed to write earlier output");
+
+            let client = make_configured_client(("out_dir", &out_dir));
+            let create_resp = create_req(
+                &client,
+                "echo",
+                vec!["new"],
+                CaptureOptions {
+                    append: true,
+                    ..CaptureOptions::stdout()
+                },
+            );
+            assert_eq!(create_resp.id, PuppetId::Seq(0));
+            assert!(wait_for_id(&client, create_resp.id).success);
+            assert_eq!(get_captured(&create_resp.stdout), "old\nnew\n");
+            std::fs::remove_dir_all(&out_dir).expect("failed to clean up out_dir");
+        }
+
+        #[test]
+        fn temp_dir_parent_and_prefix() {
+            let parent = tempfile::tempdir().unwrap();
+            let client = make_configured_client(
+                Figment::new()
+                    .merge(("temp_dir_parent", parent.path()))
+                    .merge(("temp_dir_prefix", "puppets-")),
+            );
+            let create_resp = create_req(&client, "echo", vec!["hi"], CaptureOptions::stdout());
+            assert!(wait_for_id(&client, create_resp.id).success);
+            let path = Path::new(captured_path(&create_resp.stdout));
+            let out_dir = path
+                .strip_prefix(parent.path())
+                .expect("expected the capture to be under temp_dir_parent")
+                .components()
+                .next()
+                .expect("expected a directory under temp_dir_parent");
+            let name = out_dir.as_os_str().to_str().unwrap();
+            assert!(name.starts_with("puppets-"), "got {}", name);
+            assert_eq!(get_captured(&create_resp.stdout), "hi\n");
+
+            // The directory is still only temporary.
+            let out_dir = parent.path().join(name);
+            client.terminate();
+            assert!(!out_dir.exists());
+        }
+
+        #[test]
+        fn oversized_commands_are_rejected() {
+            let client = make_configured_client(
+                Figment::new()
+                    .merge(("max_args", 2))
+                    .merge(("max_command_bytes", 16)),
+            );
+            let status = |args: Vec<&str>| {
+                client
+                    .put("/cmd")
+                    .json(&CreateReq {
+                        exec: "true",
+                        args,
+                        ..Default::default()
+                    })
+                    .dispatch()
+                    .status()
+            };
+            assert_eq!(status(vec!["a", "b", "c"]), Status::PayloadTooLarge);
+            assert_eq!(status(vec!["0123456789abcdef"]), Status::PayloadTooLarge);
+            assert_eq!(status(vec!["a", "b"]), Status::Created);
+        }
+
+        fn id_round_trip(client: &Client) -> PuppetId {
+            let create_resp = spawn_forever(client)
+                .into_json::<CreateResp>()
+                .expect("expected the puppet to spawn");
+            kill_id(client, create_resp.id);
+            let wait_resp = wait_for_id(client, create_resp.id);
+            assert_eq!(wait_resp.id, create_resp.id);
+            assert!(wait_resp.signaled);
+            create_resp.id
+        }
+
+        #[test]
+        fn default_capture_applies_when_omitted() {
+            let client = make_configured_client(("default_capture", "all"));
+            let req = CreateReq {
+                exec: "echo",
+                args: vec!["hi"],
+                ..Default::default()
+            };
+            let create_resp = create(&client, &req);
+            assert!(wait_for_id(&client, create_resp.id).success);
+            assert_eq!(get_captured(&create_resp.stdout), "hi\n");
+            assert_eq!(get_captured(&create_resp.stderr), "");
+
+            // Asking for nothing to be captured still works.
+            let create_resp = create(
+                &client,
+                &CreateReq {
+                    capture: Some(CaptureOptions::none()),
+                    ..req
+                },
+            );
+            assert_eq!(create_resp.stdout, StreamMode::Inherited);
+            assert_eq!(create_resp.stderr, StreamMode::Inherited);
+        }
+
+        #[test]
+        fn only_allowed_execs_run() {
+            let client = make_configured_client(("allowed_execs", ["echo", "/bin/true"]));
+            for exec in ["echo", "/bin/true"] {
+                let create_resp = create_req(&client, exec, vec![], CaptureOptions::none());
+                assert!(wait_for_id(&client, create_resp.id).success);
+            }
+            // A bare name only allows that name, not whatever path has it
+            // as a file name.
+            for exec in ["true", "/bin/echo"] {
+                let resp = client
+                    .put("/cmd")
+                    .json(&CreateReq {
+                        exec,
+                        ..Default::default()
+                    })
+                    .dispatch();
+                assert_eq!(resp.status(), Status::Forbidden);
+            }
+        }
+
+        #[test]
+        fn require_absolute_exec() {
+            let client = make_configured_client(("require_absolute_exec", true));
+            let status = |exec| {
+                client
+                    .put("/cmd")
+                    .json(&CreateReq {
+                        exec,
+                        ..Default::default()
+                    })
+                    .dispatch()
+                    .status()
+            };
+            assert_eq!(status("echo"), Status::BadRequest);
+            assert_eq!(status("./echo"), Status::BadRequest);
+            assert_eq!(status("/bin/echo"), Status::Created);
+        }
+
+        #[test]
+        fn sequential_ids() {
+            let client = make_configured_client(("id_scheme", "sequential"));
+            assert_eq!(id_round_trip(&client), PuppetId::Seq(0));
+            assert_eq!(id_round_trip(&client), PuppetId::Seq(1));
+        }
+
+        #[test]
+        fn uuid_ids() {
+            let client = make_configured_client(("id_scheme", "uuid"));
+            let first = id_round_trip(&client);
+            assert!(matches!(first, PuppetId::Uuid(_)));
+            assert_ne!(id_round_trip(&client), first);
+            // A sequential id is well-formed, but can't name anything here.
+            assert_eq!(client.post("/wait/0").dispatch().status(), Status::NotFound);
+        }
+    }
+
+    mod output {
+        use super::*;
+
+        fn truncate_stream(client: &Client, id: PuppetId, stream: &str) -> Status {
+            client
+                .post(format!("/output/{}/{}/truncate", id, stream))
+                .dispatch()
+                .status()
+        }
+
+        fn capture_bytes(client: &Client, bytes: Vec<&str>) -> CreateResp {
+            let bytes_print = get_testscript_path("bytes.sh");
+            let create_resp = create_req(
+                client,
+                bytes_print
+                    .to_str()
+                    .expect("failed to unwrap bytes script filepath"),
+                bytes,
+                CaptureOptions::stdout(),
+            );
+            assert!(wait_for_id(client, create_resp.id).success);
+            create_resp
+        }
+
+        #[test]
+        fn fetch_binary_output() {
+            let client = make_rocket_client();
+            let create_resp = capture_bytes(&client, vec!["00", "01", "02"]);
+            let resp = client
+                .get(format!("/output/{}/stdout", create_resp.id))
+                .dispatch();
+            assert_eq!(resp.status(), Status::Ok);
+            assert_eq!(resp.content_type(), Some(ContentType::Binary));
+            assert_eq!(resp.into_bytes(), Some(vec![0x00, 0x01, 0x02]));
+        }
+
+        #[test]
+        fn fetch_tail() {
+            let client = make_rocket_client();
+            let tail = |lines: u64, count: &str| {
+                let create_resp =
+                    create_req(&client, "seq", vec!["1", count], CaptureOptions::stdout());
+                assert!(wait_for_id(&client, create_resp.id).success);
+                let resp = client
+                    .get(format!("/output/{}/stdout?tail={}", create_resp.id, lines))
+                    .dispatch();
+                assert_eq!(resp.status(), Status::Ok);
+                resp.into_string().unwrap()
+            };
+            assert_eq!(tail(5, "100"), "96\n97\n98\n99\n100\n");
+            assert_eq!(tail(0, "100"), "");
+            // Fewer lines than asked for gets all of them.
+            assert_eq!(tail(5, "3"), "1\n2\n3\n");
+            // Far more than fits in one chunk read from the end.
+            let expected: String = (2001..=10000).map(|n| format!("{}\n", n)).collect();
+            assert_eq!(tail(8000, "10000"), expected);
+        }
+
+        #[test]
+        fn fetch_output_as_text() {
+            let client = make_rocket_client();
+            let text = capture_bytes(&client, vec!["68", "69"]);
+            let resp = client
+                .get(format!("/output/{}/stdout?as=text", text.id))
+                .dispatch();
+            assert_eq!(resp.status(), Status::Ok);
+            assert_eq!(resp.into_string(), Some(String::from("hi")));
+
+            let binary = capture_bytes(&client, vec!["ff"]);
+            let resp = client
+                .get(format!("/output/{}/stdout?as=text", binary.id))
+                .dispatch();
+            assert_eq!(resp.status(), Status::UnprocessableEntity);
+        }
+
+        #[test]
+        fn fetch_gzipped_output() {
+            use flate2::read::GzDecoder;
+            use rocket::http::Header;
+            use std::io::Read;
+
+            let client = make_rocket_client();
+            let create_resp = create_req(&client, "seq", vec!["1000"], CaptureOptions::stdout());
+            assert!(wait_for_id(&client, create_resp.id).success);
+            let expected = get_captured(&create_resp.stdout);
+            let resp = client
+                .get(format!("/output/{}/stdout", create_resp.id))
+                .header(Header::new("Accept-Encoding", "deflate, gzip;q=0.5"))
+                .dispatch();
+            assert_eq!(resp.status(), Status::Ok);
+            assert_eq!(resp.headers().get_one("Content-Encoding"), Some("gzip"));
+            let compressed = resp.into_bytes().unwrap();
+            let mut decompressed = String::new();
+            GzDecoder::new(&compressed[..])
+                .read_to_string(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, expected);
+            assert!(compressed.len() < decompressed.len());
+
+            let resp = client
+                .get(format!("/output/{}/stdout", create_resp.id))
+                .dispatch();
+            assert_eq!(resp.headers().get_one("Content-Encoding"), None);
+            assert_eq!(resp.into_string().unwrap(), expected);
+        }
+
+        #[test]
+        fn output_open_while_running() {
+            let client = make_rocket_client();
+            let create_resp = create_req(
+                &client,
+                "sh",
+                vec!["-c", "echo hi; sleep 100"],
+                CaptureOptions::stdout(),
+            );
+            let open_header = || {
+                client
+                    .get(format!("/output/{}/stdout", create_resp.id))
+                    .dispatch()
+                    .headers()
+                    .get_one("X-Puppeteer-Open")
+                    .map(String::from)
+            };
+
+            let status = get_status(&client, create_resp.id);
+            assert!(status.stdout.open);
+            assert!(!status.stderr.open);
+            assert_eq!(open_header().as_deref(), Some("true"));
+
+            kill_id(&client, create_resp.id);
+            let status = get_status(&client, create_resp.id);
+            assert!(!status.stdout.open);
+            assert_eq!(open_header().as_deref(), Some("false"));
+        }
+
+        #[test]
+        fn discard_uncaptured_streams() {
+            let client = make_rocket_client();
+            let quiet = create_req(
+                &client,
+                "echo",
+                vec!["nobody hears this"],
+                CaptureOptions {
+                    discard: true,
+                    ..CaptureOptions::none()
+                },
+            );
+            assert_eq!(quiet.stdout, StreamMode::Discarded);
+            assert_eq!(quiet.stderr, StreamMode::Discarded);
+            assert!(wait_for_id(&client, quiet.id).success);
+            let body = client
+                .get(format!("/status/{}", quiet.id))
+                .dispatch()
+                .into_string()
+                .unwrap();
+            assert!(body.contains(r#""mode":"discarded""#), "got {}", body);
+            assert_eq!(
+                client
+                    .get(format!("/output/{}/stdout", quiet.id))
+                    .dispatch()
+                    .status(),
+                Status::NotFound
+            );
+
+            // Only what isn't captured is thrown away.
+            let half = create_req(
+                &client,
+                "sh",
+                vec!["-c", "echo out; echo error >&2"],
+                CaptureOptions {
+                    discard: true,
+                    ..CaptureOptions::stdout()
+                },
+            );
+            assert_eq!(half.stderr, StreamMode::Discarded);
+            assert!(wait_for_id(&client, half.id).success);
+            assert_eq!(get_captured(&half.stdout), "out\n");
+        }
+
+        #[test]
+        fn status_counts_written_bytes() {
+            let client = make_rocket_client();
+            let expected: usize = (1..=10000).map(|n: u32| n.to_string().len() + 1).sum();
+            let trimmed = create_req(
+                &client,
+                "seq",
+                vec!["10000"],
+                CaptureOptions {
+                    max_capture_bytes: Some(100),
+                    ..CaptureOptions::stdout()
+                },
+            );
+            assert!(wait_for_id(&client, trimmed.id).success);
+            let status = get_status(&client, trimmed.id);
+            // Everything is counted, not just what's left in the capture.
+            assert_eq!(status.stdout_bytes, Some(expected as u64));
+            assert_eq!(get_captured(&trimmed.stdout).len(), 100);
+            assert_eq!(status.stderr_bytes, None);
+
+            let both = create_req(
+                &client,
+                "sh",
+                vec!["-c", "echo out; echo error >&2"],
+                CaptureOptions::all(),
+            );
+            assert!(wait_for_id(&client, both.id).success);
+            let status = get_status(&client, both.id);
+            assert_eq!(status.stdout_bytes, Some(4));
+            assert_eq!(status.stderr_bytes, Some(6));
+        }
+
+        #[test]
+        fn fetch_in_memory_output() {
+            let out_dir = tempfile::tempdir().unwrap();
+            let client = make_configured_client(("out_dir", out_dir.path()));
+            let create_resp = create_req(
+                &client,
+                "echo",
+                vec!["hello world"],
+                CaptureOptions {
+                    in_memory: true,
+                    max_capture_bytes: Some(6),
+                    ..CaptureOptions::stdout()
+                },
+            );
+            assert_eq!(create_resp.stdout, StreamMode::Memory);
+            let wait_resp = wait_for_id(&client, create_resp.id);
+            assert!(wait_resp.success);
+            let stdout = wait_resp.stdout.expect("expected stdout to be reported");
+            assert_eq!(stdout.content.as_deref(), Some("world\n"));
+            assert_eq!(stdout.path, None);
+
+            let resp = client
+                .get(format!("/output/{}/stdout", create_resp.id))
+                .dispatch();
+            assert_eq!(resp.status(), Status::Ok);
+            assert_eq!(
+                resp.headers().get_one("X-Puppeteer-Dropped-Bytes"),
+                Some("6")
+            );
+            assert_eq!(resp.into_string(), Some(String::from("world\n")));
+            // Nothing was written to disk for it.
+            assert_eq!(std::fs::read_dir(out_dir.path()).unwrap().count(), 0);
+
+            // Nor is it ever rotated.
+            let resp = client
+                .get(format!("/output/{}/stdout?rotation=1", create_resp.id))
+                .dispatch();
+            assert_eq!(resp.status(), Status::BadRequest);
+        }
+
+        #[test]
+        fn fetch_output_range() {
+            use rocket::http::Header;
+
+            let client = make_rocket_client();
+            let create_resp = create_req(
+                &client,
+                "echo",
+                vec!["hello world"],
+                CaptureOptions::stdout(),
+            );
+            assert!(wait_for_id(&client, create_resp.id).success);
+            let fetch = |range: &str| {
+                client
+                    .get(format!("/output/{}/stdout", create_resp.id))
+                    .header(Header::new("Range", range.to_string()))
+                    .dispatch()
+            };
+
+            let resp = fetch("bytes=0-3");
+            assert_eq!(resp.status(), Status::PartialContent);
+            assert_eq!(
+                resp.headers().get_one("Content-Range"),
+                Some("bytes 0-3/12")
+            );
+            assert_eq!(resp.into_bytes().unwrap(), b"hell");
+
+            let resp = fetch("bytes=-3");
+            assert_eq!(
+                resp.headers().get_one("Content-Range"),
+                Some("bytes 9-11/12")
+            );
+            assert_eq!(resp.into_bytes().unwrap(), b"ld\n");
+
+            let resp = fetch("bytes=6-100");
+            assert_eq!(
+                resp.headers().get_one("Content-Range"),
+                Some("bytes 6-11/12")
+            );
+            assert_eq!(resp.into_bytes().unwrap(), b"world\n");
+
+            let resp = fetch("bytes=12-");
+            assert_eq!(resp.status(), Status::RangeNotSatisfiable);
+            assert_eq!(resp.headers().get_one("Content-Range"), Some("bytes */12"));
+
+            // Ranges it doesn't understand get the whole output.
+            let resp = fetch("bytes=0-1,4-5");
+            assert_eq!(resp.status(), Status::Ok);
+            assert_eq!(resp.into_bytes().unwrap(), b"hello world\n");
+        }
+
+        #[test]
+        fn truncate_keeps_only_later_writes() {
+            let client = make_rocket_client();
+            let gate = std::env::temp_dir().join(format!("puppet-gate-{}", Uuid::new_v4()));
+            let gated = get_testscript_path("gated.sh");
+            let create_resp = create_req(
+                &client,
+                gated
+                    .to_str()
+                    .expect("failed to unwrap gated script filepath"),
+                vec![gate.to_str().unwrap(), "before", "after"],
+                CaptureOptions::stdout(),
+            );
+
+            while get_captured(&create_resp.stdout) != "before\n" {
+                std::thread::sleep(time::Duration::from_millis(10));
+            }
+            assert_eq!(
+                truncate_stream(&client, create_resp.id, "stdout"),
+                Status::Ok
+            );
+            assert_eq!(get_captured(&create_resp.stdout), "");
+
+            std::fs::write(&gate, "").expect("failed to create gate file");
+            assert!(wait_for_id(&client, create_resp.id).success);
+            std::fs::remove_file(&gate).expect("failed to remove gate file");
+
+            // Had the child kept its own write offset, "after" would be
+            // preceded by a hole of NUL bytes the size of "before\n".
+            assert_eq!(get_captured(&create_resp.stdout), "after\n");
+        }
+
+        fn capped_seq(client: &Client, last: &str, max_capture_bytes: u64) -> CreateResp {
+            let create_resp = create_req(
+                client,
+                "seq",
+                vec!["1", last],
+                CaptureOptions {
+                    max_capture_bytes: Some(max_capture_bytes),
+                    ..CaptureOptions::stdout()
+                },
+            );
+            assert!(wait_for_id(client, create_resp.id).success);
+            create_resp
+        }
+
+        #[test]
+        fn capture_cap_keeps_latest_output() {
+            let client = make_rocket_client();
+            let create_resp = capped_seq(&client, "1000", 100);
+            let expected: String = (1..=1000).map(|n| format!("{}\n", n)).collect();
+            let kept = &expected[expected.len() - 100..];
+            assert_eq!(get_captured(&create_resp.stdout), kept);
+
+            let status = get_status(&client, create_resp.id);
+            assert!(!status.running);
+            assert!(status.stdout.truncated);
+            assert_eq!(status.stdout.dropped_bytes, expected.len() as u64 - 100);
+            assert!(!status.stderr.truncated);
+
+            let resp = client
+                .get(format!("/output/{}/stdout", create_resp.id))
+                .dispatch();
+            assert_eq!(
+                resp.headers().get_one("X-Puppeteer-Truncated"),
+                Some("true")
+            );
+            assert_eq!(
+                resp.headers().get_one("X-Puppeteer-Dropped-Bytes"),
+                Some((expected.len() - 100).to_string().as_str())
+            );
+        }
+
+        #[test]
+        fn capture_under_cap_is_not_truncated() {
+            let client = make_rocket_client();
+            let create_resp = capped_seq(&client, "3", 100);
+            assert_eq!(get_captured(&create_resp.stdout), "1\n2\n3\n");
+
+            let listed = client
+                .get("/list")
+                .dispatch()
+                .into_json::<Vec<StatusResp>>()
+                .expect("expected a non-None response for listing puppets");
+            assert_eq!(listed.len(), 1);
+            assert_eq!(listed[0].id, create_resp.id);
+            assert!(!listed[0].stdout.truncated);
+            assert_eq!(listed[0].stdout.dropped_bytes, 0);
+            assert_eq!(listed[0].stdout.mode, create_resp.stdout);
+        }
+
+        #[test]
+        fn capture_keeps_latest_lines() {
+            let client = make_rocket_client();
+            let max_lines = |max_lines| CaptureOptions {
+                max_lines: Some(max_lines),
+                ..CaptureOptions::stdout()
+            };
+            let create_resp = create_req(&client, "seq", vec!["1", "1000"], max_lines(10));
+            assert!(wait_for_id(&client, create_resp.id).success);
+            let expected: String = (1..=1000).map(|n| format!("{}\n", n)).collect();
+            let kept: String = (991..=1000).map(|n| format!("{}\n", n)).collect();
+            assert_eq!(get_captured(&create_resp.stdout), kept);
+            assert_eq!(
+                get_status(&client, create_resp.id).stdout.dropped_bytes,
+                (expected.len() - kept.len()) as u64
+            );
+
+            // An unfinished last line doesn't count against the limit.
+            let create_resp = create_req(
+                &client,
+                "sh",
+                vec!["-c", "echo a; echo b; printf c"],
+                max_lines(1),
+            );
+            assert!(wait_for_id(&client, create_resp.id).success);
+            assert_eq!(get_captured(&create_resp.stdout), "b\nc");
+        }
+
+        #[test]
+        fn capture_rotates_by_size() {
+            let client = make_rocket_client();
+            let create_resp = create_req(
+                &client,
+                "seq",
+                vec!["1", "15"],
+                CaptureOptions {
+                    rotate_bytes: Some(10),
+                    rotate_keep: Some(2),
+                    ..CaptureOptions::stdout()
+                },
+            );
+            assert!(wait_for_id(&client, create_resp.id).success);
+            // 36 bytes in all, so three rotations, the first of which has
+            // since been dropped.
+            let expected: String = (1..=15).map(|n| format!("{}\n", n)).collect();
+            let path = captured_path(&create_resp.stdout);
+            assert_eq!(get_captured(&create_resp.stdout), &expected[30..]);
+            assert_eq!(get_contents(&format!("{}.1", path)), &expected[20..30]);
+            assert_eq!(get_contents(&format!("{}.2", path)), &expected[10..20]);
+            assert!(!Path::new(&format!("{}.3", path)).exists());
+            assert_eq!(get_status(&client, create_resp.id).stdout.dropped_bytes, 10);
+
+            let rotations = client
+                .get(format!("/output/{}/stdout/rotations", create_resp.id))
+                .dispatch()
+                .into_json::<crate::routes::RotationsResp>()
+                .expect("expected a non-None response for listing rotations");
+            assert_eq!(
+                rotations.rotated,
+                vec![format!("{}.1", path), format!("{}.2", path)]
+            );
+            let resp = client
+                .get(format!("/output/{}/stdout?rotation=2", create_resp.id))
+                .dispatch();
+            assert_eq!(resp.into_string().unwrap(), &expected[10..20]);
+        }
+    }
+
+    mod stdin {
+        use super::*;
+
+        fn write_stdin(client: &Client, id: PuppetId, body: &str, close: bool) -> Status {
+            client
+                .post(format!("/stdin/{}?close={}", id, close))
+                .body(body)
+                .dispatch()
+                .status()
+        }
+
+        #[test]
+        fn stream_lines_to_cat() {
+            let client = make_rocket_client();
+            let create_resp = create(
+                &client,
+                &CreateReq {
+                    exec: "cat",
+                    capture: Some(CaptureOptions::stdout()),
+                    stdin: true,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(
+                write_stdin(&client, create_resp.id, "hello\n", false),
+                Status::Ok
+            );
+            assert_eq!(
+                write_stdin(&client, create_resp.id, "world\n", true),
+                Status::Ok
+            );
+            assert!(wait_for_id(&client, create_resp.id).success);
+            assert_eq!(get_captured(&create_resp.stdout), "hello\nworld\n");
+
+            // Once closed, there's nowhere left to write to.
+            assert_eq!(
+                write_stdin(&client, create_resp.id, "again\n", false),
+                Status::Conflict
+            );
+        }
+
+        #[test]
+        fn write_over_limit() {
+            let client = make_configured_client(("limits.stdin", 8));
+            let create_resp = create(
+                &client,
+                &CreateReq {
+                    exec: "cat",
+                    capture: Some(CaptureOptions::stdout()),
+                    stdin: true,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(
+                write_stdin(&client, create_resp.id, "hello world\n", true),
+                Status::PayloadTooLarge
+            );
+            // Being refused, it wasn't closed either.
+            assert_eq!(write_stdin(&client, create_resp.id, "", true), Status::Ok);
+            assert!(wait_for_id(&client, create_resp.id).success);
+            assert_eq!(get_captured(&create_resp.stdout), "hello wo");
+        }
+
+        #[test]
+        fn write_after_exit() {
+            let client = make_rocket_client();
+            let create_resp = create(
+                &client,
+                &CreateReq {
+                    exec: "true",
+                    stdin: true,
+                    ..Default::default()
+                },
+            );
+            assert!(wait_for_id(&client, create_resp.id).success);
+            assert_eq!(
+                write_stdin(&client, create_resp.id, "anyone there?\n", false),
+                Status::Conflict
+            );
+        }
+
+        #[test]
+        fn replay_recorded_stdin() {
+            let client = make_rocket_client();
+            let create_resp = create(
+                &client,
+                &CreateReq {
+                    exec: "cat",
+                    capture: Some(CaptureOptions::stdout()),
+                    stdin: true,
+                    record_stdin: true,
+                    ..Default::default()
+                },
+            );
+            let replay = || {
+                client
+                    .post(format!("/replay/{}", create_resp.id))
+                    .dispatch()
+            };
+            // Only what's been fully written can be replayed.
+            assert_eq!(replay().status(), Status::Conflict);
+
+            assert_eq!(
+                write_stdin(&client, create_resp.id, "hello\n", false),
+                Status::Ok
+            );
+            assert_eq!(
+                write_stdin(&client, create_resp.id, "world\n", true),
+                Status::Ok
+            );
+            assert!(wait_for_id(&client, create_resp.id).success);
+
+            let resp = replay();
+            assert_eq!(resp.status(), Status::Created);
+            let location = resp.headers().get_one("Location").map(String::from);
+            let replay_resp = resp
+                .into_json::<CreateResp>()
+                .expect("expected a non-None response for replaying");
+            assert_eq!(location, Some(format!("/status/{}", replay_resp.id)));
+            assert_ne!(replay_resp.id, create_resp.id);
+            assert!(wait_for_id(&client, replay_resp.id).success);
+            assert_eq!(get_captured(&replay_resp.stdout), "hello\nworld\n");
+            assert_eq!(
+                get_captured(&replay_resp.stdout),
+                get_captured(&create_resp.stdout)
+            );
+        }
+
+        #[test]
+        fn replay_skips_writes_over_limit() {
+            let client = make_configured_client(("limits.stdin", 8));
+            let create_resp = create(
+                &client,
+                &CreateReq {
+                    exec: "cat",
+                    capture: Some(CaptureOptions::stdout()),
+                    stdin: true,
+                    record_stdin: true,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(
+                write_stdin(&client, create_resp.id, "hello world\n", false),
+                Status::PayloadTooLarge
+            );
+            assert_eq!(
+                write_stdin(&client, create_resp.id, "hello\n", true),
+                Status::Ok
+            );
+            assert!(wait_for_id(&client, create_resp.id).success);
+            assert_eq!(get_captured(&create_resp.stdout), "hello\n");
+
+            let replay_resp = client
+                .post(format!("/replay/{}", create_resp.id))
+                .dispatch()
+                .into_json::<CreateResp>()
+                .expect("expected a non-None response for replaying");
+            assert!(wait_for_id(&client, replay_resp.id).success);
+            assert_eq!(get_captured(&replay_resp.stdout), "hello\n");
+        }
+
+        #[test]
+        fn replay_requires_record_stdin() {
+            let client = make_rocket_client();
+            let create_resp = create(
+                &client,
+                &CreateReq {
+                    exec: "true",
+                    stdin: true,
+                    ..Default::default()
+                },
+            );
+            assert!(wait_for_id(&client, create_resp.id).success);
+            let resp = client
+                .post(format!("/replay/{}", create_resp.id))
+                .dispatch();
+            assert_eq!(resp.status(), Status::BadRequest);
+
+            let resp = client
+                .put("/cmd")
+                .json(&CreateReq {
+                    exec: "cat",
+                    record_stdin: true,
+                    ..Default::default()
+                })
+                .dispatch();
+            assert_eq!(resp.status(), Status::BadRequest);
+        }
+    }
+
+    // TODO: Need to test error cases:
+    // * double-wait
+    // * double-kill
+    // * exec that DNE
+    // * exec that isn't an exec?
+
+    mod errors {
+        use super::*;
+        use crate::error::ErrorJSONResp;
+
+        #[test]
+        fn puppet_dne() {
+            let client = make_rocket_client();
+            let fake_id = 243423423;
+            let resp = client.post(format!("/wait/{}", fake_id)).dispatch();
+            let err_json = resp.into_json::<ErrorJSONResp>().unwrap();
+            assert_eq!(
+                err_json.err,
+                format!("puppet with id '{}' not found", fake_id)
+            );
+        }
+
+        #[test]
+        fn error_code() {
+            let client = make_rocket_client();
+            let resp = client.post("/wait/9999").dispatch();
+            assert_eq!(resp.status(), Status::NotFound);
+            let err_json = resp.into_json::<ErrorJSONResp>().unwrap();
+            assert_eq!(err_json.code, "puppet_not_found");
+        }
+
+        #[test]
+        fn errors_as_plain_text() {
+            use rocket::http::Accept;
+
+            let client = make_rocket_client();
+            let resp = client.get("/status/9999").header(Accept::Text).dispatch();
+            assert_eq!(resp.status(), Status::NotFound);
+            assert_eq!(resp.content_type(), Some(ContentType::Plain));
+            assert_eq!(
+                resp.into_string().unwrap(),
+                "puppet with id '9999' not found"
+            );
+
+            // JSON is still preferred when asked for, or when nothing is.
+            for accept in [Accept::JSON, Accept::Any] {
+                let resp = client.get("/status/9999").header(accept).dispatch();
+                assert_eq!(resp.content_type(), Some(ContentType::JSON));
+                let err_json = resp.into_json::<ErrorJSONResp>().unwrap();
+                assert_eq!(err_json.code, "puppet_not_found");
+            }
+        }
+
+        #[test]
+        fn capture_of_running_puppet_is_unsupported() {
+            let client = make_rocket_client();
+            let create_resp = create_req(&client, "sleep", vec!["100"], CaptureOptions::none());
+            let start_capture = || {
+                client
+                    .post(format!("/capture/{}", create_resp.id))
+                    .json(&CaptureOptions::stdout())
+                    .dispatch()
+            };
+
+            let resp = start_capture();
+            assert_eq!(resp.status(), Status::NotImplemented);
+            let err_json = resp.into_json::<ErrorJSONResp>().unwrap();
+            assert_eq!(err_json.code, "unsupported");
+
+            kill_id(&client, create_resp.id);
+            assert_eq!(start_capture().status(), Status::Conflict);
+        }
+
+        #[test]
+        fn negative_id() {
+            let client = make_rocket_client();
+            let resp = client.post("/wait/-1").dispatch();
+            assert_eq!(resp.status(), Status::BadRequest);
+            let err_json = resp.into_json::<ErrorJSONResp>().unwrap();
+            assert_eq!(err_json.err, "invalid id '-1': ids are never negative");
+        }
+
+        fn create_status(client: &Client, exec: &str) -> Status {
+            client
+                .put("/cmd")
+                .json(&CreateReq {
+                    exec,
+                    args: vec![],
+                    capture: None,
+                    ..Default::default()
+                })
+                .dispatch()
+                .status()
+        }
+
+        fn dry_run<'c>(client: &'c Client, exec: &str) -> LocalResponse<'c> {
+            client
+                .put("/cmd?dry_run=true")
+                .json(&CreateReq {
+                    exec,
+                    args: vec!["hi"],
+                    ..Default::default()
+                })
+                .dispatch()
+        }
+
+        #[test]
+        fn dry_run_spawns_nothing() {
+            use crate::routes::DryRunResp;
+
+            let client = make_rocket_client();
+            let resp = dry_run(&client, "echo");
+            assert_eq!(resp.status(), Status::Ok);
+            let dry_run_resp = resp.into_json::<DryRunResp>().unwrap();
+            assert!(dry_run_resp.valid);
+            assert_eq!(dry_run_resp.command, vec!["echo", "hi"]);
+
+            let resp = dry_run(&client, "");
+            assert_eq!(resp.status(), Status::BadRequest);
+            assert!(resp.into_json::<ErrorJSONResp>().is_some());
+
+            // Neither dry run should have used up an id.
+            let create_resp = create_req(&client, "true", vec![], CaptureOptions::none());
+            assert_eq!(create_resp.id, PuppetId::Seq(0));
+        }
+
+        #[test]
+        fn deleted_output() {
+            let client = make_rocket_client();
+            let create_resp = create_req(&client, "echo", vec!["hi"], CaptureOptions::all());
+            assert!(wait_for_id(&client, create_resp.id).success);
+            std::fs::remove_file(captured_path(&create_resp.stdout))
+                .expect("failed to remove captured stdout");
+
+            let gone = client
+                .get(format!("/output/{}/stdout", create_resp.id))
+                .dispatch();
+            assert_eq!(gone.status(), Status::Gone);
+            assert_eq!(
+                gone.into_json::<ErrorJSONResp>().unwrap().err,
+                format!(
+                    "captured output of puppet with id '{}' no longer exists",
+                    create_resp.id
+                )
+            );
+            let truncate = client
+                .post(format!("/output/{}/stdout/truncate", create_resp.id))
+                .dispatch();
+            assert_eq!(truncate.status(), Status::Gone);
+
+            // The other stream is still there, and one that was never
+            // captured is still a 404.
+            let stderr = client
+                .get(format!("/output/{}/stderr", create_resp.id))
+                .dispatch();
+            assert_eq!(stderr.status(), Status::Ok);
+            let inherited = create_req(&client, "true", vec![], CaptureOptions::none());
+            let not_captured = client
+                .get(format!("/output/{}/stdout", inherited.id))
+                .dispatch();
+            assert_eq!(not_captured.status(), Status::NotFound);
+        }
+
+        #[test]
+        fn empty_exec() {
+            let client = make_rocket_client();
+            assert_eq!(create_status(&client, ""), Status::BadRequest);
+        }
+
+        #[test]
+        fn missing_exec() {
+            let client = make_rocket_client();
+            assert_eq!(
+                create_status(&client, "/does/not/exist"),
+                Status::BadRequest
+            );
+            assert_eq!(
+                create_status(&client, &format!("puppet-{}", Uuid::new_v4())),
+                Status::BadRequest
+            );
+        }
+
+        #[test]
+        fn directory_exec() {
+            let client = make_rocket_client();
+            let dir = tempfile::tempdir().expect("failed to create temp dir");
+            assert_eq!(
+                create_status(&client, dir.path().to_str().unwrap()),
+                Status::BadRequest
+            );
+        }
+    }
+
+    mod webhook {
+        use super::*;
+        use crate::puppet::PuppetManager;
+        use crate::routes::Stream;
+        use crate::webhook::WebhookEvent;
+        use rocket::tokio::time::{sleep, Instant};
+        use rocket::State;
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Default)]
+        struct Received {
+            posts: AtomicU32,
+            events: Mutex<Vec<String>>,
+        }
+
+        #[post("/hook", data = "<body>")]
+        fn hook(body: String, received: &State<Arc<Received>>) -> Status {
+            // Turning away the first post makes it be retried.
+            if received.posts.fetch_add(1, Ordering::Relaxed) == 0 {
+                return Status::InternalServerError;
+            }
+            received.events.lock().unwrap().push(body);
+            Status::Ok
+        }
+
+        #[rocket::async_test]
+        async fn receives_output_and_exit() {
+            let port = std::net::TcpListener::bind("127.0.0.1:0")
+                .and_then(|listener| listener.local_addr())
+                .expect("failed to find a free port")
+                .port();
+            let received = Arc::new(Received::default());
+            let receiver = rocket::custom(rocket::Config::figment().merge(("port", port)))
+                .mount("/", routes![hook])
+                .manage(Arc::clone(&received))
+                .ignite()
+                .await
+                .unwrap();
+            let shutdown = receiver.shutdown();
+            rocket::tokio::spawn(receiver.launch());
+
+            let rocket = rocket().ignite().await.unwrap();
+            let url = format!("http://127.0.0.1:{}/hook", port);
+            let id = {
+                let pups = rocket.state::<PuppetManager>().unwrap();
+                let req = CreateReq {
+                    exec: "echo",
+                    args: vec!["hi"],
+                    capture: Some(CaptureOptions::stdout()),
+                    webhook_url: Some(&url),
+                    ..Default::default()
+                };
+                pups.push(&req).expect("failed to spawn echo").id
+            };
+
+            let deadline = Instant::now() + time::Duration::from_secs(10);
+            let events = loop {
+                let events: Vec<WebhookEvent> = received
+                    .events
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|body| {
+                        rocket::serde::json::from_str(body).expect("expected a valid event")
+                    })
+                    .collect();
+                if matches!(events.last(), Some(WebhookEvent::Exit(_))) {
+                    break events;
+                }
+                assert!(Instant::now() < deadline, "expected an exit to be posted");
+                sleep(time::Duration::from_millis(10)).await;
+            };
+            match &events[..] {
+                [WebhookEvent::Output {
+                    id: output_id,
+                    stream: Stream::Stdout,
+                    offset: 0,
+                    data,
+                }, WebhookEvent::Exit(wait_resp)] => {
+                    assert_eq!(*output_id, id);
+                    assert_eq!(data, "hi\n");
+                    assert_eq!(wait_resp.id, id);
+                    assert!(wait_resp.success);
+                }
+                _ => panic!("expected output then an exit"),
+            }
+            assert_eq!(received.posts.load(Ordering::Relaxed), 3);
+            shutdown.notify();
         }
     }
 }
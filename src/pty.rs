@@ -0,0 +1,59 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::FromRawFd;
+use std::ptr;
+
+/// Both ends of a freshly allocated pseudo-terminal.
+pub struct Pty {
+    /// The end the server reads the child's output from.
+    pub master: File,
+    /// The end the child uses as its terminal.
+    pub slave: File,
+}
+
+impl Pty {
+    pub fn open() -> io::Result<Pty> {
+        let (mut master, mut slave) = (-1, -1);
+        // SAFETY: Null name, termios and winsize are all allowed, and
+        // leave the terminal with default settings.
+        let res = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: openpty succeeded, so both are open fds that nothing
+        // else owns.
+        Ok(unsafe {
+            Pty {
+                master: File::from_raw_fd(master),
+                slave: File::from_raw_fd(slave),
+            }
+        })
+    }
+}
+
+/// Makes the terminal on stdin the controlling terminal of the calling
+/// process, in a session of its own. Meant to be called in the child
+/// between fork and exec, once its stdio points at the pty's slave end.
+///
+/// This only makes async-signal-safe calls, as is required of anything
+/// run in the child before exec.
+pub fn make_controlling() -> io::Result<()> {
+    // SAFETY: Neither call touches any memory of ours.
+    unsafe {
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY as _, 0) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
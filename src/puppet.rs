@@ -1,132 +1,2419 @@
-use std::collections::HashMap;
-use std::fs::{create_dir_all, File};
-use std::path::PathBuf;
-use std::process::{self, Command};
-use std::process::{Child, ExitStatus};
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{CString, OsString};
+use std::fmt;
+use std::fs::{create_dir_all, remove_dir_all, remove_file, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use tempfile::{tempdir, TempDir};
+use dashmap::mapref::entry::Entry;
+use dashmap::mapref::one::Ref;
+use dashmap::{DashMap, DashSet};
+use rocket::futures::future::join_all;
+use rocket::request::FromParam;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::fs::File;
+use rocket::tokio::process::{Child, ChildStdin, Command};
+use rocket::tokio::sync::{mpsc, oneshot, watch, Mutex};
+use rocket::tokio::task::JoinHandle;
+use rocket::tokio::time::{sleep, sleep_until, timeout};
+use tempfile::TempDir;
+use uuid::Uuid;
 
+use crate::capture::{Capture, CaptureBuffer, Pump, StreamStats};
+use crate::config::{Config, IdScheme};
 use crate::error::Error;
-use crate::routes::CaptureOptions;
+use crate::events::{Event, EventLog};
+use crate::histogram::{self, Histogram};
+use crate::pty::{self, Pty};
+use crate::rate::TokenBucket;
+use crate::routes::{CaptureOptions, CreateReq, FdSpec, Ionice, RestartPolicy, Stream};
+use crate::template::Template;
+use crate::webhook::{start_webhook, Webhook};
+
+/// Identifies a puppet. Which kind of id is handed out depends on the
+/// configured `id_scheme`; both are accepted wherever an id is expected,
+/// though only one kind will ever match anything.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum PuppetId {
+    Seq(i32),
+    Uuid(Uuid),
+}
+
+impl PuppetId {
+    /// Ids handed out by the server are never negative, so one that is
+    /// can be refused outright rather than looked up and not found.
+    pub fn check(self) -> Result<PuppetId, Error> {
+        match self {
+            PuppetId::Seq(seq) if seq < 0 => Err(Error::InvalidId(self)),
+            _ => Ok(self),
+        }
+    }
+}
+
+impl fmt::Display for PuppetId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PuppetId::Seq(id) => write!(f, "{}", id),
+            PuppetId::Uuid(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+impl<'a> FromParam<'a> for PuppetId {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        param
+            .parse()
+            .map(PuppetId::Seq)
+            .or_else(|_| Uuid::parse_str(param).map(PuppetId::Uuid))
+            .map_err(|_| param)
+    }
+}
 
 pub struct Puppet {
-    pub id: i32,
-    proc: Child,
-    pub stdout: String,
-    pub stderr: String,
+    pub id: PuppetId,
+    /// The order the puppet was spawned in, whatever its id.
+    seq: i32,
+    pid: u32,
+    pub name: Option<String>,
+    /// The executable followed by its arguments, exactly as spawned.
+    pub command: Vec<String>,
+    /// The environment the child was spawned with, as best as can be
+    /// told without asking it: later changes it makes aren't seen.
+    env: BTreeMap<String, String>,
+    proc: Proc,
+    /// The write end of the child's stdin, if it was piped and hasn't
+    /// been closed since. This has its own lock, separate from the
+    /// manager's, since a write can block for as long as the child takes
+    /// to read.
+    stdin: Arc<Mutex<Option<ChildStdin>>>,
+    pub stdout: StreamMode,
+    pub stderr: StreamMode,
+    stdout_stats: Arc<StreamStats>,
+    stderr_stats: Arc<StreamStats>,
+    /// Where a stream captured `in_memory` is kept.
+    stdout_buffer: Option<Arc<CaptureBuffer>>,
+    stderr_buffer: Option<Arc<CaptureBuffer>>,
+    /// Whether the puppet should be left running when the server shuts
+    /// down.
+    pub detached: bool,
+    /// Whether the child was given a pty for its stdio.
+    pub tty: bool,
+    /// How many times the puppet has been restarted under its id.
+    pub restart_count: u32,
+    /// Set if the puppet was spawned with `record_stdin`.
+    replay: Option<Replay>,
+    /// The `RLIMIT_NOFILE` the child was started with.
+    pub open_files_limit: Rlimit,
+}
+
+/// A soft and hard resource limit, as with `setrlimit(2)`. Null stands for
+/// no limit at all.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Rlimit {
+    pub soft: Option<u64>,
+    pub hard: Option<u64>,
+}
+
+impl From<libc::rlimit> for Rlimit {
+    fn from(rlimit: libc::rlimit) -> Self {
+        let limit = |value| (value != libc::RLIM_INFINITY).then_some(value);
+        Rlimit {
+            soft: limit(rlimit.rlim_cur),
+            hard: limit(rlimit.rlim_max),
+        }
+    }
+}
+
+/// What's needed to run a puppet spawned with `record_stdin` again on the
+/// same input.
+#[derive(Clone)]
+pub struct Replay {
+    /// The request the puppet was spawned with, as JSON, for the same
+    /// reason as a `Supervisor`'s.
+    req: Arc<str>,
+    /// Everything written to the puppet's stdin, in the order it was
+    /// written. It's only added to under the lock on the puppet's stdin.
+    stdin: Arc<std::sync::Mutex<Vec<u8>>>,
+}
+
+impl Replay {
+    /// Notes that `bytes` were written to the puppet's stdin.
+    pub fn record(&self, bytes: &[u8]) {
+        self.stdin
+            .lock()
+            .expect("recorded stdin lock poisoned")
+            .extend_from_slice(bytes);
+    }
+}
+
+/// What became of one of a puppet's output streams.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum StreamMode {
+    /// The stream was passed through to the server's own stream.
+    Inherited,
+    /// The stream is being written to the file at `path`.
+    Captured { path: String },
+    /// The stream is being written to the named pipe at `path`, and is
+    /// only seen by whoever reads from it.
+    Fifo { path: String },
+    /// The stream is being kept in memory, and can only be read through
+    /// the server.
+    Memory,
+    /// The stream was thrown away.
+    Discarded,
+}
+
+impl StreamMode {
+    pub fn path(&self) -> Option<&str> {
+        match self {
+            StreamMode::Inherited
+            | StreamMode::Fifo { .. }
+            | StreamMode::Memory
+            | StreamMode::Discarded => None,
+            StreamMode::Captured { path } => Some(path),
+        }
+    }
+
+    /// Whether the output is kept for reading back, in a file or memory.
+    pub fn kept(&self) -> bool {
+        matches!(self, StreamMode::Captured { .. } | StreamMode::Memory)
+    }
 }
 
 impl Puppet {
-    pub fn wait(&mut self) -> std::io::Result<ExitStatus> {
-        self.proc.wait()
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn stdin(&self) -> Arc<Mutex<Option<ChildStdin>>> {
+        self.stdin.clone()
     }
 
-    pub fn kill(&mut self) -> std::io::Result<()> {
-        self.proc.kill()?;
-        self.proc.wait()?;
+    /// Returns what's needed to run the puppet again, if it was spawned
+    /// with `record_stdin`. Anything written to its stdin must be
+    /// recorded through this.
+    pub fn replay(&self) -> Option<Replay> {
+        self.replay.clone()
+    }
+
+    pub fn stream_stats(&self, stream: Stream) -> Arc<StreamStats> {
+        match stream {
+            Stream::Stdout => self.stdout_stats.clone(),
+            Stream::Stderr => self.stderr_stats.clone(),
+        }
+    }
+
+    /// Returns a handle on the puppet's process. Waiting or killing
+    /// through the handle doesn't need the manager, so callers should
+    /// clone it out and let go of the manager before awaiting anything.
+    pub fn proc(&self) -> Proc {
+        self.proc.clone()
+    }
+
+    /// Resets the captured output for the given stream to empty. The
+    /// child is left running and keeps writing to the same file; since
+    /// captures are opened in append mode, its next write lands at the
+    /// start of the now-empty file.
+    pub fn truncate(&self, stream: Stream) -> Result<(), Error> {
+        if let Capture::Memory(buffer) = self.capture(stream)? {
+            buffer.clear();
+            return Ok(());
+        }
+        OpenOptions::new()
+            .write(true)
+            .open(self.capture_path(stream)?)
+            .and_then(|file| file.set_len(0))
+            .map_err(|err| Error::capture_io(self.id, err))
+    }
+
+    /// Returns where the given stream's output is kept, or an error if
+    /// it isn't.
+    pub fn capture(&self, stream: Stream) -> Result<Capture, Error> {
+        let buffer = match stream {
+            Stream::Stdout => &self.stdout_buffer,
+            Stream::Stderr => &self.stderr_buffer,
+        };
+        match buffer {
+            Some(buffer) => Ok(Capture::Memory(Arc::clone(buffer))),
+            None => self
+                .capture_path(stream)
+                .map(|path| Capture::File(path.to_path_buf())),
+        }
+    }
+
+    /// Returns the file the given stream is being captured to, or an
+    /// error if the stream is inherited from the server or kept in
+    /// memory.
+    pub fn capture_path(&self, stream: Stream) -> Result<&Path, Error> {
+        let mode = match stream {
+            Stream::Stdout => &self.stdout,
+            Stream::Stderr => &self.stderr,
+        };
+        mode.path()
+            .map(Path::new)
+            .ok_or(Error::StreamNotCaptured(self.id, stream))
+    }
+}
+
+/// A cloneable handle on a puppet's process.
+///
+/// The child itself is owned by a reaper task spawned alongside it, which
+/// waits on it and publishes its exit status. Any number of handles can
+/// wait on that status concurrently, and it stays available after the
+/// child has been reaped. Kills are forwarded to the reaper so that a
+/// signal is never sent to a pid that has already been reaped (and
+/// possibly reused).
+///
+/// Since only the reaper ever touches the child, operations on one puppet
+/// need no lock of their own. A kill that lands during a wait ends that
+/// wait, and the kill and wait see the same exit status. Killing a puppet
+/// that has already exited does nothing and returns its original exit.
+/// Other signals are forwarded the same way.
+#[derive(Clone)]
+pub struct Proc {
+    id: PuppetId,
+    started_at: SystemTime,
+    status: watch::Receiver<Option<Exit>>,
+    control_tx: mpsc::UnboundedSender<Control>,
+    /// Whether the process was last sent SIGSTOP rather than SIGCONT.
+    suspended: Arc<AtomicBool>,
+    events: Option<EventLog>,
+}
+
+/// What the reaper can be asked to do with the child.
+enum Control {
+    /// Kills the child with the given signal, which is SIGKILL unless a
+    /// gentler one was asked for.
+    Kill(KillReason, i32),
+    /// Sends the child a signal, replying with whether that worked.
+    Signal(i32, oneshot::Sender<io::Result<()>>),
+}
+
+/// Why the reaper was asked to kill the child.
+#[derive(Serialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KillReason {
+    Requested,
+    TimedOut,
+    /// Ran past the server's `max_runtime_ms`.
+    MaxRuntime,
+}
+
+/// Whose time limit a puppet that timed out ran past.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeoutReason {
+    /// The timeout of a `/run`.
+    Request,
+    /// The server's `max_runtime_ms`.
+    Global,
+}
+
+/// Where a puppet is in its life, as far as can be told from outside.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PuppetState {
+    Running,
+    /// Exited by itself, with the given exit code. The code is -1 if the
+    /// exit couldn't be collected.
+    Exited {
+        code: i32,
+    },
+    /// Killed by a signal that didn't come from us.
+    Signaled {
+        signal: i32,
+    },
+    /// Killed on request, e.g. through `/kill/<id>`.
+    Killed,
+    /// Killed for running past the timeout of a `/run`, or the server's
+    /// `max_runtime_ms`, whichever came first.
+    TimedOut {
+        reason: TimeoutReason,
+    },
+}
+
+/// What the reaper publishes once the child has exited.
+struct Exit {
+    status: io::Result<ExitStatus>,
+    /// Why the child was killed, if it was asked to be before it exited.
+    killed: Option<KillReason>,
+    /// How long the child ran for, measured on the monotonic clock so
+    /// it can't be thrown off by the wall clock being adjusted.
+    runtime: Duration,
+    exited_at: Instant,
+}
+
+fn exit_state(exit: Option<&Exit>) -> PuppetState {
+    let exit = match exit {
+        Some(exit) => exit,
+        None => return PuppetState::Running,
+    };
+    match (exit.killed, &exit.status) {
+        (Some(KillReason::Requested), _) => PuppetState::Killed,
+        (Some(KillReason::TimedOut), _) => PuppetState::TimedOut {
+            reason: TimeoutReason::Request,
+        },
+        (Some(KillReason::MaxRuntime), _) => PuppetState::TimedOut {
+            reason: TimeoutReason::Global,
+        },
+        (None, Ok(status)) => match (status.code(), status.signal()) {
+            (Some(code), _) => PuppetState::Exited { code },
+            (None, Some(signal)) => PuppetState::Signaled { signal },
+            (None, None) => PuppetState::Exited { code: -1 },
+        },
+        (None, Err(_)) => PuppetState::Exited { code: -1 },
+    }
+}
+
+/// Something that can be waited on to exit. Only `Child` is, outside of
+/// tests.
+pub trait Waitable {
+    fn wait(&mut self) -> impl Future<Output = io::Result<ExitStatus>> + Send + '_;
+}
+
+impl Waitable for Child {
+    fn wait(&mut self) -> impl Future<Output = io::Result<ExitStatus>> + Send + '_ {
+        Child::wait(self)
+    }
+}
+
+/// Waits on `child`, starting over for as long as the wait fails with
+/// `ErrorKind::Interrupted`, which only means a signal arrived part way
+/// through. Reporting that as the child's exit would leave the puppet
+/// looking dead while it's still running.
+pub async fn wait_uninterrupted<W: Waitable>(child: &mut W) -> io::Result<ExitStatus> {
+    loop {
+        match child.wait().await {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Sends `signal` to `child`, which has to be one that hasn't been reaped.
+fn send_signal(child: &Child, signal: i32) -> io::Result<()> {
+    let pid = child
+        .id()
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::ESRCH))?;
+    // SAFETY: kill has no memory safety requirements.
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Signals that don't end a process that leaves them alone, and so would
+/// leave a kill waiting on an exit that never comes.
+const NON_FATAL_SIGNALS: [i32; 8] = [
+    libc::SIGCHLD,
+    libc::SIGCONT,
+    libc::SIGSTOP,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+    libc::SIGURG,
+    libc::SIGWINCH,
+];
+
+/// Looks up a signal to kill with by its name, with or without the `SIG`,
+/// in any case, or by its number. Signals that don't end a process by
+/// default aren't accepted.
+pub fn parse_signal(name: &str) -> Option<i32> {
+    if let Ok(signal) = name.parse::<i32>() {
+        return ((1..=31).contains(&signal) && !NON_FATAL_SIGNALS.contains(&signal))
+            .then_some(signal);
+    }
+    let upper = name.to_ascii_uppercase();
+    let signal = match upper.strip_prefix("SIG").unwrap_or(&upper) {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "ABRT" => libc::SIGABRT,
+        "KILL" => libc::SIGKILL,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "PIPE" => libc::SIGPIPE,
+        "ALRM" => libc::SIGALRM,
+        "TERM" => libc::SIGTERM,
+        _ => return None,
+    };
+    Some(signal)
+}
+
+/// How long the reaper gives pumps to drain whatever a child wrote
+/// before it exited. Anything that inherited the pipes (a grandchild
+/// left behind, say) can hold them open indefinitely, so this has to be
+/// bounded; the pumps themselves carry on regardless.
+const PUMP_GRACE: Duration = Duration::from_millis(500);
+
+impl Proc {
+    fn reap(
+        id: PuppetId,
+        (started_at, started): (SystemTime, Instant),
+        mut child: Child,
+        pumps: Vec<JoinHandle<()>>,
+        events: Option<EventLog>,
+        max_runtime: Option<Duration>,
+    ) -> Proc {
+        let (status_tx, status_rx) = watch::channel(None);
+        let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+        let reaper_events = events.clone();
+        rocket::tokio::spawn(async move {
+            let mut killed = None;
+            // Kept apart from `killed`, as a kill with a gentler signal
+            // mustn't stop `max_runtime` from being enforced.
+            let mut sigkilled = false;
+            let deadline = max_runtime.map(|max_runtime| started + max_runtime);
+            let status = loop {
+                rocket::tokio::select! {
+                    status = wait_uninterrupted(&mut child) => break status,
+                    // A SIGKILL that came first (from a `/run` timeout,
+                    // say) has already done its job, so this only ever
+                    // fires once.
+                    _ = sleep_until(deadline.unwrap_or(started).into()),
+                        if deadline.is_some() && !sigkilled =>
+                    {
+                        sigkilled = true;
+                        killed.get_or_insert(KillReason::MaxRuntime);
+                        if let Some(events) = &reaper_events {
+                            events.record(id, Event::Kill { reason: KillReason::MaxRuntime });
+                        }
+                        if let Err(err) = child.start_kill() {
+                            warn!("failed to kill puppet id={} err={}", id, err);
+                        }
+                    }
+                    Some(control) = control_rx.recv() => match control {
+                        Control::Kill(reason, signal) => {
+                            // The first reason given is the one that counts.
+                            killed.get_or_insert(reason);
+                            let result = match signal {
+                                libc::SIGKILL => {
+                                    sigkilled = true;
+                                    child.start_kill()
+                                }
+                                signal => send_signal(&child, signal),
+                            };
+                            if let Err(err) = result {
+                                warn!("failed to kill puppet id={} err={}", id, err);
+                            }
+                        }
+                        Control::Signal(signal, reply) => {
+                            let _ = reply.send(send_signal(&child, signal));
+                        }
+                    }
+                }
+            };
+            let exited_at = Instant::now();
+            let runtime = exited_at - started;
+            // Make sure everything written before the exit has hit the
+            // capture files by the time anyone hears about it.
+            let _ = timeout(PUMP_GRACE, join_all(pumps)).await;
+            // Nobody may be listening yet, which is fine: the status is
+            // kept around for whoever asks later.
+            let _ = status_tx.send(Some(Exit {
+                status,
+                killed,
+                runtime,
+                exited_at,
+            }));
+            if let Some(events) = reaper_events {
+                let state = exit_state(status_tx.borrow().as_ref());
+                events.record(id, Event::Exit { state });
+            }
+        });
+        Proc {
+            id,
+            started_at,
+            status: status_rx,
+            control_tx,
+            suspended: Arc::new(AtomicBool::new(false)),
+            events,
+        }
+    }
+
+    pub fn id(&self) -> PuppetId {
+        self.id
+    }
+
+    /// When the process was started, in milliseconds since the Unix
+    /// epoch.
+    pub fn started_at_ms(&self) -> u64 {
+        self.started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+
+    /// How long the process ran for, or `None` if it's still running.
+    pub fn runtime(&self) -> Option<Duration> {
+        self.status.borrow().as_ref().map(|exit| exit.runtime)
+    }
+
+    /// When the process exited, or `None` if it's still running.
+    pub fn exited_at(&self) -> Option<Instant> {
+        self.status.borrow().as_ref().map(|exit| exit.exited_at)
+    }
+
+    pub fn state(&self) -> PuppetState {
+        exit_state(self.status.borrow().as_ref())
+    }
+
+    /// Returns the exit status if the process has exited, without
+    /// blocking.
+    pub fn try_wait(&self) -> Option<Result<ExitStatus, Error>> {
+        self.status
+            .borrow()
+            .as_ref()
+            .map(|exit| match &exit.status {
+                Ok(status) => Ok(*status),
+                Err(err) => Err(Error::Io(io::Error::new(err.kind(), err.to_string()))),
+            })
+    }
+
+    /// Waits for the process to exit, giving up after `limit` if one is
+    /// given. Giving up leaves the process running, as does dropping the
+    /// returned future: all it holds is its own watch on the reaper, so
+    /// nothing else is kept waiting on an abandoned wait.
+    pub async fn wait(&mut self, limit: Option<Duration>) -> Result<ExitStatus, Error> {
+        let status = match limit {
+            Some(limit) => timeout(limit, self.exit_status())
+                .await
+                .map_err(|_| Error::WaitTimedOut(self.id, limit.as_millis()))??,
+            None => self.exit_status().await?,
+        };
+        info!("waited on puppet id={} status={}", self.id, status);
+        Ok(status)
+    }
+
+    fn send_kill(&self, reason: KillReason, signal: i32) {
+        // The reaper hangs up once the child has exited, in which case
+        // there's nothing left to kill.
+        if self.control_tx.send(Control::Kill(reason, signal)).is_ok() {
+            self.record(Event::Kill { reason });
+        }
+    }
+
+    fn record(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.record(self.id, event);
+        }
+    }
+
+    /// Sends the process a signal. Unlike a kill, this is an error if the
+    /// process has already exited, since then the signal can't have had
+    /// the effect asked for.
+    pub async fn signal(&self, signal: i32) -> Result<(), Error> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if self.try_wait().is_some()
+            || self
+                .control_tx
+                .send(Control::Signal(signal, reply_tx))
+                .is_err()
+        {
+            return Err(Error::NotRunning(self.id));
+        }
+        match reply_rx.await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) if err.raw_os_error() == Some(libc::ESRCH) => {
+                return Err(Error::NotRunning(self.id))
+            }
+            Ok(Err(err)) => return Err(Error::Io(err)),
+            // The reaper only drops a request unanswered once the child
+            // has exited.
+            Err(_) => return Err(Error::NotRunning(self.id)),
+        }
+        match signal {
+            libc::SIGSTOP => self.suspended.store(true, Ordering::Relaxed),
+            libc::SIGCONT => self.suspended.store(false, Ordering::Relaxed),
+            _ => {}
+        }
+        self.record(Event::Signal { signal });
+        info!("signaled puppet id={} signal={}", self.id, signal);
         Ok(())
     }
 
-    pub fn pid(&self) -> u32 {
-        self.proc.id()
+    /// Whether the process is stopped by a SIGSTOP sent through `signal`.
+    pub fn suspended(&self) -> bool {
+        self.try_wait().is_none() && self.suspended.load(Ordering::Relaxed)
+    }
+
+    /// Asks the reaper to kill the process, then waits for the exit the
+    /// reaper records, just as `wait` does. Only the reaper ever reaps
+    /// the child, and the exit it records is kept, so every wait or kill
+    /// after this one, or running alongside it, sees the same status. A
+    /// process that has already exited isn't signaled at all.
+    pub async fn kill(&mut self) -> Result<ExitStatus, Error> {
+        self.kill_with(libc::SIGKILL).await
+    }
+
+    /// Like `kill`, but with `signal` in place of SIGKILL. The process
+    /// counts as killed either way, even if it catches the signal and
+    /// exits cleanly, and is waited on however long it takes to exit;
+    /// one that ignores the signal can be killed again with another.
+    pub async fn kill_with(&mut self, signal: i32) -> Result<ExitStatus, Error> {
+        self.send_kill(KillReason::Requested, signal);
+        // A stopped process holds any signal but SIGKILL pending until
+        // it's continued. Having exited in the meantime is no matter.
+        if signal != libc::SIGKILL && self.suspended() {
+            let _ = self.signal(libc::SIGCONT).await;
+        }
+        let status = self.exit_status().await?;
+        info!("killed puppet id={} status={}", self.id, status);
+        Ok(status)
+    }
+
+    /// Kills the process for running past its time limit.
+    pub async fn time_out(&mut self) -> Result<ExitStatus, Error> {
+        self.send_kill(KillReason::TimedOut, libc::SIGKILL);
+        let status = self.exit_status().await?;
+        info!("timed out puppet id={} status={}", self.id, status);
+        Ok(status)
+    }
+
+    async fn exit_status(&mut self) -> Result<ExitStatus, Error> {
+        loop {
+            if let Some(status) = self.try_wait() {
+                return status;
+            }
+            if self.status.changed().await.is_err() {
+                return Err(Error::Io(io::Error::other(format!(
+                    "reaper for puppet with id '{}' went away",
+                    self.id
+                ))));
+            }
+        }
     }
 }
 
 struct Stdio {
     stdio: process::Stdio,
-    label: String,
+    mode: StreamMode,
+    /// Set if the child writes to a pipe that has to be pumped into the
+    /// capture file, rather than to the file itself.
+    pump: Option<Pump>,
+    /// Set if the child writes to a pipe that is kept in memory instead.
+    buffer: Option<Arc<CaptureBuffer>>,
 }
 
 impl Stdio {
-    pub const INHERITED: &str = "inherited";
-
     fn inherit() -> Stdio {
         Stdio {
             stdio: process::Stdio::inherit(),
-            label: String::from(Stdio::INHERITED),
+            mode: StreamMode::Inherited,
+            pump: None,
+            buffer: None,
+        }
+    }
+
+    /// Captures to the file at `filepath`, through a pump unless the
+    /// child is to be `detached`. See `Pump` for why.
+    fn capture(
+        filepath: &Path,
+        capture_opts: CaptureOptions,
+        detached: bool,
+    ) -> Result<Stdio, Error> {
+        let mut stdio = Stdio::capture_file(filepath, capture_opts.append)?;
+        if !detached {
+            stdio.stdio = process::Stdio::piped();
+            stdio.pump = Some(Pump {
+                path: filepath.to_path_buf(),
+                max_bytes: capture_opts.max_capture_bytes,
+                max_lines: capture_opts.max_lines,
+                rotation: capture_opts.rotation(),
+            });
+        }
+        Ok(stdio)
+    }
+
+    fn discard() -> Stdio {
+        Stdio {
+            stdio: process::Stdio::null(),
+            mode: StreamMode::Discarded,
+            pump: None,
+            buffer: None,
+        }
+    }
+
+    fn memory(max_bytes: Option<u64>) -> Stdio {
+        Stdio {
+            stdio: process::Stdio::piped(),
+            mode: StreamMode::Memory,
+            pump: None,
+            buffer: Some(Arc::new(CaptureBuffer::new(max_bytes))),
+        }
+    }
+
+    /// Points the stream at a fresh named pipe. The child gets it open for
+    /// reading and writing both, so that it can start before anyone has
+    /// opened the other end; it only blocks once the pipe fills up.
+    fn fifo(filepath: &Path) -> Result<Stdio, Error> {
+        remove_stale(filepath)?;
+        let c_path = CString::new(filepath.as_os_str().as_bytes())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        if unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) } != 0 {
+            return Err(Error::Io(io::Error::last_os_error()));
         }
+        let fifo = OpenOptions::new().read(true).write(true).open(filepath)?;
+        Ok(Stdio {
+            stdio: process::Stdio::from(fifo),
+            mode: StreamMode::Fifo {
+                path: filepath
+                    .to_str()
+                    .expect("failed to convert Path -> &str")
+                    .to_string(),
+            },
+            pump: None,
+            buffer: None,
+        })
+    }
+
+    fn capture_file(filepath: &Path, append: bool) -> Result<Stdio, Error> {
+        // A fifo left behind by an earlier puppet with the same id would
+        // block the open below until someone reads from it.
+        if is_fifo(filepath) {
+            remove_stale(filepath)?;
+        }
+        // Open in append mode (O_APPEND) rather than with a plain write
+        // offset. A child writing through a regular fd would keep its own
+        // offset after we truncate the file, leaving a hole of NUL bytes
+        // in front of anything it writes afterwards.
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(filepath)?;
+        if !append {
+            file.set_len(0)?;
+        }
+        Ok(Stdio {
+            stdio: process::Stdio::from(file),
+            mode: StreamMode::Captured {
+                path: filepath
+                    .to_str()
+                    .expect("failed to convert Path -> &str")
+                    .to_string(),
+            },
+            pump: None,
+            buffer: None,
+        })
     }
 }
 
-impl Into<process::Stdio> for Stdio {
-    fn into(self) -> process::Stdio {
-        return self.stdio;
+/// A point-in-time summary of the puppets the manager has seen.
+pub struct Stats {
+    pub spawned: u64,
+    pub running: u64,
+    /// Puppets that have exited but are still being kept track of.
+    pub exited: u64,
+    pub spawn_failures: u64,
+    /// How long each attempt to spawn a puppet's process took.
+    pub spawn_durations: histogram::Snapshot,
+}
+
+/// The directory captured output is written under.
+enum OutDir {
+    Temp(TempDir),
+    Fixed(PathBuf),
+}
+
+impl OutDir {
+    fn path(&self) -> &Path {
+        match self {
+            OutDir::Temp(dir) => dir.path(),
+            OutDir::Fixed(path) => path,
+        }
+    }
+}
+
+/// Makes the temporary directory captured output goes under when no
+/// `out_dir` is set.
+fn make_temp_dir(config: &Config) -> Result<TempDir, Error> {
+    let mut builder = tempfile::Builder::new();
+    if let Some(prefix) = &config.temp_dir_prefix {
+        if prefix.contains('/') {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("temp_dir_prefix '{}' must not include '/'", prefix),
+            )));
+        }
+        builder.prefix(prefix);
+    }
+    let dir = match &config.temp_dir_parent {
+        Some(parent) => {
+            create_dir_all(parent)?;
+            builder.tempdir_in(parent)?
+        }
+        None => builder.tempdir()?,
+    };
+    Ok(dir)
+}
+
+/// Keeps an id from being given to any other spawn until dropped.
+struct Reservation<'a> {
+    reserved: &'a DashSet<PuppetId>,
+    id: PuppetId,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.reserved.remove(&self.id);
     }
 }
 
+/// Keeps track of every puppet spawned. Everything here can be used
+/// concurrently, so spawning one puppet doesn't hold up spawning (or
+/// waiting on, or reading the output of) another.
 pub struct PuppetManager {
-    cur_id: i32,
-    pups: HashMap<i32, Puppet>,
-    out_dir: TempDir,
+    /// Also held, weakly, by the tasks restarting puppets, since those
+    /// have to be able to spawn them again.
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    /// Orders puppets by when they were spawned.
+    cur_seq: AtomicI32,
+    /// The next id to hand out under `IdScheme::Sequential`. Kept apart
+    /// from `cur_seq` so requested ids don't leave gaps in it.
+    next_seq_id: AtomicI32,
+    /// Shared with the sweeper, if there is one.
+    pups: Arc<DashMap<PuppetId, Puppet>>,
+    /// Ids given to spawns that haven't made it into `pups` yet, so that
+    /// no two spawns are ever given the same one.
+    reserved: DashSet<PuppetId>,
+    out_dir: OutDir,
+    spawned: AtomicU64,
+    spawn_failures: AtomicU64,
+    spawn_durations: Histogram,
+    /// Held from checking `max_puppets` until the new puppet is counted
+    /// as running, since concurrent spawns could otherwise all see room
+    /// for one more. Only taken when there is a limit to enforce.
+    admission: std::sync::Mutex<()>,
+    /// Set if spawns are rate limited.
+    spawn_rate: Option<TokenBucket>,
+    templates: DashMap<String, Template>,
+    /// Set if lifecycle events are to be logged.
+    events: Option<EventLog>,
+    /// Set on shutdown, so that puppets killed then aren't restarted, and
+    /// no new ones are spawned only to be killed.
+    shutting_down: AtomicBool,
+    /// What `/kill` sends unless told otherwise.
+    kill_signal: i32,
+    started: Instant,
+    config: Config,
 }
 
 impl PuppetManager {
-    pub fn new() -> Result<Self, Error> {
+    pub fn new(config: Config) -> Result<Self, Error> {
+        if let Some(template) = &config.capture_file_name {
+            if !template.contains("{stream}") || template.contains('/') {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "capture_file_name '{}' must include {{stream}} and no '/'",
+                        template
+                    ),
+                )));
+            }
+        }
+        let out_dir = match &config.out_dir {
+            Some(path) => {
+                create_dir_all(path)?;
+                OutDir::Fixed(path.clone())
+            }
+            None => OutDir::Temp(make_temp_dir(&config)?),
+        };
+        if let Some(umask) = config.umask {
+            if umask > 0o777 {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("umask {:#o} must be at most 0o777", umask),
+                )));
+            }
+        }
+        let kill_signal = match &config.kill_signal {
+            Some(name) => parse_signal(name).ok_or_else(|| {
+                Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "kill_signal '{}' is not a known signal that ends a process",
+                        name
+                    ),
+                ))
+            })?,
+            None => libc::SIGKILL,
+        };
+        let events = config
+            .event_log
+            .as_deref()
+            .map(EventLog::open)
+            .transpose()?;
+        let shared = Shared {
+            cur_seq: AtomicI32::new(0),
+            next_seq_id: AtomicI32::new(0),
+            pups: Arc::new(DashMap::new()),
+            reserved: DashSet::new(),
+            out_dir,
+            spawned: AtomicU64::new(0),
+            spawn_failures: AtomicU64::new(0),
+            spawn_durations: Histogram::default(),
+            admission: std::sync::Mutex::new(()),
+            spawn_rate: config
+                .spawn_rate_per_sec
+                .filter(|&rate| rate > 0)
+                .map(TokenBucket::new),
+            templates: DashMap::new(),
+            events,
+            shutting_down: AtomicBool::new(false),
+            kill_signal,
+            started: Instant::now(),
+            config,
+        };
         Ok(PuppetManager {
-            cur_id: 0,
-            pups: HashMap::new(),
-            out_dir: tempdir()?,
+            shared: Arc::new(shared),
         })
     }
 
-    pub fn push(
-        &mut self,
-        exec: &str,
-        args: &Vec<&str>,
+    /// Spawns the requested puppet. The returned reference keeps part of
+    /// the manager locked, so it mustn't be held across an await.
+    pub fn push(&self, req: &CreateReq) -> Result<Ref<'_, PuppetId, Puppet>, Error> {
+        self.push_restarted(req, 0)
+    }
+
+    /// Spawns the requested puppet as its `restarts`th restart, and sees
+    /// to restarting it again if its `restart_policy` calls for it.
+    fn push_restarted(
+        &self,
+        req: &CreateReq,
+        restarts: u32,
+    ) -> Result<Ref<'_, PuppetId, Puppet>, Error> {
+        // Like a restart, a replay starts over from the request as given.
+        let replay = req
+            .record_stdin
+            .then(|| serde_json::to_string(req))
+            .transpose()
+            .map_err(|err| Error::Io(err.into()))?;
+        let pup = with_normalized(req, |req| self.push_normalized(req, restarts, replay))?;
+        if req.restart_policy != RestartPolicy::Never {
+            self.supervise(&pup, req, restarts);
+        }
+        // The URL was checked before spawning, so this can't fail.
+        if let Some(Ok(webhook)) = req.webhook_url.map(Webhook::new) {
+            start_webhook(webhook, &pup, self.max_inline_output_bytes());
+        }
+        Ok(pup)
+    }
+
+    fn push_normalized(
+        &self,
+        req: &CreateReq,
+        restarts: u32,
+        replay: Option<String>,
+    ) -> Result<Ref<'_, PuppetId, Puppet>, Error> {
+        let _admission = self.shared.config.max_puppets.map(|_| {
+            self.shared
+                .admission
+                .lock()
+                .expect("admission lock poisoned")
+        });
+        let (exec, args) = (req.exec, &req.args);
+        let capture_opts = self.capture_opts(req);
+        // A request that's refused outright doesn't use up an id, but one
+        // that fails to spawn still uses up its place in the sequence, as
+        // there's no telling whether a later one has taken the next. The
+        // id is reserved before anything is spawned under it, so nothing
+        // is run, or captured, for a request that loses a race for it.
+        let spawned = self.admit(req).and_then(|()| {
+            let reservation = match req.requested_id {
+                Some(id) => self.reserve(id).ok_or(Error::IdInUse(id))?,
+                None => self.next_id(),
+            };
+            let seq = self.shared.cur_seq.fetch_add(1, Ordering::Relaxed);
+            let pup = self.spawn(reservation.id, seq, req, capture_opts)?;
+            Ok((pup, reservation))
+        });
+        let (mut pup, _reservation) = match spawned {
+            Ok(spawned) => spawned,
+            Err(err) => {
+                self.shared.spawn_failures.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "failed to spawn puppet exec={:?} argc={} args={} capture={:?} err={}",
+                    exec,
+                    args.len(),
+                    describe_args(args, self.shared.config.redact_args),
+                    capture_opts,
+                    err
+                );
+                return Err(err);
+            }
+        };
+        let next_id = pup.id;
+        info!(
+            "spawned puppet id={} pid={} exec={:?} argc={} args={} capture={:?}",
+            pup.id,
+            pup.pid(),
+            exec,
+            args.len(),
+            describe_args(args, self.shared.config.redact_args),
+            capture_opts
+        );
+        pup.restart_count = restarts;
+        pup.replay = replay.map(|req| Replay {
+            req: req.into(),
+            stdin: Arc::default(),
+        });
+        self.shared.spawned.fetch_add(1, Ordering::Relaxed);
+        // The reservation is only given up once the puppet is in place.
+        match self.shared.pups.entry(next_id) {
+            Entry::Occupied(mut entry) => {
+                entry.insert(pup);
+                Ok(entry.into_ref().downgrade())
+            }
+            Entry::Vacant(entry) => Ok(entry.insert(pup).downgrade()),
+        }
+    }
+
+    /// Starts a `Supervisor` watching `pup`. `req` is kept as it was
+    /// given rather than normalized, so that a restart goes through the
+    /// same steps, but with the id pinned and capturing appending to what
+    /// was captured before.
+    fn supervise(&self, pup: &Puppet, req: &CreateReq, restarts: u32) {
+        let capture_opts = self.capture_opts(req);
+        let respawn = CreateReq {
+            requested_id: Some(pup.id),
+            capture: Some(CaptureOptions {
+                append: !capture_opts.fifo,
+                ..capture_opts
+            }),
+            ..req.clone()
+        };
+        let respawn = match serde_json::to_string(&respawn) {
+            Ok(respawn) => respawn,
+            Err(err) => {
+                warn!("cannot restart puppet id={} err={}", pup.id, err);
+                return;
+            }
+        };
+        let supervisor = Supervisor {
+            shared: Arc::downgrade(&self.shared),
+            proc: pup.proc(),
+            seq: pup.seq,
+            req: respawn,
+            policy: req.restart_policy,
+            max_restarts: req.max_restarts,
+            backoff: Duration::from_millis(req.backoff_ms.unwrap_or(DEFAULT_BACKOFF_MS)),
+            restarts,
+        };
+        rocket::tokio::spawn(supervisor.run());
+    }
+
+    /// Spawns a new puppet from the request the exited puppet `id` was
+    /// spawned with, under an id of its own. Returns it along with what
+    /// was written to the old puppet's stdin, which is left for the
+    /// caller to write to the new one's.
+    pub fn replay(&self, id: PuppetId) -> Result<(Ref<'_, PuppetId, Puppet>, Vec<u8>), Error> {
+        let replay = {
+            let pup = self.get(id)?;
+            if pup.proc.try_wait().is_none() {
+                return Err(Error::StillRunning(id));
+            }
+            pup.replay.clone().ok_or_else(|| {
+                Error::InvalidRequest(format!(
+                    "puppet with id '{}' was not spawned with record_stdin",
+                    id
+                ))
+            })?
+        };
+        let input = replay
+            .stdin
+            .lock()
+            .expect("recorded stdin lock poisoned")
+            .clone();
+        let req =
+            serde_json::from_str::<CreateReq>(&replay.req).map_err(|err| Error::Io(err.into()))?;
+        let pup = self.push(&CreateReq {
+            requested_id: None,
+            ..req
+        })?;
+        Ok((pup, input))
+    }
+
+    /// Keeps puppets from being spawned or restarted from now on. Those
+    /// already running are left to whoever is shutting down.
+    pub fn shut_down(&self) {
+        self.shared.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    /// Picks and reserves an id for a puppet that didn't request one,
+    /// skipping over any that were requested by others.
+    fn next_id(&self) -> Reservation<'_> {
+        loop {
+            let id = match self.shared.config.id_scheme {
+                IdScheme::Sequential => {
+                    PuppetId::Seq(self.shared.next_seq_id.fetch_add(1, Ordering::Relaxed))
+                }
+                IdScheme::Uuid => PuppetId::Uuid(Uuid::new_v4()),
+            };
+            if let Some(reservation) = self.reserve(id) {
+                if !self.shared.pups.contains_key(&id) {
+                    return reservation;
+                }
+            }
+        }
+    }
+
+    /// Reserves `id` for a spawn, unless another spawn has it reserved or
+    /// a running puppet has it. Reserving comes before looking in `pups`,
+    /// as a spawn only gives up its reservation once its puppet is there.
+    fn reserve(&self, id: PuppetId) -> Option<Reservation<'_>> {
+        if !self.shared.reserved.insert(id) {
+            return None;
+        }
+        let reservation = Reservation {
+            reserved: &self.shared.reserved,
+            id,
+        };
+        let running = self
+            .shared
+            .pups
+            .get(&id)
+            .is_some_and(|pup| pup.proc.try_wait().is_none());
+        (!running).then_some(reservation)
+    }
+
+    /// Forgets an exited puppet and removes its capture files.
+    pub fn remove(&self, id: PuppetId) -> Result<(), Error> {
+        id.check()?;
+        if self
+            .shared
+            .pups
+            .remove_if(&id, |_, pup| pup.proc.try_wait().is_some())
+            .is_none()
+        {
+            return Err(match self.shared.pups.contains_key(&id) {
+                true => Error::StillRunning(id),
+                false => Error::PuppetNotFound(id),
+            });
+        }
+        remove_out_dir(self.shared.out_dir.path(), id)
+    }
+
+    /// Returns a task that forgets puppets, and removes their capture
+    /// files, once they've been exited for longer than the configured
+    /// TTL. There's nothing to do if no TTL is set.
+    pub fn sweeper(&self) -> Option<Sweeper> {
+        let ttl = self.shared.config.exited_ttl()?;
+        Some(Sweeper {
+            pups: Arc::clone(&self.shared.pups),
+            out_dir: self.shared.out_dir.path().to_path_buf(),
+            ttl,
+        })
+    }
+
+    /// Looks up a puppet. Like with `push`, the returned reference
+    /// mustn't be held across an await.
+    pub fn get(&self, id: PuppetId) -> Result<Ref<'_, PuppetId, Puppet>, Error> {
+        self.shared
+            .pups
+            .get(&id.check()?)
+            .ok_or(Error::PuppetNotFound(id))
+    }
+
+    /// Gives a puppet a new name, or takes its name away. Files it was
+    /// already capturing to keep the name they were created with.
+    pub fn rename(&self, id: PuppetId, name: Option<String>) -> Result<(), Error> {
+        let mut pup = self
+            .shared
+            .pups
+            .get_mut(&id.check()?)
+            .ok_or(Error::PuppetNotFound(id))?;
+        pup.name = name;
+        Ok(())
+    }
+
+    /// The environment a puppet was spawned with, with the values of
+    /// variables that look like they hold secrets hidden.
+    pub fn env(&self, id: PuppetId) -> Result<BTreeMap<String, String>, Error> {
+        let pup = self.get(id)?;
+        Ok(pup
+            .env
+            .iter()
+            .map(|(name, val)| match self.shared.config.env_is_secret(name) {
+                true => (name.clone(), String::from(REDACTED)),
+                false => (name.clone(), val.clone()),
+            })
+            .collect())
+    }
+
+    /// Registers a template under the given name, replacing any earlier
+    /// one of the same name.
+    pub fn add_template(&self, name: String, template: Template) {
+        info!(
+            "registered template name={:?} exec={:?}",
+            name, template.exec
+        );
+        self.shared.templates.insert(name, template);
+    }
+
+    pub fn template(&self, name: &str) -> Option<Template> {
+        self.shared
+            .templates
+            .get(name)
+            .map(|template| template.clone())
+    }
+
+    /// Runs every check a spawn would, without spawning anything, and
+    /// returns the command that would be run.
+    pub fn validate(&self, req: &CreateReq) -> Result<Vec<String>, Error> {
+        with_normalized(req, |req| {
+            self.check(req)?;
+            Ok(req.command())
+        })
+    }
+
+    /// Decides whether an already normalized request may be spawned at
+    /// all, before it's given an id.
+    fn admit(&self, req: &CreateReq) -> Result<(), Error> {
+        if self.shared.shutting_down.load(Ordering::Relaxed) {
+            return Err(Error::ShuttingDown);
+        }
+        self.check(req)?;
+        // Only taken once the request is known to be good, so bad ones
+        // don't use up anyone's allowance.
+        if let Some(spawn_rate) = &self.shared.spawn_rate {
+            spawn_rate.take().map_err(Error::RateLimited)?;
+        }
+        Ok(())
+    }
+
+    /// Checks an already normalized request.
+    fn check(&self, req: &CreateReq) -> Result<(), Error> {
+        self.validate_size(req)?;
+        if let Some(url) = req.webhook_url {
+            Webhook::new(url)?;
+        }
+        if !self.shared.config.exec_allowed(req.exec) {
+            return Err(Error::ExecNotAllowed(String::from(req.exec)));
+        }
+        if self.shared.config.require_absolute_exec && !Path::new(req.exec).is_absolute() {
+            return Err(Error::InvalidRequest(format!(
+                "exec '{}' must be an absolute path",
+                req.exec
+            )));
+        }
+        if let Some(cwd) = req.cwd {
+            if !Path::new(cwd).is_dir() {
+                return Err(Error::InvalidRequest(format!(
+                    "cwd '{}' is not a directory",
+                    cwd
+                )));
+            }
+        }
+        if req.path.is_some()
+            && !self.shared.config.allowed_execs.is_empty()
+            && !req.exec.contains('/')
+        {
+            // Otherwise an allowed name could be made to run anything.
+            return Err(Error::InvalidRequest(String::from(
+                "path cannot be used to look up an exec while allowed_execs is set",
+            )));
+        }
+        validate_exec(req.exec, req.path)?;
+        if let Some(nice) = req.nice {
+            validate_nice(nice)?;
+        }
+        if let Some(ionice) = req.ionice {
+            validate_ionice(ionice)?;
+        }
+        if let Some(max) = req.max_open_files {
+            let hard = open_files_limit().rlim_max;
+            if max > hard {
+                return Err(Error::InvalidRequest(format!(
+                    "max_open_files cannot be more than the server's hard limit of {}",
+                    hard
+                )));
+            }
+        }
+        let capture_opts = self.capture_opts(req);
+        if req.pty && !capture_opts.stdout {
+            return Err(Error::InvalidRequest(String::from(
+                "pty requires capturing stdout",
+            )));
+        }
+        validate_rotation(capture_opts)?;
+        let pumped = capture_opts.pumped();
+        if capture_opts.fifo && (req.pty || capture_opts.append || pumped) {
+            return Err(Error::InvalidRequest(String::from(
+                "fifo cannot be used with pty, append, max_capture_bytes, max_lines or rotate_bytes",
+            )));
+        }
+        let trimmed = capture_opts.max_lines.is_some() || capture_opts.rotate_bytes.is_some();
+        if capture_opts.in_memory
+            && (req.pty || req.detach || capture_opts.fifo || capture_opts.append || trimmed)
+        {
+            return Err(Error::InvalidRequest(String::from(
+                "in_memory cannot be used with pty, detach, fifo, append, max_lines or rotate_bytes",
+            )));
+        }
+        if capture_opts.combine.is_some() {
+            if !capture_opts.stdout || !capture_opts.stderr {
+                return Err(Error::InvalidRequest(String::from(
+                    "combine requires capturing both stdout and stderr",
+                )));
+            }
+            if req.pty || req.detach || capture_opts.fifo || capture_opts.in_memory {
+                return Err(Error::InvalidRequest(String::from(
+                    "combine cannot be used with pty, detach, fifo or in_memory",
+                )));
+            }
+        }
+        if req.detach && (req.pty || pumped) {
+            return Err(Error::InvalidRequest(String::from(
+                "detach cannot be used with pty, max_capture_bytes, max_lines or rotate_bytes",
+            )));
+        }
+        if req.pty && req.stdin {
+            return Err(Error::InvalidRequest(String::from(
+                "pty and stdin cannot be used together",
+            )));
+        }
+        if req.record_stdin && !req.stdin {
+            return Err(Error::InvalidRequest(String::from(
+                "record_stdin requires stdin",
+            )));
+        }
+        if req.restart_policy == RestartPolicy::Never
+            && (req.max_restarts.is_some() || req.backoff_ms.is_some())
+        {
+            return Err(Error::InvalidRequest(String::from(
+                "max_restarts and backoff_ms require a restart_policy",
+            )));
+        }
+        if req.expand_env_strict && !req.expand_env {
+            return Err(Error::InvalidRequest(String::from(
+                "expand_env_strict requires expand_env",
+            )));
+        }
+        if req.inherit_env.is_some() && !req.clear_env {
+            return Err(Error::InvalidRequest(String::from(
+                "inherit_env requires clear_env",
+            )));
+        }
+        if let Some(id) = req.requested_id {
+            id.check()?;
+            if self
+                .shared
+                .pups
+                .get(&id)
+                .is_some_and(|pup| pup.proc.try_wait().is_none())
+            {
+                return Err(Error::IdInUse(id));
+            }
+        }
+        if let Some(max_puppets) = self.shared.config.max_puppets {
+            if self.stats().running as usize >= max_puppets {
+                return Err(Error::TooManyPuppets(max_puppets));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_size(&self, req: &CreateReq) -> Result<(), Error> {
+        let max_args = self.shared.config.max_args();
+        if req.args.len() > max_args {
+            return Err(Error::RequestTooLarge(format!(
+                "{} args given, but at most {} are allowed",
+                req.args.len(),
+                max_args
+            )));
+        }
+        let max_bytes = self.shared.config.max_command_bytes();
+        let bytes = req.exec.len() + req.args.iter().map(|arg| arg.len()).sum::<usize>();
+        if bytes > max_bytes {
+            return Err(Error::RequestTooLarge(format!(
+                "command is {} bytes, but at most {} are allowed",
+                bytes, max_bytes
+            )));
+        }
+        Ok(())
+    }
+
+    /// Applies `f` to every puppet, in the order they were spawned.
+    pub fn list<T>(&self, f: impl Fn(&Puppet) -> T) -> Vec<T> {
+        let mut pups: Vec<(i32, T)> = self
+            .shared
+            .pups
+            .iter()
+            .map(|pup| (pup.seq, f(pup.value())))
+            .collect();
+        pups.sort_by_key(|(seq, _)| *seq);
+        pups.into_iter().map(|(_, t)| t).collect()
+    }
+
+    /// Applies `f` to each of the puppets with the given ids, in one pass.
+    /// An id that can't be looked up gets the error saying why instead.
+    pub fn get_each<T>(
+        &self,
+        ids: &[PuppetId],
+        f: impl Fn(&Puppet) -> T,
+    ) -> Vec<(PuppetId, Result<T, Error>)> {
+        ids.iter()
+            .map(|&id| (id, self.get(id).map(|pup| f(&pup))))
+            .collect()
+    }
+
+    /// Returns handles on every puppet that hasn't exited yet and wasn't
+    /// detached, which is to say those that go down with the server.
+    pub fn attached(&self) -> Vec<Proc> {
+        self.shared
+            .pups
+            .iter()
+            .filter(|pup| !pup.detached && pup.proc.try_wait().is_none())
+            .map(|pup| pup.proc())
+            .collect()
+    }
+
+    /// The capture options a request asked for, or the configured default
+    /// if it didn't ask.
+    fn capture_opts(&self, req: &CreateReq) -> CaptureOptions {
+        req.capture
+            .unwrap_or_else(|| self.shared.config.default_capture())
+    }
+
+    pub fn default_timeout(&self) -> Option<Duration> {
+        self.shared.config.default_timeout()
+    }
+
+    pub fn kill_signal(&self) -> i32 {
+        self.shared.kill_signal
+    }
+
+    pub fn max_inline_output_bytes(&self) -> u64 {
+        self.shared.config.max_inline_output_bytes()
+    }
+
+    pub fn id_scheme(&self) -> IdScheme {
+        self.shared.config.id_scheme
+    }
+
+    /// How long it's been since the manager, and so the server, started.
+    pub fn uptime(&self) -> Duration {
+        self.shared.started.elapsed()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let (mut running, mut exited) = (0, 0);
+        for pup in self.shared.pups.iter() {
+            match pup.proc.try_wait() {
+                Some(_) => exited += 1,
+                None => running += 1,
+            }
+        }
+        Stats {
+            spawned: self.shared.spawned.load(Ordering::Relaxed),
+            running,
+            exited,
+            spawn_failures: self.shared.spawn_failures.load(Ordering::Relaxed),
+            spawn_durations: self.shared.spawn_durations.snapshot(),
+        }
+    }
+
+    fn spawn(
+        &self,
+        id: PuppetId,
+        seq: i32,
+        req: &CreateReq,
         capture_opts: CaptureOptions,
-    ) -> Result<&Puppet, Error> {
-        let next_id = self.cur_id;
-        let (stdout, stderr) = self.make_stdio(next_id, capture_opts)?;
-        // TODO: Exercise - Can we avoid the copy here?
-        let (stdout_label, stderr_label) = (stdout.label.clone(), stderr.label.clone());
-        let proc = Command::new(exec)
-            .args(args)
-            .stdout(stdout)
-            .stderr(stderr)
-            .spawn()?;
-        let pup = Puppet {
-            id: next_id,
-            proc,
-            stdout: stdout_label,
-            stderr: stderr_label,
-        };
-        self.pups.insert(next_id, pup);
-        self.cur_id += 1;
-        return Ok(self.pups.get(&next_id).unwrap());
-    }
-
-    pub fn get(&mut self, id: i32) -> Option<&mut Puppet> {
-        self.pups.get_mut(&id)
-    }
-
-    fn make_stdio(&self, id: i32, capture_opts: CaptureOptions) -> Result<(Stdio, Stdio), Error> {
-        let dirpath = self.out_dir.path();
-        let id_dir = dirpath.join(id.to_string());
-        create_dir_all(&id_dir)?;
-        let stdout_file = if capture_opts.stdout {
-            let stdout_filepath = id_dir.join("stdout");
-            Stdio {
-                stdio: process::Stdio::from(File::create(&stdout_filepath)?),
-                label: PathBuf::from(&stdout_filepath) // TODO: Exercise - Maybe can avoid the copy?
-                    .to_str()
-                    .expect("failed to convert Path -> &str")
-                    .to_string(),
+    ) -> Result<Puppet, Error> {
+        let (exec, args) = (req.exec, &req.args);
+        let terminal = if req.pty { Some(Pty::open()?) } else { None };
+        let (stdout, stderr) = match &terminal {
+            Some(terminal) => self.make_pty_stdio(id, req.name, capture_opts, terminal)?,
+            None => self.make_stdio(id, req.name, capture_opts, req.detach)?,
+        };
+        let stdin = match &terminal {
+            Some(terminal) => process::Stdio::from(terminal.slave.try_clone()?),
+            None if req.stdin => process::Stdio::piped(),
+            None => process::Stdio::inherit(),
+        };
+        let mut cmd = Command::new(exec);
+        if let Some(arg0) = req.arg0 {
+            cmd.arg0(arg0);
+        }
+        if let Some(cwd) = req.cwd {
+            cmd.current_dir(cwd);
+        }
+        cmd.args(args)
+            .stdin(stdin)
+            .stdout(stdout.stdio)
+            .stderr(stderr.stdio);
+        if req.clear_env {
+            cmd.env_clear();
+            for name in req.inherit_env.iter().flatten() {
+                if let Some(val) = env::var_os(name) {
+                    cmd.env(name, val);
+                }
             }
-        } else {
-            Stdio::inherit()
+        }
+        // Setting it on the command makes it what a bare exec is looked
+        // up on, too.
+        if let Some(path) = req.path {
+            cmd.env("PATH", path);
+        }
+        if terminal.is_some() {
+            // SAFETY: make_controlling only makes async-signal-safe calls.
+            unsafe {
+                cmd.pre_exec(pty::make_controlling);
+            }
+        }
+        if let Some(nice) = req.nice {
+            let nice = clamp_nice(nice);
+            // SAFETY: setpriority is async-signal-safe, and nothing else
+            // is done between the fork and the exec.
+            unsafe {
+                cmd.pre_exec(move || {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        if let Some(groups) = req.groups.clone() {
+            // SAFETY: setgroups is async-signal-safe, and the list it's
+            // given was allocated before the fork.
+            unsafe {
+                cmd.pre_exec(move || {
+                    if libc::setgroups(groups.len() as _, groups.as_ptr()) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(ionice) = req.ionice {
+            let ioprio = ioprio(ionice);
+            // SAFETY: A bare syscall is async-signal-safe.
+            unsafe {
+                cmd.pre_exec(move || {
+                    if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        if let Some(umask) = self.shared.config.umask {
+            // SAFETY: umask is async-signal-safe, and can't fail.
+            unsafe {
+                cmd.pre_exec(move || {
+                    libc::umask(umask as libc::mode_t);
+                    Ok(())
+                });
+            }
+        }
+        // A pty's child is made a session leader along with taking the
+        // terminal, and can't be made one twice.
+        if req.detach || (req.setsid && terminal.is_none()) {
+            // SAFETY: setsid is async-signal-safe.
+            unsafe {
+                cmd.pre_exec(|| {
+                    if libc::setsid() == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+        }
+        if !req.fds.is_empty() {
+            let files = open_fds(&req.fds)?;
+            // SAFETY: pass_fds only makes async-signal-safe calls.
+            unsafe {
+                cmd.pre_exec(pass_fds(files));
+            }
+        }
+        // Lowered after the extra fds are installed, which a low enough
+        // limit would otherwise keep from being.
+        let open_files_limit = match req.max_open_files {
+            Some(max) => {
+                let rlimit = libc::rlimit {
+                    rlim_cur: max,
+                    rlim_max: max,
+                };
+                // SAFETY: setrlimit is async-signal-safe.
+                unsafe {
+                    cmd.pre_exec(move || {
+                        if libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit) == -1 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        Ok(())
+                    });
+                }
+                Rlimit::from(rlimit)
+            }
+            None => Rlimit::from(open_files_limit()),
         };
-        let stderr_file = if capture_opts.stderr {
-            let stderr_filepath = id_dir.join("stderr");
-            Stdio {
-                stdio: process::Stdio::from(File::create(&stderr_filepath)?),
-                label: stderr_filepath
-                    .to_str()
-                    .expect("failed to convert Path -> &str")
-                    .to_string(),
+        // Opened up front, so that a cgroup that can't be joined at all
+        // fails the spawn before there's a child to clean up.
+        let cgroup = req
+            .cgroup
+            .map(|cgroup| {
+                OpenOptions::new()
+                    .write(true)
+                    .open(Path::new(cgroup).join("cgroup.procs"))
+                    .map(|procs| (cgroup, procs))
+                    .map_err(|err| Error::CgroupFailed(String::from(cgroup), err))
+            })
+            .transpose()?;
+        let started = (SystemTime::now(), Instant::now());
+        let spawn_started = Instant::now();
+        let spawned = cmd.spawn();
+        self.shared.spawn_durations.record(spawn_started.elapsed());
+        let mut child = spawned.map_err(|err| match (&req.groups, req.ionice) {
+            // Nothing else done before the exec can be refused for want of
+            // privileges: the nice value is checked up front. Whether the
+            // groups can be set takes `CAP_SETGID`, which only trying
+            // tells, and they're set before the I/O priority, so they're
+            // the first that could have failed.
+            _ if err.raw_os_error() != Some(libc::EPERM) => Error::Io(err),
+            (Some(_), _) => Error::GroupsNotPermitted,
+            (None, Some(ionice)) => Error::IoniceNotPermitted(ionice.class()),
+            (None, None) => Error::Io(err),
+        })?;
+        // Our copies of the terminal's slave end have to go, or reading
+        // the master end would never see the child close it.
+        drop(cmd);
+        let pid = child
+            .id()
+            .expect("a freshly spawned child should not have been reaped yet");
+        if let Some((cgroup, mut procs)) = cgroup {
+            // The child runs outside the cgroup until now, though only
+            // for as long as it takes to get here.
+            if let Err(err) = procs.write_all(pid.to_string().as_bytes()) {
+                let _ = child.start_kill();
+                return Err(Error::CgroupFailed(String::from(cgroup), err));
+            }
+        }
+        // Recorded before the reaper starts, so it can't beat us to
+        // recording the exit.
+        if let Some(events) = &self.shared.events {
+            let args = (!self.shared.config.redact_args)
+                .then(|| args.iter().map(|&arg| arg.into()).collect());
+            events.record(
+                id,
+                Event::Spawn {
+                    pid,
+                    exec: exec.into(),
+                    args,
+                },
+            );
+        }
+        let stats = |pump: &Option<Pump>, buffer: &Option<Arc<CaptureBuffer>>| match pump.is_some()
+            || buffer.is_some()
+        {
+            true => Arc::new(StreamStats::counted()),
+            false => Arc::default(),
+        };
+        let stdout_stats = stats(&stdout.pump, &stdout.buffer);
+        // A combined stderr has no capture of its own, but is still
+        // counted on its way into stdout's.
+        let stderr_stats = match capture_opts.combine {
+            Some(_) => Arc::new(StreamStats::counted()),
+            None => stats(&stderr.pump, &stderr.buffer),
+        };
+        let mut pumps = Vec::new();
+        match (stdout.pump, terminal) {
+            (Some(pump), Some(terminal)) => {
+                let src = File::from_std(terminal.master);
+                pumps.push(pump.start(id, Stream::Stdout, src, Arc::clone(&stdout_stats)));
+            }
+            (Some(pump), None) => {
+                let src = child
+                    .stdout
+                    .take()
+                    .expect("a pumped stdout should be piped");
+                let stats = Arc::clone(&stdout_stats);
+                match capture_opts.combine {
+                    Some(combine) => {
+                        let stderr = child
+                            .stderr
+                            .take()
+                            .expect("a combined stderr should be piped");
+                        pumps.push(pump.start_combined(
+                            id,
+                            combine,
+                            src,
+                            stderr,
+                            stats,
+                            Arc::clone(&stderr_stats),
+                        ));
+                    }
+                    None => pumps.push(pump.start(id, Stream::Stdout, src, stats)),
+                }
+            }
+            (None, _) => {}
+        }
+        if let Some(buffer) = &stdout.buffer {
+            let src = child
+                .stdout
+                .take()
+                .expect("a buffered stdout should be piped");
+            pumps.push(Arc::clone(buffer).start(
+                id,
+                Stream::Stdout,
+                src,
+                Arc::clone(&stdout_stats),
+            ));
+        }
+        if let Some(buffer) = &stderr.buffer {
+            let src = child
+                .stderr
+                .take()
+                .expect("a buffered stderr should be piped");
+            pumps.push(Arc::clone(buffer).start(
+                id,
+                Stream::Stderr,
+                src,
+                Arc::clone(&stderr_stats),
+            ));
+        }
+        if let Some(pump) = stderr.pump {
+            let src = child
+                .stderr
+                .take()
+                .expect("a pumped stderr should be piped");
+            pumps.push(pump.start(id, Stream::Stderr, src, Arc::clone(&stderr_stats)));
+        }
+        Ok(Puppet {
+            stdin: Arc::new(Mutex::new(child.stdin.take())),
+            id,
+            seq,
+            name: req.name.map(String::from),
+            command: req.command(),
+            env: child_env(req),
+            pid,
+            proc: Proc::reap(
+                id,
+                started,
+                child,
+                pumps,
+                self.shared.events.clone(),
+                self.shared.config.max_runtime(),
+            ),
+            stdout: stdout.mode,
+            stderr: stderr.mode,
+            stdout_stats,
+            stderr_stats,
+            stdout_buffer: stdout.buffer,
+            stderr_buffer: stderr.buffer,
+            detached: req.detach,
+            tty: req.pty,
+            restart_count: 0,
+            replay: None,
+            open_files_limit,
+        })
+    }
+
+    /// Where a puppet's stream is captured to, creating the directory it
+    /// goes in if need be.
+    fn capture_file(
+        &self,
+        id: PuppetId,
+        name: Option<&str>,
+        stream: Stream,
+    ) -> Result<PathBuf, Error> {
+        let id_dir = self.shared.out_dir.path().join(id.to_string());
+        create_dir_all(&id_dir)?;
+        Ok(id_dir.join(self.shared.config.capture_file_name(stream, id, name)))
+    }
+
+    fn make_stdio(
+        &self,
+        id: PuppetId,
+        name: Option<&str>,
+        capture_opts: CaptureOptions,
+        detached: bool,
+    ) -> Result<(Stdio, Stdio), Error> {
+        let make = |captured: bool, stream: Stream| {
+            if !captured && capture_opts.discard {
+                return Ok(Stdio::discard());
+            }
+            if !captured {
+                return Ok(Stdio::inherit());
             }
-        } else {
-            Stdio::inherit()
+            if capture_opts.in_memory {
+                return Ok(Stdio::memory(capture_opts.max_capture_bytes));
+            }
+            self.capture_file(id, name, stream)
+                .and_then(|path| match capture_opts.fifo {
+                    true => Stdio::fifo(&path),
+                    false => Stdio::capture(&path, capture_opts, detached),
+                })
+                .map_err(|err| Error::capture_setup(stream, err))
         };
+        let stdout_file = make(capture_opts.stdout, Stream::Stdout)?;
+        // Merged into stdout on its way to the capture, which it shares
+        // as with a pty.
+        if capture_opts.combine.is_some() {
+            let stderr_file = Stdio {
+                stdio: process::Stdio::piped(),
+                mode: stdout_file.mode.clone(),
+                pump: None,
+                buffer: None,
+            };
+            return Ok((stdout_file, stderr_file));
+        }
+        let stderr_file = make(capture_opts.stderr, Stream::Stderr)?;
         Ok((stdout_file, stderr_file))
     }
+
+    /// Sets up stdio for a child running in `terminal`. Both of its
+    /// output streams go to the terminal, so they share the one capture.
+    fn make_pty_stdio(
+        &self,
+        id: PuppetId,
+        name: Option<&str>,
+        capture_opts: CaptureOptions,
+        terminal: &Pty,
+    ) -> Result<(Stdio, Stdio), Error> {
+        let (path, mut stdout) = self
+            .capture_file(id, name, Stream::Stdout)
+            .and_then(|path| {
+                let stdout = Stdio::capture_file(&path, capture_opts.append)?;
+                Ok((path, stdout))
+            })
+            .map_err(|err| Error::capture_setup(Stream::Stdout, err))?;
+        stdout.stdio = process::Stdio::from(terminal.slave.try_clone()?);
+        stdout.pump = Some(Pump {
+            path,
+            max_bytes: capture_opts.max_capture_bytes,
+            max_lines: capture_opts.max_lines,
+            rotation: capture_opts.rotation(),
+        });
+        let stderr = Stdio {
+            stdio: process::Stdio::from(terminal.slave.try_clone()?),
+            mode: stdout.mode.clone(),
+            pump: None,
+            buffer: None,
+        };
+        Ok((stdout, stderr))
+    }
+}
+
+/// Runs `f` on `req` once it's been expanded and its executable resolved,
+/// which is what's checked and spawned.
+fn with_normalized<T>(
+    req: &CreateReq,
+    f: impl FnOnce(&CreateReq) -> Result<T, Error>,
+) -> Result<T, Error> {
+    with_env_expanded(req, |req| with_exec_resolved(req, f))
+}
+
+/// Runs `f` on `req`, or if it gives both a `cwd` and a relative path to
+/// its executable, on a copy of it with that path resolved against `cwd`.
+/// `Command` would otherwise resolve it against the server's directory,
+/// before moving into `cwd`.
+fn with_exec_resolved<T>(
+    req: &CreateReq,
+    f: impl FnOnce(&CreateReq) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let cwd = match req.cwd {
+        Some(cwd) if req.exec.contains('/') && Path::new(req.exec).is_relative() => cwd,
+        _ => return f(req),
+    };
+    let resolved = Path::new(cwd).join(req.exec).canonicalize().map_err(|_| {
+        Error::InvalidRequest(format!(
+            "exec '{}' does not exist in cwd '{}'",
+            req.exec, cwd
+        ))
+    })?;
+    let exec = resolved.to_str().ok_or_else(|| {
+        Error::InvalidRequest(format!("exec '{}' does not resolve to UTF-8", req.exec))
+    })?;
+    f(&CreateReq {
+        exec,
+        ..req.clone()
+    })
+}
+
+/// Runs `f` on `req`, or with `expand_env` set, on a copy of it with the
+/// `${VAR}` references in its command expanded.
+fn with_env_expanded<T>(
+    req: &CreateReq,
+    f: impl FnOnce(&CreateReq) -> Result<T, Error>,
+) -> Result<T, Error> {
+    if !req.expand_env {
+        return f(req);
+    }
+    let expand = |part: &str| expand_vars(part, |name| child_var(req, name), req.expand_env_strict);
+    let exec = expand(req.exec)?;
+    let args = req
+        .args
+        .iter()
+        .map(|arg| expand(arg))
+        .collect::<Result<Vec<_>, _>>()?;
+    f(&CreateReq {
+        exec: &exec,
+        args: args.iter().map(String::as_str).collect(),
+        ..req.clone()
+    })
+}
+
+/// Looks up a variable in the environment the child of `req` will get.
+fn child_var(req: &CreateReq, name: &str) -> Option<String> {
+    match req.path {
+        Some(path) if name == "PATH" => Some(String::from(path)),
+        _ => inherits(req, name).then(|| env::var(name).ok()).flatten(),
+    }
+}
+
+/// The whole environment the child of `req` will get. Anything that
+/// isn't valid UTF-8 is converted lossily.
+fn child_env(req: &CreateReq) -> BTreeMap<String, String> {
+    let mut vars: BTreeMap<_, _> = env::vars_os()
+        .map(|(name, val)| {
+            (
+                name.to_string_lossy().into_owned(),
+                val.to_string_lossy().into_owned(),
+            )
+        })
+        .filter(|(name, _)| inherits(req, name))
+        .collect();
+    if let Some(path) = req.path {
+        vars.insert(String::from("PATH"), String::from(path));
+    }
+    vars
+}
+
+/// Whether the child of `req` gets the server's value of a variable.
+fn inherits(req: &CreateReq, name: &str) -> bool {
+    !req.clear_env || req.inherit_env.iter().flatten().any(|&var| var == name)
+}
+
+/// Replaces each `${NAME}` in `part` with the value `lookup` gives for
+/// it. Unset variables are an error when `strict`, and empty otherwise.
+/// A `${` without a closing brace is left as it is.
+fn expand_vars(
+    part: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+    strict: bool,
+) -> Result<String, Error> {
+    let mut out = String::with_capacity(part.len());
+    let mut rest = part;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => break,
+        };
+        let name = &rest[2..end];
+        match lookup(name) {
+            Some(val) => out.push_str(&val),
+            None if strict => {
+                return Err(Error::InvalidRequest(format!(
+                    "environment variable '{}' is not set",
+                    name
+                )))
+            }
+            None => {}
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+const DEFAULT_BACKOFF_MS: u64 = 1000;
+
+/// The longest the wait between restarts grows to.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Waits for a puppet to exit and restarts it if its `restart_policy`
+/// says to. Each restart is watched over by a supervisor of its own.
+struct Supervisor {
+    /// Weak, so that a puppet waiting to be restarted doesn't keep the
+    /// manager around.
+    shared: Weak<Shared>,
+    proc: Proc,
+    /// Tells whether the puppet is still the one under its id.
+    seq: i32,
+    /// The request to restart the puppet with, as JSON, since the
+    /// original borrows from a request body that's long gone by now.
+    req: String,
+    policy: RestartPolicy,
+    max_restarts: Option<u32>,
+    backoff: Duration,
+    restarts: u32,
+}
+
+impl Supervisor {
+    async fn run(mut self) {
+        let id = self.proc.id();
+        // Only fails if the reaper went away, which leaves nothing to go
+        // on.
+        if self.proc.wait(None).await.is_err() || !self.should_restart() {
+            return;
+        }
+        sleep(self.backoff()).await;
+        let manager = match self.shared.upgrade() {
+            Some(shared) => PuppetManager { shared },
+            None => return,
+        };
+        // Nothing is restarted once shutting down, nor if the puppet has
+        // been deleted, or replaced through `requested_id`, while waiting.
+        if manager.shared.shutting_down.load(Ordering::Relaxed)
+            || manager
+                .shared
+                .pups
+                .get(&id)
+                .is_none_or(|pup| pup.seq != self.seq)
+        {
+            return;
+        }
+        let restarted = serde_json::from_str::<CreateReq>(&self.req)
+            .map_err(|err| Error::Io(err.into()))
+            .and_then(|req| {
+                manager
+                    .push_restarted(&req, self.restarts + 1)
+                    .map(|pup| pup.pid())
+            });
+        match restarted {
+            Ok(pid) => info!(
+                "restarted puppet id={} pid={} restarts={}",
+                id,
+                pid,
+                self.restarts + 1
+            ),
+            Err(err) => warn!("failed to restart puppet id={} err={}", id, err),
+        }
+    }
+
+    fn should_restart(&self) -> bool {
+        if self
+            .max_restarts
+            .is_some_and(|max_restarts| self.restarts >= max_restarts)
+        {
+            return false;
+        }
+        match (self.policy, self.proc.state()) {
+            (_, PuppetState::Killed | PuppetState::TimedOut { .. }) => false,
+            (RestartPolicy::Always, _) => true,
+            (RestartPolicy::OnFailure, PuppetState::Exited { code }) => code != 0,
+            (RestartPolicy::OnFailure, PuppetState::Signaled { .. }) => true,
+            _ => false,
+        }
+    }
+
+    fn backoff(&self) -> Duration {
+        self.backoff
+            .saturating_mul(2u32.saturating_pow(self.restarts))
+            .min(MAX_BACKOFF)
+    }
+}
+
+/// How often the sweeper looks for puppets to forget, at most. A puppet
+/// is forgotten somewhere between the TTL and the TTL plus this after it
+/// exits.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Forgets puppets that exited long enough ago. See
+/// `PuppetManager::sweeper`.
+pub struct Sweeper {
+    pups: Arc<DashMap<PuppetId, Puppet>>,
+    out_dir: PathBuf,
+    ttl: Duration,
+}
+
+impl Sweeper {
+    /// Sweeps every so often, forever.
+    pub async fn run(self) {
+        let mut interval = rocket::tokio::time::interval(self.ttl.min(SWEEP_INTERVAL));
+        loop {
+            interval.tick().await;
+            self.sweep();
+        }
+    }
+
+    fn sweep(&self) {
+        let expired: Vec<PuppetId> = self
+            .pups
+            .iter()
+            .filter(|pup| self.expired(pup.value()))
+            .map(|pup| pup.id)
+            .collect();
+        for id in expired {
+            // A requested id may have been reused since it was listed.
+            if self
+                .pups
+                .remove_if(&id, |_, pup| self.expired(pup))
+                .is_none()
+            {
+                continue;
+            }
+            info!("forgot exited puppet id={}", id);
+            if let Err(err) = remove_out_dir(&self.out_dir, id) {
+                warn!("failed to remove output of puppet id={} err={}", id, err);
+            }
+        }
+    }
+
+    fn expired(&self, pup: &Puppet) -> bool {
+        pup.proc
+            .exited_at()
+            .is_some_and(|exited_at| exited_at.elapsed() >= self.ttl)
+    }
+}
+
+/// Removes the directory a puppet's output is captured to, if it has
+/// one.
+fn remove_out_dir(out_dir: &Path, id: PuppetId) -> Result<(), Error> {
+    match remove_dir_all(out_dir.join(id.to_string())) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(Error::Io(err)),
+        _ => Ok(()),
+    }
+}
+
+fn is_fifo(path: &Path) -> bool {
+    path.symlink_metadata()
+        .is_ok_and(|meta| meta.file_type().is_fifo())
+}
+
+/// Removes whatever an earlier puppet with the same id left at `path`.
+fn remove_stale(path: &Path) -> io::Result<()> {
+    match remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Checks that the rotation options make sense together.
+fn validate_rotation(capture_opts: CaptureOptions) -> Result<(), Error> {
+    let invalid = |msg: &str| Err(Error::InvalidRequest(String::from(msg)));
+    match (capture_opts.rotate_bytes, capture_opts.rotate_keep) {
+        (None, Some(_)) => invalid("rotate_keep requires rotate_bytes"),
+        (Some(_), _)
+            if capture_opts.max_capture_bytes.is_some() || capture_opts.max_lines.is_some() =>
+        {
+            invalid("rotate_bytes cannot be used with max_capture_bytes or max_lines")
+        }
+        (Some(0), _) => invalid("rotate_bytes must be greater than 0"),
+        (_, Some(0)) => invalid("rotate_keep must be greater than 0"),
+        _ => Ok(()),
+    }
+}
+
+/// Catches obviously unrunnable executables up front, where otherwise
+/// they'd fail with a much less helpful error from the OS. Checks that
+/// `exec` exists, looking it up on `path` if it's a bare name and `path`
+/// is given, or on the server's `PATH` otherwise.
+fn validate_exec(exec: &str, path: Option<&str>) -> Result<(), Error> {
+    if exec.is_empty() {
+        return Err(Error::InvalidRequest(String::from(
+            "exec must not be empty",
+        )));
+    }
+    if exec.contains('/') {
+        let path = Path::new(exec);
+        if path.is_dir() {
+            return Err(Error::InvalidRequest(format!(
+                "exec '{}' is a directory",
+                exec
+            )));
+        }
+        if !path.exists() {
+            return Err(Error::InvalidRequest(format!(
+                "exec '{}' does not exist",
+                exec
+            )));
+        }
+    } else if !on_path(exec, path) {
+        return Err(Error::InvalidRequest(format!(
+            "exec '{}' was not found on PATH",
+            exec
+        )));
+    }
+    Ok(())
+}
+
+fn open_fds(fds: &[FdSpec]) -> Result<Vec<std::fs::File>, Error> {
+    fds.iter()
+        .map(|fd| {
+            let mut opts = OpenOptions::new();
+            if fd.write {
+                opts.append(true).create(true);
+            } else {
+                opts.read(true);
+            }
+            opts.open(fd.path).map_err(|err| {
+                Error::InvalidRequest(format!("cannot open fd path '{}': {}", fd.path, err))
+            })
+        })
+        .collect()
+}
+
+/// Returns a `pre_exec` hook that installs `files` in the child as fds 3
+/// onwards.
+///
+/// The hook runs in the child between fork and exec, where only
+/// async-signal-safe calls are allowed: in particular nothing may
+/// allocate, since another thread could have held the allocator's lock
+/// at the time of the fork. Everything it needs is allocated up front.
+fn pass_fds(files: Vec<std::fs::File>) -> impl FnMut() -> io::Result<()> + Send + Sync {
+    let mut moved: Vec<RawFd> = vec![-1; files.len()];
+    move || {
+        let first = 3;
+        let past_last = first + files.len() as RawFd;
+        // Get every file out of the way first, since the one destined
+        // for fd 3 could currently be sitting at fd 4, where the next
+        // file is headed. The moved copies are close-on-exec, so they
+        // don't outlive the exec.
+        for (file, moved) in files.iter().zip(moved.iter_mut()) {
+            // SAFETY: This only duplicates an fd we own.
+            *moved = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, past_last) };
+            if *moved == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        for (target, &moved) in (first..past_last).zip(moved.iter()) {
+            // SAFETY: dup2 replaces whatever was at target, which is what
+            // we're after. The new fd isn't close-on-exec.
+            if unsafe { libc::dup2(moved, target) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn clamp_nice(nice: i32) -> i32 {
+    nice.clamp(-20, 19)
+}
+
+/// Checks up front whether the server could give a child the given nice
+/// value, since the child failing to would otherwise be reported as a
+/// generic spawn failure. Anyone can lower their own priority, but raising
+/// it past the server's takes root or a high enough `RLIMIT_NICE`.
+fn validate_nice(nice: i32) -> Result<(), Error> {
+    let nice = clamp_nice(nice);
+    // SAFETY: These only read the calling process's own attributes.
+    let (current, euid, rlimit) = unsafe {
+        let mut rlimit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        libc::getrlimit(libc::RLIMIT_NICE, &mut rlimit);
+        (
+            libc::getpriority(libc::PRIO_PROCESS, 0),
+            libc::geteuid(),
+            rlimit.rlim_cur,
+        )
+    };
+    // RLIMIT_NICE is expressed as 20 - nice, so that it can't go negative.
+    let rlimit_floor = 20 - rlimit.min(40) as i32;
+    if nice >= current || euid == 0 || nice >= rlimit_floor {
+        Ok(())
+    } else {
+        Err(Error::NiceNotPermitted(nice))
+    }
+}
+
+/// Checks that an I/O priority is one the kernel takes. Whether the
+/// server may give it to a child is only found out on spawning, since
+/// unlike with nice values the rules for it vary between kernels.
+#[cfg(target_os = "linux")]
+fn validate_ionice(ionice: Ionice) -> Result<(), Error> {
+    match ionice {
+        Ionice::Realtime { priority } | Ionice::BestEffort { priority } if priority > 7 => Err(
+            Error::InvalidRequest(format!("ionice priority {} is not from 0 to 7", priority)),
+        ),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn validate_ionice(_: Ionice) -> Result<(), Error> {
+    Err(Error::Unsupported(String::from(
+        "ionice is only supported on Linux",
+    )))
+}
+
+/// `ioprio_set(2)`'s `which` for a single process.
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// Packs an I/O class and priority the way `ioprio_set(2)` takes them.
+#[cfg(target_os = "linux")]
+fn ioprio(ionice: Ionice) -> libc::c_int {
+    let (class, priority) = match ionice {
+        Ionice::Realtime { priority } => (1, priority),
+        Ionice::BestEffort { priority } => (2, priority),
+        Ionice::Idle => (3, 0),
+    };
+    (class << 13) | libc::c_int::from(priority)
+}
+
+/// The server's own `RLIMIT_NOFILE`, which children inherit unless told
+/// otherwise.
+fn open_files_limit() -> libc::rlimit {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: This only reads the calling process's own limits.
+    unsafe {
+        libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit);
+    }
+    rlimit
+}
+
+/// Whether a bare executable name would be found by the same PATH lookup
+/// the spawn does.
+fn on_path(exec: &str, path: Option<&str>) -> bool {
+    path.map(OsString::from)
+        .or_else(|| env::var_os("PATH"))
+        .map(|paths| env::split_paths(&paths).any(|dir| dir.join(exec).is_file()))
+        .unwrap_or(false)
+}
+
+/// Kills all of the given processes, waiting at most `grace` for them to
+/// be reaped. A process stuck in uninterruptible sleep can outlive even
+/// SIGKILL, and shouldn't be able to hold up whoever is calling this.
+pub async fn kill_all(procs: Vec<Proc>, grace: Duration) {
+    let kills = join_all(procs.into_iter().map(|mut proc| async move {
+        if let Err(err) = proc.kill().await {
+            warn!("failed to kill puppet id={} err={}", proc.id, err);
+        }
+    }));
+    if timeout(grace, kills).await.is_err() {
+        warn!("gave up waiting on puppets to exit after {:?}", grace);
+    }
+}
+
+/// What stands in for anything hidden: spawn arguments in the log when
+/// `redact_args` is set, and the values of secret variables in `/env`.
+const REDACTED: &str = "[REDACTED]";
+
+/// Renders spawn arguments for logging. Arguments can carry secrets, so
+/// the whole list is replaced with a marker when `redact` is set; the
+/// argument count is logged separately either way.
+pub fn describe_args(args: &[&str], redact: bool) -> String {
+    if redact {
+        String::from(REDACTED)
+    } else {
+        format!("{:?}", args)
+    }
 }
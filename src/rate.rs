@@ -0,0 +1,43 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Lets through up to `rate` events a second on average, in bursts of up
+/// to `rate` at once.
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// Starts out full. `rate` must be positive.
+    pub fn new(rate: u32) -> TokenBucket {
+        TokenBucket {
+            rate: f64::from(rate),
+            state: Mutex::new(Bucket {
+                tokens: f64::from(rate),
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token if there's one to take, or returns how long until
+    /// there will be.
+    pub fn take(&self) -> Result<(), Duration> {
+        let mut bucket = self.state.lock().expect("token bucket lock poisoned");
+        let now = Instant::now();
+        let refill = (now - bucket.refilled).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.rate);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
@@ -1,43 +1,115 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::io::{self, Write};
 use std::os::unix::process::ExitStatusExt;
 use std::process::ExitStatus;
+use std::time::{Duration, Instant};
 
-use rocket::http::Status;
-use rocket::serde::json::Json;
-use rocket::serde::{Deserialize, Serialize};
-use rocket::tokio::sync::Mutex;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rocket::data::{Data, Limits, ToByteUnit, N};
+use rocket::futures::future::{join_all, select_all};
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{self, FromParam, FromRequest, Request};
+use rocket::response::status::{Created, NoContent};
+use rocket::serde::json::{self, Json};
+use rocket::serde::{de, Deserialize, Deserializer, Serialize};
+use rocket::tokio::fs::File;
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use rocket::tokio::process::ChildStdin;
+use rocket::tokio::task::spawn_blocking;
+use rocket::tokio::time::sleep;
 use rocket::State;
+use rocket_ws::stream::DuplexStream;
+use rocket_ws::{Channel, Message, WebSocket};
+use serde_json::value::RawValue;
+use uuid::Uuid;
 
-use crate::error::Error;
-use crate::puppet::{Puppet, PuppetManager};
+use crate::capture::{self, read_past, utf8_prefix, Capture, Captures, Rotation, StreamStats};
+use crate::config::IdScheme;
+use crate::error::{Error, ErrorJSONResp};
+use crate::histogram::BUCKETS_MS;
+use crate::puppet::{
+    parse_signal, Proc, Puppet, PuppetId, PuppetManager, PuppetState, Replay, Rlimit, Stats,
+    StreamMode, TimeoutReason,
+};
+use crate::template::Template;
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
 pub struct CaptureOptions {
     pub stdout: bool,
     pub stderr: bool,
+    /// Append to any existing capture files rather than truncating them.
+    /// Only meaningful with a persistent `out_dir`, where a restarted
+    /// server hands out the same ids (and so the same files) again.
+    #[serde(default)]
+    pub append: bool,
+    /// Keep only this many of the most recent bytes of each captured
+    /// stream, dropping older output as newer output arrives. With
+    /// `append`, whatever was already in the file counts towards this.
+    #[serde(default)]
+    pub max_capture_bytes: Option<u64>,
+    /// Keep only this many of the most recent lines of each captured
+    /// stream. A line still being written is kept on top of these until
+    /// it's finished. Can be combined with `max_capture_bytes`, in which
+    /// case both limits apply.
+    #[serde(default)]
+    pub max_lines: Option<u64>,
+    /// Write each captured stream to a named pipe rather than a file, for
+    /// another process to read from as it's produced. Nothing is kept, so
+    /// this can't be combined with `append` or any limit on what's kept.
+    #[serde(default)]
+    pub fifo: bool,
+    /// Rotate each captured stream once it reaches this many bytes,
+    /// moving it aside to `stdout.1` (or `stderr.1`) and starting afresh.
+    /// Can't be combined with `max_capture_bytes` or `max_lines`.
+    #[serde(default)]
+    pub rotate_bytes: Option<u64>,
+    /// With `rotate_bytes`, how many rotated files to keep. Defaults to 5.
+    #[serde(default)]
+    pub rotate_keep: Option<usize>,
+    /// Keep each captured stream in memory rather than in a file, for
+    /// small jobs where a file per stream isn't worth it. It's still
+    /// read through `/output/<id>/<stream>`, and `max_capture_bytes`
+    /// still applies. Can't be combined with `fifo`, `append`,
+    /// `max_lines` or `rotate_bytes`.
+    #[serde(default)]
+    pub in_memory: bool,
+    /// Throw away whichever of the streams aren't captured, rather than
+    /// letting them through to the server's own.
+    #[serde(default)]
+    pub discard: bool,
+    /// Capture both streams together, into stdout's capture, with each
+    /// line marked with the stream it came from. Needs both streams
+    /// captured, and can't be combined with `fifo` or `in_memory`.
+    #[serde(default)]
+    pub combine: Option<Combine>,
 }
 
 impl CaptureOptions {
-    #[allow(dead_code)]
     pub fn all() -> CaptureOptions {
         CaptureOptions {
             stdout: true,
             stderr: true,
+            ..CaptureOptions::none()
         }
     }
 
-    #[allow(dead_code)]
     pub fn stdout() -> CaptureOptions {
         CaptureOptions {
             stdout: true,
-            stderr: false,
+            ..CaptureOptions::none()
         }
     }
 
-    #[allow(dead_code)]
     pub fn stderr() -> CaptureOptions {
         CaptureOptions {
-            stdout: false,
             stderr: true,
+            ..CaptureOptions::none()
         }
     }
 
@@ -45,29 +117,279 @@ impl CaptureOptions {
         CaptureOptions {
             stdout: false,
             stderr: false,
+            append: false,
+            max_capture_bytes: None,
+            max_lines: None,
+            fifo: false,
+            rotate_bytes: None,
+            rotate_keep: None,
+            in_memory: false,
+            discard: false,
+            combine: None,
         }
     }
+
+    /// Whether output has to pass through the server on its way to the
+    /// capture file, rather than being written there by the child.
+    pub fn pumped(&self) -> bool {
+        self.max_capture_bytes.is_some() || self.max_lines.is_some() || self.rotate_bytes.is_some()
+    }
+
+    pub fn rotation(&self) -> Option<Rotation> {
+        self.rotate_bytes.map(|max_bytes| Rotation {
+            max_bytes,
+            keep: self.rotate_keep.unwrap_or(DEFAULT_ROTATE_KEEP),
+        })
+    }
 }
 
+const DEFAULT_ROTATE_KEEP: usize = 5;
+
 impl Default for CaptureOptions {
     fn default() -> CaptureOptions {
         CaptureOptions::none()
     }
 }
 
-#[derive(Serialize, Deserialize)]
+/// How each line of a `combine`d capture is marked with its stream.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Combine {
+    /// Prefixed with `[out] ` or `[err] `.
+    Tagged,
+    /// Written as a JSON record of its own, e.g.
+    /// `{"stream": "stdout", "line": "..."}`. Output that isn't valid
+    /// UTF-8 is mangled.
+    Jsonl,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+impl<'a> FromParam<'a> for Stream {
+    type Error = &'a str;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        match param {
+            "stdout" => Ok(Stream::Stdout),
+            "stderr" => Ok(Stream::Stderr),
+            _ => Err(param),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct CreateReq<'r> {
     pub exec: &'r str,
+    /// Numbers and booleans are taken as they're written, so `42` and
+    /// `true` are passed as `"42"` and `"true"`.
+    #[serde(borrow, deserialize_with = "typed_args")]
     pub args: Vec<&'r str>,
     pub capture: Option<CaptureOptions>,
+    /// Give the child a stdin pipe that can be written to through
+    /// `/stdin/<id>`, rather than inheriting the server's.
+    #[serde(default)]
+    pub stdin: bool,
+    /// Keep a copy of everything written to the child's stdin, so it can
+    /// be run again on the same input through `/replay/<id>`. Requires
+    /// `stdin`.
+    #[serde(default)]
+    pub record_stdin: bool,
+    /// An http:// URL to post the child's captured output to as it's
+    /// written, and then its exit, as `WebhookEvent`s.
+    pub webhook_url: Option<&'r str>,
+    /// Start the child with an empty environment rather than a copy of
+    /// the server's.
+    #[serde(default)]
+    pub clear_env: bool,
+    /// With `clear_env`, copy just these variables over from the server's
+    /// environment. Any the server doesn't have set are skipped.
+    pub inherit_env: Option<Vec<&'r str>>,
+    /// The child's nice value, from -20 (highest priority) to 19
+    /// (lowest). Values outside that range are clamped to it. Inherits
+    /// the server's if unset.
+    pub nice: Option<i32>,
+    /// The most files the child may have open at once, set as both its
+    /// soft and hard `RLIMIT_NOFILE`. It can't be more than the server's
+    /// own hard limit. Inherits the server's limits if unset.
+    pub max_open_files: Option<u64>,
+    /// The I/O scheduling class and priority to run the child with.
+    /// Inherits the server's if unset. Linux only.
+    pub ionice: Option<Ionice>,
+    /// Supplementary group ids to run the child with, in place of the
+    /// server's. Setting them takes `CAP_SETGID`. Unix only.
+    #[serde(default)]
+    pub groups: Option<Vec<u32>>,
+    /// Run the child in a pseudo-terminal of its own, for programs that
+    /// behave differently when writing to one. Its stdout and stderr
+    /// both go to the terminal, which is captured as stdout, so this
+    /// requires capturing stdout.
+    #[serde(default)]
+    pub pty: bool,
+    /// Files to open on the server and hand to the child as extra fds,
+    /// numbered from 3 in the order given.
+    #[serde(default, borrow)]
+    pub fds: Vec<FdSpec<'r>>,
+    /// Leave the child running when the server shuts down, in a session
+    /// of its own so it isn't caught up in signals meant for the server's.
+    /// Its output can't go through the server, so this can't be combined
+    /// with `pty` or `max_capture_bytes`.
+    #[serde(default)]
+    pub detach: bool,
+    /// Start the child in a session of its own, as its leader, for
+    /// daemons that insist on it. Unlike with `detach`, it's otherwise
+    /// treated like any other puppet: its output goes through the server
+    /// as usual, and it's killed when the server shuts down. A `pty` or
+    /// `detach` already implies this.
+    #[serde(default)]
+    pub setsid: bool,
+    /// Expand `${VAR}` in the executable and arguments against the
+    /// environment the child will get, after `clear_env` and
+    /// `inherit_env` are applied. Unset variables expand to nothing.
+    #[serde(default)]
+    pub expand_env: bool,
+    /// With `expand_env`, refuse to spawn rather than expand an unset
+    /// variable to nothing.
+    #[serde(default)]
+    pub expand_env_strict: bool,
+    /// A name to pick the puppet out by, e.g. to kill a group of them with
+    /// `/kill-all`. Names needn't be unique.
+    pub name: Option<&'r str>,
+    /// Use this id rather than having one picked. It may be the id of a
+    /// puppet that has since exited, which is then replaced, but not of
+    /// one that is still running.
+    pub requested_id: Option<PuppetId>,
+    /// The name the child is told it was run as, in place of `exec`. For
+    /// programs that behave differently depending on it.
+    pub arg0: Option<&'r str>,
+    /// The directory to run the child in, rather than the server's. A
+    /// relative `exec` path like `./script.sh` is taken to be relative to
+    /// this too, whereas a bare name is still looked up on `PATH`.
+    pub cwd: Option<&'r str>,
+    /// The child's `PATH`, which a bare `exec` is also looked up on.
+    /// Applies even with `clear_env`, over any inherited `PATH`.
+    pub path: Option<&'r str>,
+    /// Spawn the child again, under the same id, when it exits.
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    /// The most times the child is restarted. Unlimited if unset.
+    pub max_restarts: Option<u32>,
+    /// How long to wait before restarting the child the first time. The
+    /// wait doubles with each restart after that.
+    pub backoff_ms: Option<u64>,
+    /// The directory of a cgroup v2 cgroup to move the child into, e.g.
+    /// `/sys/fs/cgroup/puppets`. Linux only.
+    pub cgroup: Option<&'r str>,
+}
+
+/// When a puppet is spawned again after it exits. A puppet killed
+/// through puppeteer, whether on request or for running too long, is
+/// never restarted.
+#[derive(Serialize, Deserialize, Default, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    /// When it exits with a non-zero code, or is killed by a signal.
+    OnFailure,
+    Always,
+}
+
+/// An I/O scheduling class to run a puppet in, as with `ionice(1)`.
+/// Priorities within a class go from 0 (highest) to 7 (lowest).
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(tag = "class", rename_all = "snake_case")]
+pub enum Ionice {
+    /// Served ahead of everything else. Takes privileges.
+    Realtime {
+        priority: u8,
+    },
+    BestEffort {
+        priority: u8,
+    },
+    /// Only served when nothing else wants the disk.
+    Idle,
+}
+
+impl Ionice {
+    pub fn class(&self) -> &'static str {
+        match self {
+            Ionice::Realtime { .. } => "realtime",
+            Ionice::BestEffort { .. } => "best_effort",
+            Ionice::Idle => "idle",
+        }
+    }
+}
+
+/// Reads `args` as strings, numbers or booleans. The latter two are
+/// borrowed exactly as they appear in the request, rather than parsed and
+/// printed again, so nothing about them changes on the way through.
+fn typed_args<'de, D>(deserializer: D) -> Result<Vec<&'de str>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<&'de RawValue>::deserialize(deserializer)?
+        .into_iter()
+        .map(|arg| {
+            let text = arg.get();
+            let kind = match text.as_bytes().first() {
+                Some(b'"') => return serde_json::from_str(text).map_err(de::Error::custom),
+                Some(b't' | b'f' | b'-' | b'0'..=b'9') => return Ok(text),
+                Some(b'{') => "an object",
+                Some(b'[') => "an array",
+                _ => "null",
+            };
+            Err(de::Error::custom(format!(
+                "args must be strings, numbers or booleans, not {}",
+                kind
+            )))
+        })
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FdSpec<'r> {
+    pub path: &'r str,
+    /// Open the file for appending to, creating it if need be, rather
+    /// than for reading.
+    #[serde(default)]
+    pub write: bool,
+}
+
+impl CreateReq<'_> {
+    /// The executable followed by its arguments.
+    pub fn command(&self) -> Vec<String> {
+        std::iter::once(self.exec)
+            .chain(self.args.iter().copied())
+            .map(String::from)
+            .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
 pub struct CreateResp {
-    pub id: i32,
+    pub id: PuppetId,
     pub pid: u32,
-    pub stdout: String,
-    pub stderr: String,
+    pub command: Vec<String>,
+    pub stdout: StreamMode,
+    pub stderr: StreamMode,
+    /// Whether the puppet's stdio is a pty, and so whether its output may
+    /// have terminal escapes and CRLF line endings in it.
+    pub tty: bool,
 }
 
 impl From<&Puppet> for CreateResp {
@@ -75,42 +397,210 @@ impl From<&Puppet> for CreateResp {
         CreateResp {
             id: pup.id,
             pid: pup.pid(),
+            command: pup.command.clone(),
             // TODO: Exercise - Can we avoid clone()?
             stdout: pup.stdout.clone(),
             stderr: pup.stderr.clone(),
+            tty: pup.tty,
         }
     }
 }
 
-#[put("/cmd", format = "json", data = "<pup_req>")]
+/// The response to a dry run: the request passed every check a spawn
+/// would make, and this is what would have been run.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+pub struct DryRunResp {
+    pub valid: bool,
+    pub command: Vec<String>,
+}
+
+/// Answers a request that spawned a puppet with a 201 whose `Location`
+/// is the new puppet's `/status/<id>`.
+fn created(resp: CreateResp) -> Created<Json<CreateResp>> {
+    Created::new(format!("/status/{}", resp.id)).body(Json(resp))
+}
+
+#[derive(Responder)]
+pub enum CmdResp {
+    Created(Created<Json<CreateResp>>),
+    Validated(Json<DryRunResp>),
+}
+
+/// Spawns the requested command, answering with a 201 whose `Location` is
+/// the new puppet's `/status/<id>`. With `dry_run` set, the request is
+/// only validated; nothing is spawned and no id is used up.
+#[put("/cmd?<dry_run>", format = "json", data = "<pup_req>")]
 pub async fn cmd(
     pup_req: Json<CreateReq<'_>>,
-    pups: &'_ State<Mutex<PuppetManager>>,
-) -> Result<Json<CreateResp>, Error> {
-    let mut pups = pups.lock().await;
-    let pup = pups.push(
-        pup_req.exec,
-        &pup_req.args,
-        pup_req.capture.unwrap_or(CaptureOptions::default()),
-    )?;
-    Ok(Json(CreateResp::from(pup)))
+    dry_run: Option<bool>,
+    pups: &'_ State<PuppetManager>,
+) -> Result<CmdResp, Error> {
+    if dry_run.unwrap_or(false) {
+        let command = pups.validate(&pup_req)?;
+        return Ok(CmdResp::Validated(Json(DryRunResp {
+            valid: true,
+            command,
+        })));
+    }
+    let pup = pups.push(&pup_req)?;
+    Ok(CmdResp::Created(created(CreateResp::from(&*pup))))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BatchCreateReq<'r> {
+    #[serde(borrow)]
+    pub commands: Vec<CreateReq<'r>>,
 }
 
 #[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BatchCreateResult {
+    Created(CreateResp),
+    Failed(ErrorJSONResp),
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+pub struct BatchCreateResp {
+    pub results: Vec<BatchCreateResult>,
+}
+
+/// Spawns every command in the batch, in order. A command that fails to
+/// spawn is reported in its slot of the results rather than failing the
+/// whole batch.
+#[put("/cmd/batch", format = "json", data = "<batch_req>")]
+pub async fn cmd_batch(
+    batch_req: Json<BatchCreateReq<'_>>,
+    pups: &'_ State<PuppetManager>,
+) -> Json<BatchCreateResp> {
+    let results = batch_req
+        .commands
+        .iter()
+        .map(|pup_req| match pups.push(pup_req) {
+            Ok(pup) => BatchCreateResult::Created(CreateResp::from(&*pup)),
+            Err(err) => BatchCreateResult::Failed(ErrorJSONResp::from(&err)),
+        })
+        .collect();
+    Json(BatchCreateResp { results })
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TemplateReq {
+    pub name: String,
+    #[serde(flatten)]
+    pub template: Template,
+}
+
+/// Registers a command template that `/cmd/from-template/<name>` can
+/// then spawn with different values filled in.
+#[put("/template", format = "json", data = "<template_req>")]
+pub async fn add_template(
+    template_req: Json<TemplateReq>,
+    pups: &'_ State<PuppetManager>,
+) -> Status {
+    let TemplateReq { name, template } = template_req.into_inner();
+    pups.add_template(name, template);
+    Status::Ok
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct FromTemplateReq<'r> {
+    /// The values for the template's `{0}`, `{1}`, ... placeholders.
+    #[serde(borrow)]
+    pub values: Vec<&'r str>,
+}
+
+/// Spawns the command of a registered template, with its placeholders
+/// filled in from the request. Answered as `/cmd` is.
+#[put("/cmd/from-template/<name>", format = "json", data = "<template_req>")]
+pub async fn cmd_from_template(
+    name: &str,
+    template_req: Json<FromTemplateReq<'_>>,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Created<Json<CreateResp>>, Error> {
+    let template = pups
+        .template(name)
+        .ok_or_else(|| Error::TemplateNotFound(String::from(name)))?;
+    let (exec, args) = template.render(name, &template_req.values)?;
+    let pup = pups.push(&CreateReq {
+        exec: &exec,
+        args: args.iter().map(String::as_str).collect(),
+        capture: template.capture,
+        ..Default::default()
+    })?;
+    Ok(created(CreateResp::from(&*pup)))
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
 pub struct WaitResp {
-    id: i32,
+    pub id: PuppetId,
     pub exit_code: i32,
     pub signal_code: i32,
     pub signaled: bool,
     pub success: bool,
+    /// When the puppet was started, in milliseconds since the Unix epoch.
+    pub started_at: u64,
+    /// How long the puppet ran for.
+    pub duration_ms: u64,
+    /// For a puppet that didn't exit successfully, the end of its captured
+    /// stderr, which usually says why. Null if it succeeded or its stderr
+    /// wasn't captured.
+    pub stderr_tail: Option<String>,
+    /// The captured output, inlined if it's small enough. Null for a
+    /// stream that wasn't captured.
+    pub stdout: Option<InlineOutput>,
+    pub stderr: Option<InlineOutput>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+pub struct InlineOutput {
+    /// Whether the output is given in `content`. Output over
+    /// `max_inline_output_bytes`, or that isn't valid UTF-8, has to be
+    /// fetched from `/output/<id>/<stream>` instead.
+    pub inlined: bool,
+    pub content: Option<String>,
+    /// The capture file. Null for output kept `in_memory`.
+    pub path: Option<String>,
+}
+
+impl InlineOutput {
+    /// Reads `capture` in if it has at most `max_bytes` of text in it.
+    /// Returns `None` only if its path can't be reported.
+    async fn read(capture: &Capture, max_bytes: u64) -> Option<InlineOutput> {
+        let content = match capture.size().await {
+            Ok(len) if max_bytes > 0 && len <= max_bytes => capture
+                .read()
+                .await
+                .ok()
+                .filter(|bytes| bytes.len() as u64 <= max_bytes)
+                .and_then(|bytes| String::from_utf8(bytes).ok()),
+            _ => None,
+        };
+        let path = match capture {
+            Capture::File(path) => Some(path.to_str()?.to_string()),
+            Capture::Memory(_) => None,
+        };
+        Some(InlineOutput {
+            inlined: content.is_some(),
+            content,
+            path,
+        })
+    }
 }
 
+/// How much of the end of a failed puppet's stderr is reported along with
+/// its exit.
+const STDERR_TAIL_BYTES: u64 = 4 * 1024;
+
 impl WaitResp {
     const NOVAL: i32 = -1;
 
-    fn from(id: i32, status: ExitStatus) -> Self {
-        let resp = WaitResp {
-            id,
+    pub fn from(proc: &Proc, status: ExitStatus) -> Self {
+        WaitResp {
+            id: proc.id(),
             exit_code: status.code().unwrap_or(Self::NOVAL),
             signal_code: status.code().unwrap_or(
                 status
@@ -119,24 +609,1466 @@ impl WaitResp {
             ),
             signaled: status.code().is_none(),
             success: status.success(),
-        };
+            started_at: proc.started_at_ms(),
+            duration_ms: proc.runtime().unwrap_or_default().as_millis() as u64,
+            stderr_tail: None,
+            stdout: None,
+            stderr: None,
+        }
+    }
 
-        resp
+    /// Fills in the captured output, inlining whatever is at most
+    /// `max_inline` bytes, and `stderr_tail` if the puppet failed. A
+    /// capture that can't be read just isn't inlined.
+    pub async fn with_output(mut self, captures: Captures, max_inline: u64) -> Self {
+        if let Some(capture) = &captures.stdout {
+            self.stdout = InlineOutput::read(capture, max_inline).await;
+        }
+        if let Some(capture) = &captures.stderr {
+            self.stderr = InlineOutput::read(capture, max_inline).await;
+            if !self.success {
+                self.stderr_tail = read_tail(capture, STDERR_TAIL_BYTES)
+                    .await
+                    .ok()
+                    .map(|tail| String::from_utf8_lossy(&tail).into_owned());
+            }
+        }
+        self
     }
 }
 
+async fn read_tail(capture: &Capture, max_bytes: u64) -> io::Result<Vec<u8>> {
+    let len = capture.size().await?;
+    capture
+        .read_at(len.saturating_sub(max_bytes), u64::MAX)
+        .await
+}
+
+/// Returns a handle on the puppet's process, along with where its output
+/// is captured to.
+fn get_proc(pups: &PuppetManager, id: PuppetId) -> Result<(Proc, Captures), Error> {
+    let pup = pups.get(id)?;
+    Ok((pup.proc(), Captures::of(&pup)))
+}
+
+/// Waits for the puppet to exit. Nothing about the puppet is locked while
+/// waiting, so other requests on it go ahead as normal, and a wait that
+/// is cancelled part way through leaves the puppet running.
+///
+/// Rocket only notices a client has hung up once it goes to write the
+/// response, so the wait of a disconnected client carries on until the
+/// puppet exits or `default_timeout_ms` passes, at the cost of a parked
+/// task and nothing more.
 #[post("/wait/<id>")]
-pub async fn wait(id: i32, pups: &'_ State<Mutex<PuppetManager>>) -> Result<Json<WaitResp>, Error> {
-    let mut pups = pups.lock().await;
-    let pup = pups.get(id).ok_or(Error::PuppetNotFound(id))?;
-    let exit_status = pup.wait()?;
-    Ok(Json(WaitResp::from(pup.id, exit_status)))
-}
-
-#[post("/kill/<id>")]
-pub async fn kill(id: i32, pups: &'_ State<Mutex<PuppetManager>>) -> Result<Status, Error> {
-    let mut pups = pups.lock().await;
-    let pup = pups.get(id).ok_or(Error::PuppetNotFound(id))?;
-    pup.kill()?;
+pub async fn wait(id: PuppetId, pups: &'_ State<PuppetManager>) -> Result<Json<WaitResp>, Error> {
+    let limit = pups.default_timeout();
+    let (mut proc, captures) = get_proc(pups, id)?;
+    let exit_status = proc.wait(limit).await?;
+    let wait_resp = WaitResp::from(&proc, exit_status);
+    let max_inline = pups.max_inline_output_bytes();
+    Ok(Json(wait_resp.with_output(captures, max_inline).await))
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+pub struct RunResp {
+    pub wait: WaitResp,
+    /// Whether the puppet was killed for running past the timeout, or
+    /// the server's `max_runtime_ms`.
+    pub timed_out: bool,
+    /// Which of the two it ran past, or null if it didn't time out.
+    pub timeout_reason: Option<TimeoutReason>,
+    /// The captured output, or null for a stream that wasn't captured.
+    /// Anything that isn't valid UTF-8 is replaced.
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    /// The captured output exactly, base64-encoded. Only included when
+    /// asked for.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub stdout_b64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub stderr_b64: Option<String>,
+}
+
+async fn read_capture(id: PuppetId, capture: Option<Capture>) -> Result<Option<Vec<u8>>, Error> {
+    match capture {
+        Some(capture) => capture
+            .read()
+            .await
+            .map(Some)
+            .map_err(|err| Error::capture_io(id, err)),
+        None => Ok(None),
+    }
+}
+
+fn lossy_text(output: &Option<Vec<u8>>) -> Option<String> {
+    output
+        .as_ref()
+        .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Spawns the command, waits for it to exit and returns its captured
+/// output inline. A puppet still running after `timeout_ms` (or the
+/// configured default) is killed, and whatever it managed to write is
+/// returned all the same. With `base64` set, the output is also included
+/// base64-encoded, for when it isn't text.
+#[put("/run?<timeout_ms>&<base64>", format = "json", data = "<pup_req>")]
+pub async fn run(
+    pup_req: Json<CreateReq<'_>>,
+    timeout_ms: Option<u64>,
+    base64: Option<bool>,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Json<RunResp>, Error> {
+    let (id, mut proc, stdout, stderr, limit) = {
+        let limit = timeout_ms
+            .map(Duration::from_millis)
+            .or_else(|| pups.default_timeout());
+        let pup = pups.push(&pup_req)?;
+        let capture = |stream| pup.capture(stream).ok();
+        (
+            pup.id,
+            pup.proc(),
+            capture(Stream::Stdout),
+            capture(Stream::Stderr),
+            limit,
+        )
+    };
+    let status = match proc.wait(limit).await {
+        Ok(status) => status,
+        Err(Error::WaitTimedOut(..)) => proc.time_out().await?,
+        Err(err) => return Err(err),
+    };
+    // Whichever limit was reached first is the one that killed it.
+    let timeout_reason = match proc.state() {
+        PuppetState::TimedOut { reason } => Some(reason),
+        _ => None,
+    };
+    let stdout = read_capture(id, stdout).await?;
+    let stderr = read_capture(id, stderr).await?;
+    let encode = |output: &Option<Vec<u8>>| match base64 {
+        Some(true) => output.as_ref().map(|bytes| BASE64.encode(bytes)),
+        _ => None,
+    };
+    Ok(Json(RunResp {
+        wait: WaitResp::from(&proc, status),
+        timed_out: timeout_reason.is_some(),
+        timeout_reason,
+        stdout_b64: encode(&stdout),
+        stderr_b64: encode(&stderr),
+        stdout: lossy_text(&stdout),
+        stderr: lossy_text(&stderr),
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BatchWaitReq {
+    pub ids: Vec<PuppetId>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BatchWaitResult {
+    Exited(WaitResp),
+    Failed(ErrorJSONResp),
+}
+
+/// Waits on every puppet in the batch concurrently. Results come back in
+/// the order the ids were given, with unknown ids reported in their slot.
+#[post("/wait/batch", format = "json", data = "<batch_req>")]
+pub async fn wait_batch(
+    batch_req: Json<BatchWaitReq>,
+    pups: &'_ State<PuppetManager>,
+) -> Json<Vec<BatchWaitResult>> {
+    let (procs, limit, max_inline): (Vec<Result<_, Error>>, _, _) = {
+        let procs = batch_req.ids.iter().map(|&id| get_proc(pups, id)).collect();
+        (
+            procs,
+            pups.default_timeout(),
+            pups.max_inline_output_bytes(),
+        )
+    };
+    let waits = procs.into_iter().map(|proc| async move {
+        let (mut proc, captures) = proc?;
+        let status = proc.wait(limit).await?;
+        let wait_resp = WaitResp::from(&proc, status);
+        Ok(wait_resp.with_output(captures, max_inline).await)
+    });
+    let results = join_all(waits)
+        .await
+        .into_iter()
+        .map(|result| match result {
+            Ok(wait_resp) => BatchWaitResult::Exited(wait_resp),
+            Err(err) => BatchWaitResult::Failed(ErrorJSONResp::from(&err)),
+        })
+        .collect();
+    Json(results)
+}
+
+/// Waits on every puppet in the batch concurrently, reporting on
+/// whichever exits first. Unlike `/wait/batch`, any unknown id fails the
+/// whole request before anything is waited on.
+#[post("/wait/any", format = "json", data = "<batch_req>")]
+pub async fn wait_any(
+    batch_req: Json<BatchWaitReq>,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Json<WaitResp>, Error> {
+    if batch_req.ids.is_empty() {
+        return Err(Error::InvalidRequest(String::from("ids must not be empty")));
+    }
+    let (procs, limit, max_inline) = {
+        let procs = batch_req
+            .ids
+            .iter()
+            .map(|&id| get_proc(pups, id))
+            .collect::<Result<Vec<_>, _>>()?;
+        (
+            procs,
+            pups.default_timeout(),
+            pups.max_inline_output_bytes(),
+        )
+    };
+    let waits = procs.into_iter().map(|(mut proc, captures)| {
+        Box::pin(async move {
+            let status = proc.wait(limit).await?;
+            let wait_resp = WaitResp::from(&proc, status);
+            Ok::<_, Error>(wait_resp.with_output(captures, max_inline).await)
+        })
+    });
+    let (first, _, _) = select_all(waits).await;
+    Ok(Json(first?))
+}
+
+/// Kills the puppet and reports how it exited. A puppet that had already
+/// exited is left alone, and its original exit is reported instead. The
+/// kill doesn't use up the exit: `/wait/<id>` reports the same one
+/// afterwards.
+///
+/// The signal sent is `signal` if given, by name or number, or else the
+/// configured `kill_signal`. Only signals that end a process by default
+/// are accepted, but anything other than SIGKILL can be caught, and then
+/// the kill waits for however long the puppet takes to exit.
+#[post("/kill/<id>?<signal>")]
+pub async fn kill(
+    id: PuppetId,
+    signal: Option<&str>,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Json<WaitResp>, Error> {
+    let signal = match signal {
+        Some(name) => parse_signal(name).ok_or_else(|| {
+            Error::InvalidRequest(format!(
+                "unknown signal '{}', or one that doesn't end a process",
+                name
+            ))
+        })?,
+        None => pups.kill_signal(),
+    };
+    let (mut proc, captures) = get_proc(pups, id)?;
+    let exit_status = proc.kill_with(signal).await?;
+    let wait_resp = WaitResp::from(&proc, exit_status);
+    let max_inline = pups.max_inline_output_bytes();
+    Ok(Json(wait_resp.with_output(captures, max_inline).await))
+}
+
+/// Pauses the puppet with SIGSTOP until it's resumed.
+#[post("/suspend/<id>")]
+pub async fn suspend(id: PuppetId, pups: &'_ State<PuppetManager>) -> Result<Status, Error> {
+    let proc = { pups.get(id)?.proc() };
+    proc.signal(libc::SIGSTOP).await?;
+    Ok(Status::Ok)
+}
+
+/// Lets a suspended puppet carry on with SIGCONT.
+#[post("/resume/<id>")]
+pub async fn resume(id: PuppetId, pups: &'_ State<PuppetManager>) -> Result<Status, Error> {
+    let proc = { pups.get(id)?.proc() };
+    proc.signal(libc::SIGCONT).await?;
+    Ok(Status::Ok)
+}
+
+/// Would start capturing a running puppet's output, but can't: a child
+/// that was handed the server's stdout or stderr holds its own copy of
+/// it, which nothing short of attaching a debugger to the child could
+/// swap out. Capturing has to be asked for when the puppet is spawned.
+#[post("/capture/<id>", format = "json", data = "<_capture>")]
+pub async fn start_capture(
+    id: PuppetId,
+    _capture: Json<CaptureOptions>,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Status, Error> {
+    let proc = { pups.get(id)?.proc() };
+    if proc.try_wait().is_some() {
+        return Err(Error::NotRunning(id));
+    }
+    Err(Error::Unsupported(String::from(
+        "the output of a running puppet cannot be redirected; set capture when spawning it",
+    )))
+}
+
+/// Forgets an exited puppet and removes its captured output, freeing up
+/// its id. A running puppet is refused, unless `force` is set, in which
+/// case it's killed and waited on first.
+#[delete("/cmd/<id>?<force>")]
+pub async fn remove(
+    id: PuppetId,
+    force: Option<bool>,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Status, Error> {
+    if force.unwrap_or(false) {
+        let mut proc = { pups.get(id)?.proc() };
+        proc.kill().await?;
+    }
+    pups.remove(id)?;
+    Ok(Status::Ok)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RenameReq {
+    /// The puppet's new name, or null to take its name away.
+    pub name: Option<String>,
+}
+
+/// Renames a puppet, running or not. As when creating one, names needn't
+/// be unique.
+#[patch("/cmd/<id>", format = "json", data = "<rename_req>")]
+pub async fn rename(
+    id: PuppetId,
+    rename_req: Json<RenameReq>,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Json<StatusResp>, Error> {
+    pups.rename(id, rename_req.into_inner().name)?;
+    Ok(Json(StatusResp::from(&*pups.get(id)?)))
+}
+
+/// Picks out puppets by name or executable. Every field that is given
+/// has to match, so an empty filter matches every puppet.
+#[derive(Serialize, Deserialize, Default)]
+pub struct KillAllReq {
+    pub name_prefix: Option<String>,
+    /// Matched against the exec the puppet was run as, i.e. the first
+    /// element of `CreateResp::command`, which has been resolved against
+    /// `cwd` and had environment variables expanded.
+    pub exec: Option<String>,
+}
+
+impl KillAllReq {
+    fn matches(&self, pup: &Puppet) -> bool {
+        let name_matches = match (&self.name_prefix, &pup.name) {
+            (Some(prefix), Some(name)) => name.starts_with(prefix.as_str()),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        let exec_matches = match &self.exec {
+            Some(exec) => pup.command[0] == *exec,
+            None => true,
+        };
+        name_matches && exec_matches
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+pub struct KillAllResp {
+    /// The puppets that were killed, in the order they were created.
+    pub killed: Vec<PuppetId>,
+}
+
+/// Kills every running puppet that matches the filter. A puppet that
+/// can't be killed is skipped over, and left out of the response. An
+/// `exec` in the filter has to match the command the puppet was run as,
+/// not the exec as it was requested.
+#[post("/kill-all", format = "json", data = "<filter>")]
+pub async fn kill_matching(
+    filter: Json<KillAllReq>,
+    pups: &'_ State<PuppetManager>,
+) -> Json<KillAllResp> {
+    let procs = pups
+        .list(|pup| filter.matches(pup).then(|| pup.proc()))
+        .into_iter()
+        .flatten()
+        .filter(|proc| proc.try_wait().is_none());
+    let kills = procs.map(|mut proc| async move {
+        match proc.kill().await {
+            Ok(_) => Some(proc.id()),
+            Err(err) => {
+                warn!("failed to kill puppet id={} err={}", proc.id(), err);
+                None
+            }
+        }
+    });
+    let killed = join_all(kills).await.into_iter().flatten().collect();
+    Json(KillAllResp { killed })
+}
+
+#[post("/output/<id>/<stream>/truncate")]
+pub async fn truncate(
+    id: PuppetId,
+    stream: Stream,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Status, Error> {
+    let pup = pups.get(id)?;
+    pup.truncate(stream)?;
+    Ok(Status::Ok)
+}
+
+impl Stats {
+    /// Renders the stats in the Prometheus text exposition format.
+    fn to_prometheus(&self) -> String {
+        let metrics = [
+            (
+                "puppeteer_puppets_total",
+                "counter",
+                "Total number of puppets spawned.",
+                self.spawned,
+            ),
+            (
+                "puppeteer_puppets_running",
+                "gauge",
+                "Number of puppets that have not yet exited.",
+                self.running,
+            ),
+            (
+                "puppeteer_spawn_failures_total",
+                "counter",
+                "Total number of puppets that failed to spawn.",
+                self.spawn_failures,
+            ),
+        ];
+        let mut out: String = metrics
+            .iter()
+            .map(|(name, kind, help, value)| {
+                format!(
+                    "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+                    name = name,
+                    kind = kind,
+                    help = help,
+                    value = value
+                )
+            })
+            .collect();
+        let name = "puppeteer_spawn_duration_ms";
+        let durations = &self.spawn_durations;
+        out.push_str(&format!(
+            "# HELP {name} How long spawning a puppet's process took, in milliseconds.\n\
+             # TYPE {name} histogram\n",
+            name = name
+        ));
+        for (bound, count) in BUCKETS_MS.iter().zip(durations.cumulative) {
+            out.push_str(&format!("{}_bucket{{le=\"{}\"}} {}\n", name, bound, count));
+        }
+        out.push_str(&format!(
+            "{name}_bucket{{le=\"+Inf\"}} {count}\n{name}_sum {sum}\n{name}_count {count}\n",
+            name = name,
+            count = durations.count,
+            sum = durations.sum_ms
+        ));
+        out
+    }
+}
+
+/// A hand-written OpenAPI description of the routes above. It needs
+/// updating along with the request and response types; the tests check
+/// that the two agree on field names.
+const OPENAPI: &str = include_str!("openapi.json");
+
+#[get("/openapi.json")]
+pub async fn openapi() -> (ContentType, &'static str) {
+    (ContentType::JSON, OPENAPI)
+}
+
+/// Identifies the server, for clients juggling more than one.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+pub struct InfoResp {
+    pub pid: u32,
+    pub version: String,
+    pub uptime_ms: u64,
+    pub id_scheme: IdScheme,
+}
+
+#[get("/info")]
+pub async fn info(pups: &'_ State<PuppetManager>) -> Json<InfoResp> {
+    Json(InfoResp {
+        pid: std::process::id(),
+        version: String::from(env!("CARGO_PKG_VERSION")),
+        uptime_ms: pups.uptime().as_millis() as u64,
+        id_scheme: pups.id_scheme(),
+    })
+}
+
+#[get("/metrics")]
+pub async fn metrics(pups: &'_ State<PuppetManager>) -> (ContentType, String) {
+    (ContentType::Plain, pups.stats().to_prometheus())
+}
+
+/// Counts of puppets, for when `/list` would be more than is needed.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+pub struct StatsResp {
+    pub total_spawned: u64,
+    pub running: u64,
+    pub exited: u64,
+    pub spawn_failures: u64,
+}
+
+impl From<Stats> for StatsResp {
+    fn from(stats: Stats) -> Self {
+        StatsResp {
+            total_spawned: stats.spawned,
+            running: stats.running,
+            exited: stats.exited,
+            spawn_failures: stats.spawn_failures,
+        }
+    }
+}
+
+#[get("/stats")]
+pub async fn stats(pups: &'_ State<PuppetManager>) -> Json<StatsResp> {
+    Json(StatsResp::from(pups.stats()))
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+pub struct StreamStatus {
+    #[serde(flatten)]
+    pub mode: StreamMode,
+    /// Whether older output was dropped to keep the capture under its
+    /// `max_capture_bytes`.
+    pub truncated: bool,
+    pub dropped_bytes: u64,
+    /// Whether the stream is captured and the puppet is still running,
+    /// so that more output may yet arrive.
+    pub open: bool,
+    /// Why capturing the stream stopped early, such as the disk filling
+    /// up. Anything the puppet wrote to it afterwards was thrown away.
+    pub error: Option<String>,
+}
+
+impl StreamStatus {
+    fn from(mode: &StreamMode, stats: &StreamStats, running: bool) -> Self {
+        StreamStatus {
+            mode: mode.clone(),
+            truncated: stats.truncated(),
+            dropped_bytes: stats.dropped_bytes(),
+            open: running && mode.kept() && stats.error().is_none(),
+            error: stats.error(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+pub struct StatusResp {
+    pub id: PuppetId,
+    pub name: Option<String>,
+    pub pid: u32,
+    pub command: Vec<String>,
+    pub running: bool,
+    pub state: PuppetState,
+    /// Whether the puppet is paused by `/suspend/<id>`.
+    pub suspended: bool,
+    /// How many times the puppet has been restarted under its
+    /// `restart_policy`.
+    pub restart_count: u32,
+    /// When the puppet was started, in milliseconds since the Unix epoch.
+    pub started_at: u64,
+    /// How long the puppet ran for, or null if it's still running.
+    pub duration_ms: Option<u64>,
+    pub stdout: StreamStatus,
+    pub stderr: StreamStatus,
+    /// Whether the puppet's stdio is a pty.
+    pub tty: bool,
+    /// How many bytes the puppet has written to each stream in all, even
+    /// if they've since been trimmed or rotated away. Null for a stream
+    /// that doesn't pass through the server: one that isn't captured, is
+    /// captured to a fifo, or belongs to a detached puppet. Under a pty,
+    /// everything is counted as stdout.
+    pub stdout_bytes: Option<u64>,
+    pub stderr_bytes: Option<u64>,
+    /// The `RLIMIT_NOFILE` the puppet was started with.
+    pub open_files_limit: Rlimit,
+}
+
+impl From<&Puppet> for StatusResp {
+    fn from(pup: &Puppet) -> Self {
+        let proc = pup.proc();
+        let runtime = proc.runtime();
+        StatusResp {
+            id: pup.id,
+            name: pup.name.clone(),
+            pid: pup.pid(),
+            command: pup.command.clone(),
+            running: runtime.is_none(),
+            state: proc.state(),
+            suspended: proc.suspended(),
+            restart_count: pup.restart_count,
+            started_at: proc.started_at_ms(),
+            duration_ms: runtime.map(|runtime| runtime.as_millis() as u64),
+            stdout: StreamStatus::from(
+                &pup.stdout,
+                &pup.stream_stats(Stream::Stdout),
+                runtime.is_none(),
+            ),
+            stderr: StreamStatus::from(
+                &pup.stderr,
+                &pup.stream_stats(Stream::Stderr),
+                runtime.is_none(),
+            ),
+            tty: pup.tty,
+            stdout_bytes: pup.stream_stats(Stream::Stdout).written_bytes(),
+            stderr_bytes: pup.stream_stats(Stream::Stderr).written_bytes(),
+            open_files_limit: pup.open_files_limit,
+        }
+    }
+}
+
+/// Reports on a puppet. An exited puppet's state is the exit its reaper
+/// recorded, which waits and kills only ever read, so it's the same
+/// however many times the puppet has been waited on.
+#[get("/status/<id>")]
+pub async fn status(
+    id: PuppetId,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Json<StatusResp>, Error> {
+    let pup = pups.get(id)?;
+    Ok(Json(StatusResp::from(&*pup)))
+}
+
+/// Either kind of result is renamed by its own type under `camel-case`.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BatchStatusResult {
+    Found(Box<StatusResp>),
+    Failed(ErrorJSONResp),
+}
+
+/// Reports on every puppet in the batch, keyed by id, with unknown ids
+/// reported under theirs. Each puppet is looked up once, so the statuses
+/// are taken as close together as they can be.
+#[post("/status/batch", format = "json", data = "<batch_req>")]
+pub async fn status_batch(
+    batch_req: Json<BatchWaitReq>,
+    pups: &'_ State<PuppetManager>,
+) -> Json<BTreeMap<String, BatchStatusResult>> {
+    let results = pups
+        .get_each(&batch_req.ids, |pup| StatusResp::from(pup))
+        .into_iter()
+        .map(|(id, result)| {
+            let result = match result {
+                Ok(status) => BatchStatusResult::Found(Box::new(status)),
+                Err(err) => BatchStatusResult::Failed(ErrorJSONResp::from(&err)),
+            };
+            (id.to_string(), result)
+        })
+        .collect();
+    Json(results)
+}
+
+/// The environment a puppet was spawned with, with secrets hidden.
+#[get("/env/<id>")]
+pub async fn env(
+    id: PuppetId,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Json<BTreeMap<String, String>>, Error> {
+    Ok(Json(pups.env(id)?))
+}
+
+/// Lists every puppet the server knows of, exited or not, in the order
+/// they were created.
+#[get("/list")]
+pub async fn list(pups: &'_ State<PuppetManager>) -> Json<Vec<StatusResp>> {
+    Json(pups.list(|pup| StatusResp::from(pup)))
+}
+
+#[derive(FromFormField)]
+pub enum OutputFormat {
+    Raw,
+    Text,
+}
+
+#[derive(FromForm)]
+pub struct OutputQuery {
+    #[field(name = "as")]
+    format: Option<OutputFormat>,
+    /// Wait for output past `offset` to arrive, and return just that.
+    follow: Option<bool>,
+    offset: Option<u64>,
+    /// Read the `n`th most recent rotation of the capture rather than
+    /// the capture itself.
+    rotation: Option<usize>,
+    /// Return only the last this many lines, without reading the rest.
+    tail: Option<u64>,
+}
+
+#[derive(Responder)]
+pub enum Output {
+    #[response(content_type = "application/octet-stream")]
+    Raw(File),
+    #[response(content_type = "application/octet-stream")]
+    Chunk(Vec<u8>),
+    #[response(content_type = "text/plain")]
+    Text(String),
+    #[response(content_type = "application/octet-stream")]
+    GzipRaw(Vec<u8>, Header<'static>),
+    #[response(content_type = "text/plain")]
+    GzipText(Vec<u8>, Header<'static>),
+}
+
+impl Output {
+    /// Compresses the output with gzip, keeping its content type.
+    async fn gzip(self) -> io::Result<Output> {
+        let (bytes, text) = match self {
+            Output::Raw(mut file) => {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes).await?;
+                (bytes, false)
+            }
+            Output::Chunk(bytes) => (bytes, false),
+            Output::Text(text) => (text.into_bytes(), true),
+            gzipped => return Ok(gzipped),
+        };
+        let compressed = spawn_blocking(move || {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&bytes)?;
+            encoder.finish()
+        })
+        .await
+        .map_err(io::Error::other)??;
+        let encoding = Header::new("Content-Encoding", "gzip");
+        Ok(match text {
+            true => Output::GzipText(compressed, encoding),
+            false => Output::GzipRaw(compressed, encoding),
+        })
+    }
+}
+
+/// Whether the client's `Accept-Encoding` allows a gzipped response.
+pub struct AcceptsGzip(bool);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptsGzip {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let accepts = req
+            .headers()
+            .get("Accept-Encoding")
+            .flat_map(|codings| codings.split(','))
+            .any(|coding| {
+                let mut params = coding.split(';').map(str::trim);
+                params.next() == Some("gzip")
+                    && !params.any(|param| {
+                        param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+                    })
+            });
+        request::Outcome::Success(AcceptsGzip(accepts))
+    }
+}
+
+/// Captured output, along with headers saying whether any of it was
+/// dropped to keep the capture under its size limit, and whether more
+/// may still be written to it.
+#[derive(Responder)]
+pub struct OutputResp {
+    output: Output,
+    truncated: Header<'static>,
+    dropped_bytes: Header<'static>,
+    open: Header<'static>,
+}
+
+impl OutputResp {
+    fn new(output: Output, stats: &StreamStats, open: bool) -> Self {
+        OutputResp {
+            output,
+            truncated: Header::new("X-Puppeteer-Truncated", stats.truncated().to_string()),
+            dropped_bytes: Header::new(
+                "X-Puppeteer-Dropped-Bytes",
+                stats.dropped_bytes().to_string(),
+            ),
+            open: Header::new("X-Puppeteer-Open", open.to_string()),
+        }
+    }
+}
+
+/// A chunk of output from following a capture, along with the offset to
+/// follow on from and whether the puppet has exited with nothing more to
+/// come.
+#[derive(Responder)]
+pub struct FollowResp {
+    output: OutputResp,
+    offset: Header<'static>,
+    eof: Header<'static>,
+}
+
+/// Part of a capture, as asked for with a `Range` header.
+#[derive(Responder)]
+#[response(status = 206)]
+pub struct PartialResp {
+    output: OutputResp,
+    content_range: Header<'static>,
+}
+
+#[derive(Responder)]
+pub enum OutputResult {
+    Whole(OutputResp),
+    Followed(FollowResp),
+    Partial(PartialResp),
+}
+
+/// The single byte range asked for with a `Range` header, if any. Any
+/// other kind of range, or more than one, is ignored and the whole output
+/// served instead, as HTTP allows.
+pub struct ByteRange(Option<RangeSpec>);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum RangeSpec {
+    /// From the first offset up to and including the second, or to the
+    /// end if there isn't one.
+    From(u64, Option<u64>),
+    /// The last so many bytes.
+    Suffix(u64),
+}
+
+impl RangeSpec {
+    fn parse(header: &str) -> Option<RangeSpec> {
+        let spec = header.trim().strip_prefix("bytes=")?;
+        let (start, end) = spec.trim().split_once('-')?;
+        match (start, end) {
+            ("", suffix) => suffix.parse().ok().map(RangeSpec::Suffix),
+            (start, "") => Some(RangeSpec::From(start.parse().ok()?, None)),
+            (start, end) => {
+                let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+                (start <= end).then_some(RangeSpec::From(start, Some(end)))
+            }
+        }
+    }
+
+    /// The offsets of the first and last bytes of the range within `len`
+    /// bytes of output, or `None` if none of them fall within it.
+    fn resolve(self, len: u64) -> Option<(u64, u64)> {
+        let (start, end) = match self {
+            RangeSpec::From(start, end) => (start, end.unwrap_or(u64::MAX)),
+            RangeSpec::Suffix(0) => return None,
+            RangeSpec::Suffix(n) => (len.saturating_sub(n), u64::MAX),
+        };
+        (start < len).then(|| (start, end.min(len - 1)))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ByteRange {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let range = req.headers().get_one("Range").and_then(RangeSpec::parse);
+        request::Outcome::Success(ByteRange(range))
+    }
+}
+
+/// How long a follow waits for new output before giving up and returning
+/// nothing, so that clients can't tie up a connection indefinitely.
+const FOLLOW_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a follow checks for new output.
+pub const FOLLOW_POLL: Duration = Duration::from_millis(50);
+
+#[get("/output/<id>/<stream>?<query..>")]
+pub async fn output(
+    id: PuppetId,
+    stream: Stream,
+    query: OutputQuery,
+    gzip: AcceptsGzip,
+    range: ByteRange,
+    pups: &'_ State<PuppetManager>,
+) -> Result<OutputResult, Error> {
+    let (capture, stats, proc) = {
+        let pup = pups.get(id)?;
+        (pup.capture(stream)?, pup.stream_stats(stream), pup.proc())
+    };
+    if query.follow.unwrap_or(false) && query.tail.is_some() {
+        return Err(Error::InvalidRequest(String::from(
+            "follow cannot be used with tail",
+        )));
+    }
+    let capture = match (query.rotation, capture) {
+        (Some(_), _) if query.follow.unwrap_or(false) => {
+            return Err(Error::InvalidRequest(String::from(
+                "follow cannot be used with rotation",
+            )))
+        }
+        (Some(0) | None, capture) => capture,
+        (Some(n), Capture::File(path)) => Capture::File(capture::rotated_path(&path, n)),
+        // Output kept in memory is never rotated.
+        (Some(_), Capture::Memory(_)) => {
+            return Err(Error::InvalidRequest(String::from(
+                "rotation cannot be used with in_memory captures",
+            )));
+        }
+    };
+    // Checked before reading, for the same reason as when following. A
+    // rotated file is never written to again.
+    let open = proc.try_wait().is_none() && query.rotation.unwrap_or(0) == 0;
+    let format = query.format.unwrap_or(OutputFormat::Raw);
+    let text = |bytes| {
+        String::from_utf8(bytes)
+            .map(Output::Text)
+            .map_err(|_| Error::InvalidUtf8(id, stream))
+    };
+    let gone = |err| Error::capture_io(id, err);
+    let encode = |output: Output| async {
+        match gzip.0 {
+            true => output.gzip().await.map_err(Error::Io),
+            false => Ok(output),
+        }
+    };
+    if query.follow.unwrap_or(false) {
+        let (bytes, offset, eof) = follow(&capture, &proc, query.offset.unwrap_or(0))
+            .await
+            .map_err(gone)?;
+        let output = match format {
+            OutputFormat::Raw => Output::Chunk(bytes),
+            OutputFormat::Text => text(bytes)?,
+        };
+        let output = encode(output).await?;
+        return Ok(OutputResult::Followed(FollowResp {
+            output: OutputResp::new(output, &stats, !eof),
+            offset: Header::new("X-Puppeteer-Offset", offset.to_string()),
+            eof: Header::new("X-Puppeteer-Eof", eof.to_string()),
+        }));
+    }
+    // A range is ignored in favour of the tail, as HTTP allows.
+    if let Some(lines) = query.tail {
+        let bytes = capture.read_last_lines(lines).await.map_err(gone)?;
+        let output = match format {
+            OutputFormat::Raw => Output::Chunk(bytes),
+            OutputFormat::Text => text(bytes)?,
+        };
+        let output = encode(output).await?;
+        return Ok(OutputResult::Whole(OutputResp::new(output, &stats, open)));
+    }
+    // Ranges only make sense of the raw bytes, so are served as they
+    // are, without compressing them.
+    if let (OutputFormat::Raw, ByteRange(Some(range))) = (&format, range) {
+        let (bytes, content_range) = read_range(id, &capture, range).await?;
+        return Ok(OutputResult::Partial(PartialResp {
+            output: OutputResp::new(Output::Chunk(bytes), &stats, open),
+            content_range,
+        }));
+    }
+    let output = match (format, capture) {
+        (OutputFormat::Raw, Capture::File(path)) => {
+            Output::Raw(File::open(path).await.map_err(gone)?)
+        }
+        (OutputFormat::Raw, capture) => Output::Chunk(capture.read().await.map_err(gone)?),
+        (OutputFormat::Text, capture) => text(capture.read().await.map_err(gone)?)?,
+    };
+    let output = encode(output).await?;
+    Ok(OutputResult::Whole(OutputResp::new(output, &stats, open)))
+}
+
+/// Serves every captured stream of a puppet at once, as a part each of a
+/// `multipart/mixed` body. Streams that can't be read through the server,
+/// such as inherited ones, are left out, as is stderr under a pty, since
+/// it's the same capture as stdout.
+#[get("/output/<id>")]
+pub async fn output_multipart(
+    id: PuppetId,
+    pups: &'_ State<PuppetManager>,
+) -> Result<(ContentType, Vec<u8>), Error> {
+    let captures = Captures::of(&*pups.get(id)?);
+    let mut streams = vec![(Stream::Stdout, captures.stdout.as_ref())];
+    if captures.stderr != captures.stdout {
+        streams.push((Stream::Stderr, captures.stderr.as_ref()));
+    }
+    let boundary = format!("puppeteer-{}", Uuid::new_v4().simple());
+    let mut body = Vec::new();
+    for (stream, capture) in streams {
+        let capture = match capture {
+            Some(capture) => capture,
+            None => continue,
+        };
+        let bytes = capture
+            .read()
+            .await
+            .map_err(|err| Error::capture_io(id, err))?;
+        write!(
+            body,
+            "--{}\r\nContent-Type: application/octet-stream\r\n\
+             Content-Disposition: inline; name=\"{}\"\r\n\r\n",
+            boundary, stream
+        )?;
+        body.extend_from_slice(&bytes);
+        body.extend_from_slice(b"\r\n");
+    }
+    write!(body, "--{}--\r\n", boundary)?;
+    let content_type = ContentType::new("multipart", "mixed").with_params(("boundary", boundary));
+    Ok((content_type, body))
+}
+
+/// Reads `range` of `capture`, returning it along with its
+/// `Content-Range`.
+async fn read_range(
+    id: PuppetId,
+    capture: &Capture,
+    range: RangeSpec,
+) -> Result<(Vec<u8>, Header<'static>), Error> {
+    let gone = |err| Error::capture_io(id, err);
+    let len = capture.size().await.map_err(gone)?;
+    let (start, end) = range.resolve(len).ok_or(Error::RangeNotSatisfiable(len))?;
+    let bytes = capture
+        .read_at(start, end - start + 1)
+        .await
+        .map_err(gone)?;
+    // The capture may have been cut short since its length was taken.
+    let end = start + (bytes.len() as u64).max(1) - 1;
+    let content_range = Header::new("Content-Range", format!("bytes {}-{}/{}", start, end, len));
+    Ok((bytes, content_range))
+}
+
+/// The files a capture has been rotated out to.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+pub struct RotationsResp {
+    /// Most recent first, so that the file at index `i` can be read with
+    /// `?rotation=<i + 1>`.
+    pub rotated: Vec<String>,
+}
+
+#[get("/output/<id>/<stream>/rotations")]
+pub async fn rotations(
+    id: PuppetId,
+    stream: Stream,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Json<RotationsResp>, Error> {
+    let rotated = match pups.get(id)?.capture(stream)? {
+        Capture::File(path) => capture::rotations(&path)
+            .iter()
+            .map(|rotated| rotated.display().to_string())
+            .collect(),
+        Capture::Memory(_) => Vec::new(),
+    };
+    Ok(Json(RotationsResp { rotated }))
+}
+
+/// Waits up to `FOLLOW_TIMEOUT` for `capture` to grow past
+/// `offset`, returning whatever it grew by, the offset just past that and
+/// whether the puppet has exited with nothing further written. A capture
+/// that has shrunk below `offset` since (by being truncated, or trimmed
+/// to its size limit) is read again from the start.
+async fn follow(capture: &Capture, proc: &Proc, offset: u64) -> io::Result<(Vec<u8>, u64, bool)> {
+    let deadline = Instant::now() + FOLLOW_TIMEOUT;
+    loop {
+        // Checked before reading, as output written just before exiting
+        // has made it to the capture by the time the exit is published.
+        let exited = proc.try_wait().is_some();
+        let (bytes, offset) = read_past(capture, offset).await?;
+        if !bytes.is_empty() {
+            return Ok((bytes, offset, false));
+        }
+        if exited || Instant::now() >= deadline {
+            return Ok((bytes, offset, exited));
+        }
+        sleep(FOLLOW_POLL).await;
+    }
+}
+
+/// A message sent over `/ws/<id>`.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+#[cfg_attr(feature = "camel-case", serde(rename_all_fields = "camelCase"))]
+pub enum WsFrame {
+    /// More of what the puppet wrote to one of its captured streams.
+    Output { stream: Stream, data: String },
+    /// The puppet has exited. This is the last frame before the socket is
+    /// closed.
+    Exit(WaitResp),
+    /// A message from the client couldn't be written to stdin.
+    Error(ErrorJSONResp),
+}
+
+/// Streams a puppet's captured output to the client, and writes whatever
+/// the client sends to the puppet's stdin, until the puppet exits.
+#[get("/ws/<id>")]
+pub fn websocket(
+    id: PuppetId,
+    ws: WebSocket,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Channel<'static>, Error> {
+    let (proc, captures, stdin, replay) = {
+        let pup = pups.get(id)?;
+        (pup.proc(), Captures::of(&pup), pup.stdin(), pup.replay())
+    };
+    let max_inline = pups.max_inline_output_bytes();
+    let mut streams = vec![(Stream::Stdout, captures.stdout.clone(), 0)];
+    // Under a pty, stderr goes to the same capture as stdout.
+    if captures.stderr != captures.stdout {
+        streams.push((Stream::Stderr, captures.stderr.clone(), 0));
+    }
+    Ok(ws.channel(move |mut socket| {
+        Box::pin(async move {
+            loop {
+                let exited = proc.try_wait();
+                for (stream, capture, offset) in streams.iter_mut() {
+                    let capture = match capture {
+                        Some(capture) => capture,
+                        None => continue,
+                    };
+                    let (bytes, end) = read_past(capture, *offset).await?;
+                    let (data, read) = utf8_prefix(&bytes, exited.is_some());
+                    *offset = end - (bytes.len() - read) as u64;
+                    if !data.is_empty() {
+                        let frame = WsFrame::Output {
+                            stream: *stream,
+                            data,
+                        };
+                        socket.send(ws_frame(&frame)?).await?;
+                    }
+                }
+                if let Some(status) = exited {
+                    let status = status.map_err(|err| io::Error::other(err.to_string()))?;
+                    let wait_resp = WaitResp::from(&proc, status)
+                        .with_output(captures, max_inline)
+                        .await;
+                    socket.send(ws_frame(&WsFrame::Exit(wait_resp))?).await?;
+                    return socket.close(None).await;
+                }
+                rocket::tokio::select! {
+                    msg = socket.next() => match msg {
+                        Some(Ok(Message::Text(text))) => {
+                            write_ws_stdin(id, &stdin, replay.as_ref(), &mut socket, text.as_bytes()).await?
+                        }
+                        Some(Ok(Message::Binary(bytes))) => {
+                            write_ws_stdin(id, &stdin, replay.as_ref(), &mut socket, &bytes).await?
+                        }
+                        Some(Ok(Message::Close(_))) | None => return Ok(()),
+                        Some(Ok(_)) => {}
+                        Some(Err(err)) => return Err(err),
+                    },
+                    _ = sleep(FOLLOW_POLL) => {}
+                }
+            }
+        })
+    }))
+}
+
+fn ws_frame(frame: &WsFrame) -> io::Result<Message> {
+    json::to_string(frame)
+        .map(Message::Text)
+        .map_err(io::Error::other)
+}
+
+async fn write_ws_stdin(
+    id: PuppetId,
+    stdin: &rocket::tokio::sync::Mutex<Option<ChildStdin>>,
+    replay: Option<&Replay>,
+    socket: &mut DuplexStream,
+    bytes: &[u8],
+) -> rocket_ws::result::Result<()> {
+    let mut stdin = stdin.lock().await;
+    let result = match stdin.as_mut() {
+        Some(pipe) => match pipe.write_all(bytes).await {
+            Ok(()) => {
+                replay.inspect(|replay| replay.record(bytes));
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                *stdin = None;
+                Err(Error::StdinClosed(id))
+            }
+            result => result.map_err(Error::Io),
+        },
+        None => Err(Error::StdinClosed(id)),
+    };
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let frame = WsFrame::Error(ErrorJSONResp::from(&err));
+            socket.send(ws_frame(&frame)?).await
+        }
+    }
+}
+
+/// Writes the request body to the puppet's stdin, then closes it if
+/// `close` is set so the child sees EOF.
+#[post("/stdin/<id>?<close>", data = "<data>")]
+pub async fn stdin(
+    id: PuppetId,
+    close: Option<bool>,
+    data: Data<'_>,
+    limits: &Limits,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Status, Error> {
+    let (stdin, replay) = {
+        let pup = pups.get(id)?;
+        (pup.stdin(), pup.replay())
+    };
+    let mut stdin = stdin.lock().await;
+    let pipe = stdin.as_mut().ok_or(Error::StdinClosed(id))?;
+    let limit = limits.get("stdin").unwrap_or_else(|| 1.mebibytes());
+    let result = match &replay {
+        // The body is read in whole first, so that what's recorded is
+        // exactly what was written, and one over the limit is neither.
+        Some(replay) => match data.open(limit).into_bytes().await {
+            Ok(bytes) if !bytes.is_complete() => {
+                return Err(Error::RequestTooLarge(format!(
+                    "request is over the limit of {}; none of it was written",
+                    limit
+                )));
+            }
+            Ok(bytes) => pipe.write_all(&bytes).await.map(|()| {
+                replay.record(&bytes);
+                N {
+                    written: bytes.len() as u64,
+                    complete: true,
+                }
+            }),
+            Err(err) => Err(err),
+        },
+        None => data.open(limit).stream_to(pipe).await,
+    };
+    let written = match result {
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+            *stdin = None;
+            return Err(Error::StdinClosed(id));
+        }
+        result => result?,
+    };
+    // Otherwise the body is streamed, so what came before the limit has
+    // already been written by the time it's known to be over.
+    if !written.complete {
+        return Err(Error::RequestTooLarge(format!(
+            "request is over the limit of {}; only its first {} bytes were written",
+            limit, written.written
+        )));
+    }
+    if close.unwrap_or(false) {
+        *stdin = None;
+    }
     Ok(Status::Ok)
 }
+
+/// Spawns a new puppet from the request an exited one was spawned with,
+/// and writes everything that was written to its stdin to the new one's,
+/// closing it after. The exited puppet must have been spawned with
+/// `record_stdin`. Answered as `/cmd` is.
+#[post("/replay/<id>")]
+pub async fn replay(
+    id: PuppetId,
+    pups: &'_ State<PuppetManager>,
+) -> Result<Created<Json<CreateResp>>, Error> {
+    let (resp, stdin, replay, input) = {
+        let (pup, input) = pups.replay(id)?;
+        (CreateResp::from(&*pup), pup.stdin(), pup.replay(), input)
+    };
+    let new_id = resp.id;
+    // Written in the background, since the puppet may not read all of it
+    // until something reads its output in turn.
+    rocket::tokio::spawn(async move {
+        let mut stdin = stdin.lock().await;
+        if let Some(pipe) = stdin.as_mut() {
+            match pipe.write_all(&input).await {
+                Ok(()) => {
+                    replay.inspect(|replay| replay.record(&input));
+                }
+                Err(err) => warn!("failed to replay stdin of puppet id={} err={}", new_id, err),
+            }
+        }
+        *stdin = None;
+    });
+    Ok(created(resp))
+}
+
+/// A JSON-RPC 2.0 request. Its `params` are left as they are until it's
+/// known which method they're for.
+#[derive(Serialize, Deserialize)]
+pub struct RpcReq<'r> {
+    pub jsonrpc: &'r str,
+    pub method: &'r str,
+    #[serde(borrow, default)]
+    pub params: Option<&'r RawValue>,
+    /// Echoed back in the response. A request without one is a
+    /// notification, which gets no response at all, but one with a null
+    /// id still gets one.
+    #[serde(
+        default,
+        deserialize_with = "present",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub id: Option<serde_json::Value>,
+}
+
+/// Reads a field that's there, even as null, as `Some`, so that only a
+/// missing one, left to its default, is `None`.
+fn present<'de, D>(deserializer: D) -> Result<Option<serde_json::Value>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    serde_json::Value::deserialize(deserializer).map(Some)
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+pub struct RpcResp {
+    pub jsonrpc: String,
+    /// What the method's route would have responded with. Only set if it
+    /// succeeded.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub result: Option<serde_json::Value>,
+    /// Only set if the method failed.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<RpcError>,
+    /// The request's id, or null if it couldn't be read.
+    pub id: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "camel-case", serde(rename_all = "camelCase"))]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+    /// For an error from the method itself, the `err` and `code` its
+    /// route would have responded with.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub data: Option<ErrorJSONResp>,
+}
+
+const RPC_PARSE_ERROR: i32 = -32700;
+const RPC_INVALID_REQUEST: i32 = -32600;
+const RPC_METHOD_NOT_FOUND: i32 = -32601;
+const RPC_INVALID_PARAMS: i32 = -32602;
+const RPC_INTERNAL_ERROR: i32 = -32603;
+/// The first of the codes JSON-RPC leaves to the server, used for every
+/// error a method's route would have responded with.
+const RPC_SERVER_ERROR: i32 = -32000;
+
+impl RpcError {
+    fn new(code: i32, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(err: Error) -> Self {
+        RpcError {
+            code: RPC_SERVER_ERROR,
+            message: err.to_string(),
+            data: Some(ErrorJSONResp::from(&err)),
+        }
+    }
+}
+
+/// The params of `wait` and `status`.
+#[derive(Serialize, Deserialize)]
+pub struct RpcIdParams {
+    pub id: PuppetId,
+}
+
+/// The params of `kill`.
+#[derive(Serialize, Deserialize)]
+pub struct RpcKillParams<'r> {
+    pub id: PuppetId,
+    #[serde(default)]
+    pub signal: Option<&'r str>,
+}
+
+#[derive(Responder)]
+pub enum RpcReply {
+    Replied(Json<RpcResp>),
+    Notified(NoContent),
+}
+
+/// Serves `create`, `wait`, `kill`, `status` and `list` over JSON-RPC
+/// 2.0, for clients that speak it rather than REST. Each method takes
+/// the same parameters as its route, by name, and results in what the
+/// route would have responded with. Batches aren't supported.
+#[post("/rpc", data = "<data>")]
+pub async fn rpc(
+    data: Data<'_>,
+    limits: &Limits,
+    pups: &'_ State<PuppetManager>,
+) -> Result<RpcReply, Error> {
+    let limit = limits.get("json").unwrap_or(Limits::JSON);
+    let body = data.open(limit).into_string().await?;
+    if !body.is_complete() {
+        return Err(Error::RequestTooLarge(format!(
+            "request is over the limit of {}",
+            limit
+        )));
+    }
+    let (id, result) = match serde_json::from_str::<RpcReq>(&body) {
+        Ok(req) if req.jsonrpc != "2.0" => (
+            req.id.unwrap_or_default(),
+            Err(RpcError::new(
+                RPC_INVALID_REQUEST,
+                "jsonrpc must be \"2.0\"",
+            )),
+        ),
+        Ok(req) => {
+            let result = call_rpc(req.method, req.params, pups).await;
+            match req.id {
+                Some(id) => (id, result),
+                None => return Ok(RpcReply::Notified(NoContent)),
+            }
+        }
+        Err(err) if err.is_syntax() || err.is_eof() => (
+            serde_json::Value::Null,
+            Err(RpcError::new(RPC_PARSE_ERROR, err.to_string())),
+        ),
+        Err(err) => (
+            serde_json::Value::Null,
+            Err(RpcError::new(RPC_INVALID_REQUEST, err.to_string())),
+        ),
+    };
+    let (result, error) = match result {
+        Ok(result) => (Some(result), None),
+        Err(err) => (None, Some(err)),
+    };
+    Ok(RpcReply::Replied(Json(RpcResp {
+        jsonrpc: String::from("2.0"),
+        result,
+        error,
+        id,
+    })))
+}
+
+async fn call_rpc(
+    method: &str,
+    params: Option<&RawValue>,
+    pups: &'_ State<PuppetManager>,
+) -> Result<serde_json::Value, RpcError> {
+    let result = match method {
+        "create" => {
+            let req: CreateReq = rpc_params(params)?;
+            let pup = pups.push(&req)?;
+            serde_json::to_value(CreateResp::from(&*pup))
+        }
+        "wait" => {
+            let RpcIdParams { id } = rpc_params(params)?;
+            serde_json::to_value(wait(id, pups).await?.into_inner())
+        }
+        "kill" => {
+            let RpcKillParams { id, signal } = rpc_params(params)?;
+            serde_json::to_value(kill(id, signal, pups).await?.into_inner())
+        }
+        "status" => {
+            let RpcIdParams { id } = rpc_params(params)?;
+            serde_json::to_value(status(id, pups).await?.into_inner())
+        }
+        "list" => serde_json::to_value(list(pups).await.into_inner()),
+        _ => {
+            return Err(RpcError::new(
+                RPC_METHOD_NOT_FOUND,
+                format!("no such method '{}'", method),
+            ))
+        }
+    };
+    result.map_err(|err| RpcError::new(RPC_INTERNAL_ERROR, err.to_string()))
+}
+
+/// Reads a method's params. Leaving them out is the same as giving none.
+fn rpc_params<'r, T: Deserialize<'r>>(params: Option<&'r RawValue>) -> Result<T, RpcError> {
+    serde_json::from_str(params.map_or("{}", RawValue::get))
+        .map_err(|err| RpcError::new(RPC_INVALID_PARAMS, err.to_string()))
+}
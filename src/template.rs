@@ -0,0 +1,69 @@
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::routes::CaptureOptions;
+
+/// A command registered for reuse, whose executable and arguments may
+/// contain `{0}`, `{1}`, ... placeholders to be filled in each time it's
+/// run.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Template {
+    pub exec: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    pub capture: Option<CaptureOptions>,
+}
+
+impl Template {
+    /// Fills in the placeholders with the given values, returning the
+    /// executable and arguments to run. Every placeholder must have a
+    /// value; values without a placeholder are ignored.
+    pub fn render(&self, name: &str, values: &[&str]) -> Result<(String, Vec<String>), Error> {
+        let fill = |part: &str| {
+            substitute(part, values).map_err(|index| {
+                Error::InvalidRequest(format!(
+                    "template '{}' needs a value for {{{}}}, but {} given",
+                    name,
+                    index,
+                    values.len()
+                ))
+            })
+        };
+        let exec = fill(&self.exec)?;
+        let args = self
+            .args
+            .iter()
+            .map(|arg| fill(arg))
+            .collect::<Result<_, _>>()?;
+        Ok((exec, args))
+    }
+}
+
+/// Replaces each `{N}` in `part` with `values[N]`. Braces that don't
+/// enclose a number are left as they are. On a missing value, returns
+/// the index that was asked for.
+fn substitute(part: &str, values: &[&str]) -> Result<String, usize> {
+    let mut out = String::with_capacity(part.len());
+    let mut rest = part;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let placeholder = rest[1..]
+            .find('}')
+            .map(|end| &rest[1..end + 1])
+            .filter(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()));
+        match placeholder {
+            Some(digits) => {
+                let index = digits.parse().unwrap_or(usize::MAX);
+                out.push_str(values.get(index).ok_or(index)?);
+                rest = &rest[digits.len() + 2..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
@@ -0,0 +1,179 @@
+use std::io;
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Method, Request, Uri};
+use rocket::serde::json::serde_json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::time::{sleep, timeout};
+
+use crate::capture::{read_past, utf8_prefix, Captures};
+use crate::error::Error;
+use crate::puppet::{Proc, Puppet, PuppetId};
+use crate::routes::{Stream, WaitResp, FOLLOW_POLL};
+
+/// How many times an event is posted before it's given up on.
+const ATTEMPTS: u32 = 5;
+
+/// How long the first retry waits. Each retry after it waits twice as
+/// long as the one before.
+const FIRST_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long the receiver has to answer each post.
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts a puppet's events, as JSON, to the URL it was created with.
+/// Delivery is at least once: an event whose post fails, or isn't
+/// answered in time, is posted again, even if the receiver did get it.
+#[derive(Clone)]
+pub struct Webhook {
+    uri: Uri,
+    client: Client<HttpConnector>,
+}
+
+impl Webhook {
+    /// Only plain HTTP URLs can be posted to.
+    pub fn new(url: &str) -> Result<Webhook, Error> {
+        let uri: Uri = url.parse().map_err(|err| {
+            Error::InvalidRequest(format!("webhook_url '{}' is not a valid URL: {}", url, err))
+        })?;
+        if uri.scheme_str() != Some("http") || uri.host().is_none() {
+            return Err(Error::InvalidRequest(format!(
+                "webhook_url '{}' must be an http:// URL",
+                url
+            )));
+        }
+        Ok(Webhook {
+            uri,
+            client: Client::new(),
+        })
+    }
+
+    /// Posts `event`, retrying with backoff until the receiver answers
+    /// with a 2xx or there have been too many attempts, in which case the
+    /// event is dropped.
+    pub async fn post<T: Serialize>(&self, id: PuppetId, event: &T) {
+        let body = match serde_json::to_vec(event) {
+            Ok(body) => body,
+            Err(err) => {
+                warn!(
+                    "failed to serialize webhook event for puppet id={} err={}",
+                    id, err
+                );
+                return;
+            }
+        };
+        let mut backoff = FIRST_BACKOFF;
+        for attempt in 1..=ATTEMPTS {
+            let err = match self.try_post(body.clone()).await {
+                Ok(()) => return,
+                Err(err) => err,
+            };
+            warn!(
+                "failed to post to webhook for puppet id={} attempt={} err={}",
+                id, attempt, err
+            );
+            if attempt < ATTEMPTS {
+                sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        warn!("gave up posting to webhook for puppet id={}", id);
+    }
+
+    async fn try_post(&self, body: Vec<u8>) -> Result<(), String> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .map_err(|err| err.to_string())?;
+        let resp = timeout(POST_TIMEOUT, self.client.request(req))
+            .await
+            .map_err(|_| String::from("timed out"))?
+            .map_err(|err| err.to_string())?;
+        match resp.status().is_success() {
+            true => Ok(()),
+            false => Err(format!("receiver answered {}", resp.status())),
+        }
+    }
+}
+
+/// A message posted to a puppet's `webhook_url`.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+#[cfg_attr(feature = "camel-case", serde(rename_all_fields = "camelCase"))]
+pub enum WebhookEvent {
+    /// More of what the puppet wrote to one of its captured streams,
+    /// starting `offset` bytes into it. A receiver can tell a repeat
+    /// delivery by its offset.
+    Output {
+        id: PuppetId,
+        stream: Stream,
+        offset: u64,
+        data: String,
+    },
+    /// The puppet has exited. Nothing is posted after this.
+    Exit(WaitResp),
+}
+
+/// Posts a puppet's captured output to `webhook` as it's written, and
+/// then its exit, from a task of its own.
+pub fn start_webhook(webhook: Webhook, pup: &Puppet, max_inline: u64) {
+    let (proc, captures) = (pup.proc(), Captures::of(pup));
+    rocket::tokio::spawn(async move {
+        if let Err(err) = post_to_webhook(&webhook, &proc, captures, max_inline).await {
+            warn!(
+                "stopped posting to webhook for puppet id={} err={}",
+                proc.id(),
+                err
+            );
+        }
+    });
+}
+
+async fn post_to_webhook(
+    webhook: &Webhook,
+    proc: &Proc,
+    captures: Captures,
+    max_inline: u64,
+) -> io::Result<()> {
+    let id = proc.id();
+    let mut streams = vec![(Stream::Stdout, captures.stdout.clone(), 0)];
+    // Under a pty, stderr goes to the same capture as stdout.
+    if captures.stderr != captures.stdout {
+        streams.push((Stream::Stderr, captures.stderr.clone(), 0));
+    }
+    loop {
+        let exited = proc.try_wait();
+        for (stream, capture, offset) in streams.iter_mut() {
+            let capture = match capture {
+                Some(capture) => capture,
+                None => continue,
+            };
+            let (bytes, end) = read_past(capture, *offset).await?;
+            let (data, read) = utf8_prefix(&bytes, exited.is_some());
+            let start = end - bytes.len() as u64;
+            *offset = start + read as u64;
+            if !data.is_empty() {
+                let event = WebhookEvent::Output {
+                    id,
+                    stream: *stream,
+                    offset: start,
+                    data,
+                };
+                webhook.post(id, &event).await;
+            }
+        }
+        if let Some(status) = exited {
+            let status = status.map_err(|err| io::Error::other(err.to_string()))?;
+            let wait_resp = WaitResp::from(proc, status)
+                .with_output(captures, max_inline)
+                .await;
+            webhook.post(id, &WebhookEvent::Exit(wait_resp)).await;
+            return Ok(());
+        }
+        sleep(FOLLOW_POLL).await;
+    }
+}
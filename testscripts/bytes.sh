@@ -0,0 +1,8 @@
+#!/usr/bin/env bash
+
+# Writes each argument, given as a two-digit hex number, to stdout as a
+# single raw byte. Handy for producing output that isn't valid text.
+for byte in "$@"
+do
+		printf "\x$byte"
+done
@@ -0,0 +1,11 @@
+#!/usr/bin/env bash
+
+# Prints the second argument, then blocks until the file given as the
+# first argument exists before printing the third argument. Lets a test
+# do something to a running puppet between two known writes.
+echo "$2"
+while [ ! -e "$1" ]
+do
+		sleep 0.05
+done
+echo "$3"
@@ -0,0 +1,10 @@
+#!/usr/bin/env bash
+
+# Ignores SIGTERM altogether. Prints "ready" once it does, so a test
+# knows when it's safe to send one.
+trap '' TERM
+echo ready
+while true
+do
+		sleep 0.05
+done
@@ -0,0 +1,10 @@
+#!/usr/bin/env bash
+
+# Exits cleanly on SIGTERM, after saying so. Prints "ready" once the trap
+# is set, so a test knows when it's safe to send one.
+trap 'echo cleaned up; exit 0' TERM
+echo ready
+while true
+do
+		sleep 0.05
+done